@@ -0,0 +1,60 @@
+//! A mod adding a granary, a cheap wooden storage that grows with upgrades.
+//!
+//! `cargo run --example granary`
+
+use bevy::prelude::*;
+use ents::{
+    buildings::{AppExt, Building},
+    ent_defs::EntDef,
+    game::{BuildingUpgrade, BuildingUpgradeEvent, EntType, SimulationSet, Storage},
+    resource_kind::Resources,
+};
+
+const GRANARY: EntType = EntType::new("Granary");
+
+struct GranaryPlugin;
+
+impl Plugin for GranaryPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_building(
+            Building::new(GRANARY)
+                .with_def(EntDef {
+                    size: (3, 3),
+                    color: (0.9, 0.7, 0.4),
+                    texture: Some("storage.png".to_owned()),
+                    max_upgrades: 2,
+                    upgrade_cost: Some(Resources::new(40, 10, 0)),
+                    cost: Some(Resources::new(40, 0, 0)),
+                    storage: Some(30),
+                    ..default()
+                })
+                .with_upgrade::<GranaryUpgrade>()
+                .with_button("icons/storage.png", Some(KeyCode::Key7), [EntType::HOUSE]),
+        );
+    }
+}
+
+struct GranaryUpgrade;
+
+impl BuildingUpgrade for GranaryUpgrade {
+    fn add_systems(app: &mut App) {
+        app.add_systems(FixedUpdate, grow_granary.in_set(SimulationSet));
+    }
+}
+
+fn grow_granary(
+    mut events: EventReader<BuildingUpgradeEvent<GranaryUpgrade>>,
+    mut storages: Query<&mut Storage>,
+) {
+    for event in events.read() {
+        if let Ok(mut storage) = storages.get_mut(event.entity) {
+            storage.max += 30;
+        }
+    }
+}
+
+fn main() {
+    let mut app = ents::app();
+    app.add_plugins(GranaryPlugin);
+    app.run();
+}
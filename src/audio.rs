@@ -4,16 +4,15 @@ use bevy::prelude::*;
 use bevy_geng_audio::prelude::*;
 
 use crate::{
+    buildings::Buildings,
     buttons::Disabled,
-    game::{
-        BuildingUpgrade, BuildingUpgradeComponent, BuildingUpgradeToPerform, EntType, Hovered,
-        Money, NeedsResource, Placeholder, ScaleOnHover, WinState,
-    },
+    game::{EntType, Hovered, NeedsResource, Placeholder, ScaleOnHover, WinState},
 };
 
 pub struct Plugin;
 
 #[derive(Component)]
+#[allow(dead_code)]
 struct PlayUpgradeSound;
 
 #[derive(Resource)]
@@ -124,23 +123,15 @@ fn audio_construct(
     audio_sources: Res<AudioSources>,
     audio: Res<Audio>,
     new_placeholders: Query<&Placeholder, Added<Placeholder>>,
+    buildings: Res<Buildings>,
 ) {
     for placeholder in new_placeholders.iter() {
-        match placeholder.0 {
-            EntType::Base
-            | EntType::Storage
-            | EntType::House
-            | EntType::UpgradeInventory
-            | EntType::BuilderAcademy
-            | EntType::Monument => {
-                audio.play(audio_sources.construct.clone()).with_volume(5.);
-            }
-            EntType::Road => {
-                audio
-                    .play(audio_sources.construct_road.clone())
-                    .with_volume(4.);
-            }
-            _ => {}
+        if placeholder.0 == EntType::ROAD {
+            audio
+                .play(audio_sources.construct_road.clone())
+                .with_volume(4.);
+        } else if buildings.get(placeholder.0).is_some() {
+            audio.play(audio_sources.construct.clone()).with_volume(5.);
         }
     }
 }
@@ -151,6 +142,7 @@ fn audio_constructed(
     new_entities: Query<&EntType, Added<EntType>>,
     existing: Query<&EntType>,
     mut finished_upgrades: RemovedComponents<NeedsResource>,
+    buildings: Res<Buildings>,
 ) {
     let mut play = false;
     for entity in finished_upgrades.read() {
@@ -158,16 +150,8 @@ fn audio_constructed(
             play = true;
         }
     }
-    for entity in new_entities.iter() {
-        if matches!(
-            entity,
-            EntType::Base
-                | EntType::Storage
-                | EntType::House
-                | EntType::UpgradeInventory
-                | EntType::BuilderAcademy
-                | EntType::Monument
-        ) {
+    for &ent_type in new_entities.iter() {
+        if ent_type != EntType::ROAD && buildings.get(ent_type).is_some() {
             play = true;
             break;
         }
@@ -0,0 +1,208 @@
+//! Registry of building kinds. The base game registers its own buildings the same way a mod would:
+//!
+//! ```ignore
+//! app.register_building(
+//!     Building::new(EntType::new("Windmill"))
+//!         .with_def(EntDef { size: (2, 2), cost: Some(Resources::new(20, 0, 0)), ..default() })
+//!         .with_bundle(|| Windmill)
+//!         .with_upgrade::<Windmill>()
+//!         .with_button("icons/windmill.png", Some(KeyCode::Key7), [EntType::HOUSE]),
+//! );
+//! ```
+
+use std::any::TypeId;
+
+use bevy::{ecs::system::EntityCommands, prelude::*, utils::HashSet};
+
+use crate::{
+    ent_defs::{EntDef, EntDefs},
+    game::{self, BuildingUpgrade, BuildingUpgradeComponent, EntType},
+    pathfind::Blocking,
+    save,
+};
+
+pub struct Plugin;
+
+impl bevy::app::Plugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Buildings>();
+        app.add_systems(PostUpdate, building_components);
+    }
+}
+
+pub trait AppExt {
+    /// Panics if the building has no def, neither given with [Building::with_def] nor in `ents.defs.ron`,
+    /// or if its def covers no tiles
+    fn register_building(&mut self, building: Building) -> &mut Self;
+}
+
+impl AppExt for App {
+    fn register_building(&mut self, mut building: Building) -> &mut Self {
+        let ent_type = building.ent_type;
+        let mut defs = self.world.resource_mut::<EntDefs>();
+        if let Some(def) = building.def.take() {
+            if let Err(e) = def.check() {
+                panic!("{}: {e}", ent_type.name());
+            }
+            defs.insert(ent_type, def);
+        }
+        assert!(
+            defs.contains(ent_type),
+            "{} is not defined",
+            ent_type.name()
+        );
+        for upgrade in std::mem::take(&mut building.upgrades) {
+            let mut buildings = self.world.resource_mut::<Buildings>();
+            if buildings.upgrades.insert(upgrade.type_id) {
+                buildings.upgrade_visuals.push(upgrade.visuals);
+                buildings.upgrade_saving.push(upgrade.saving);
+                (upgrade.simulation)(self);
+            }
+        }
+        let mut buildings = self.world.resource_mut::<Buildings>();
+        assert!(
+            buildings.get(ent_type).is_none(),
+            "{} is registered twice",
+            ent_type.name()
+        );
+        buildings.list.push(building);
+        self
+    }
+}
+
+/// Everything the game needs to know about a kind of building besides its [EntDef]
+pub struct Building {
+    pub ent_type: EntType,
+    def: Option<EntDef>,
+    blocking: bool,
+    components: Vec<Box<dyn Fn(&mut EntityCommands) + Send + Sync>>,
+    upgrades: Vec<UpgradeSystems>,
+    pub button: Option<BuildButton>,
+}
+
+struct UpgradeSystems {
+    type_id: TypeId,
+    simulation: fn(&mut App),
+    visuals: fn(&mut App),
+    saving: fn(&mut App),
+}
+
+/// Build menu button placing the building
+pub struct BuildButton {
+    pub icon: String,
+    pub key: Option<KeyCode>,
+    /// The button is hidden until one of each of these is built
+    pub requires: Vec<EntType>,
+}
+
+impl Building {
+    pub fn new(ent_type: EntType) -> Self {
+        Self {
+            ent_type,
+            def: None,
+            blocking: true,
+            components: Vec::new(),
+            upgrades: Vec::new(),
+            button: None,
+        }
+    }
+
+    /// Not needed if `ents.defs.ron` already has a def for it
+    pub fn with_def(mut self, def: EntDef) -> Self {
+        self.def = Some(def);
+        self
+    }
+
+    /// Crabs can walk through it, like a road
+    pub fn walkable(mut self) -> Self {
+        self.blocking = false;
+        self
+    }
+
+    /// Components added once the building is built (or loaded)
+    pub fn with_bundle<B: Bundle>(
+        mut self,
+        bundle: impl Fn() -> B + Send + Sync + 'static,
+    ) -> Self {
+        self.components.push(Box::new(move |entity| {
+            entity.insert(bundle());
+        }));
+        self
+    }
+
+    /// Lets the player upgrade the building by clicking it, up to `max_upgrades` from its def
+    pub fn with_upgrade<T: BuildingUpgrade>(mut self) -> Self {
+        self.upgrades.push(UpgradeSystems {
+            type_id: TypeId::of::<T>(),
+            simulation: game::register_building_upgrade::<T>,
+            visuals: game::register_building_upgrade_visuals::<T>,
+            saving: save::register_saved_upgrade::<T>,
+        });
+        self.with_bundle(BuildingUpgradeComponent::<T>::new)
+    }
+
+    pub fn with_button(
+        mut self,
+        icon: impl Into<String>,
+        key: Option<KeyCode>,
+        requires: impl IntoIterator<Item = EntType>,
+    ) -> Self {
+        self.button = Some(BuildButton {
+            icon: icon.into(),
+            key,
+            requires: requires.into_iter().collect(),
+        });
+        self
+    }
+}
+
+/// Registered buildings in the order they were registered, which is also the order of build menu buttons
+#[derive(Resource, Default)]
+pub struct Buildings {
+    list: Vec<Building>,
+    upgrades: HashSet<TypeId>,
+    upgrade_visuals: Vec<fn(&mut App)>,
+    upgrade_saving: Vec<fn(&mut App)>,
+}
+
+impl Buildings {
+    pub fn get(&self, ent_type: EntType) -> Option<&Building> {
+        self.list
+            .iter()
+            .find(|building| building.ent_type == ent_type)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Building> {
+        self.list.iter()
+    }
+
+    /// Systems showing and clicking upgrades, not needed when running headless.
+    /// Taken once every plugin has registered its buildings.
+    pub fn take_upgrade_visuals(&mut self) -> Vec<fn(&mut App)> {
+        std::mem::take(&mut self.upgrade_visuals)
+    }
+
+    /// Systems saving and loading upgrade levels, taken the same way as [Self::take_upgrade_visuals]
+    pub fn take_upgrade_saving(&mut self) -> Vec<fn(&mut App)> {
+        std::mem::take(&mut self.upgrade_saving)
+    }
+}
+
+fn building_components(
+    q: Query<(Entity, &EntType), Added<EntType>>,
+    buildings: Res<Buildings>,
+    mut commands: Commands,
+) {
+    for (entity, &ent_type) in q.iter() {
+        let Some(building) = buildings.get(ent_type) else {
+            continue;
+        };
+        let mut entity = commands.entity(entity);
+        if building.blocking {
+            entity.insert(Blocking);
+        }
+        for insert in &building.components {
+            insert(&mut entity);
+        }
+    }
+}
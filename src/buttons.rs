@@ -29,14 +29,14 @@ fn button_clicks<A: Copy + Component + Event>(
     mut click_events: EventWriter<A>,
 ) {
     for (action, disabled, bind) in keybinds.iter() {
-        if input.just_pressed(bind.0) && !disabled.map_or(false, |d| d.0) {
+        if input.just_pressed(bind.0) && !disabled.is_some_and(|d| d.0) {
             click_events.send(*action);
         }
     }
     for (button_entity, disabled, interaction, action) in buttons.iter() {
         if *interaction == Interaction::Hovered
             && prev_interaction.get(&button_entity) == Some(&Interaction::Pressed)
-            && !disabled.map_or(false, |d| d.0)
+            && !disabled.is_some_and(|d| d.0)
         {
             click_events.send(*action);
         }
@@ -64,7 +64,7 @@ fn button_visuals(
     const PRESSED_BUTTON: Color = Color::rgb(0.35, 0.75, 0.35);
     const DISABLED_BUTTON: Color = Color::rgb(0.2, 0.2, 0.2);
     for (interaction, active, disabled, mut color, mut border_color) in &mut interaction_query {
-        if disabled.map_or(false, |d| d.0) {
+        if disabled.is_some_and(|d| d.0) {
             *color = DISABLED_BUTTON.into();
             border_color.0 = Color::BLACK;
             continue;
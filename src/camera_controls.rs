@@ -14,7 +14,7 @@ fn camera_controls(
     mut cursor_events: EventReader<CursorMoved>,
     mut wheel: EventReader<MouseWheel>,
     mut camera: Query<(&mut Transform, &GlobalTransform, &Camera, &mut Projection)>,
-    time: Res<Time>,
+    time: Res<Time<Real>>,
     mut prev_cursor_pos: Local<Vec2>,
 ) {
     const CAMERA_SPEED: f32 = 50.0;
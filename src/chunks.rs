@@ -5,15 +5,28 @@ pub struct Plugin;
 impl bevy::app::Plugin for Plugin {
     fn build(&self, app: &mut App) {
         app.insert_resource(GeneratedChunks(default()));
+        app.add_event::<GenerateRegion>();
         app.add_event::<GenerateChunk>();
-        app.add_systems(Update, calculate_chunks_to_generate);
+        app.add_event::<GenerateTerrain>();
+        app.add_systems(Update, generate_regions);
+    }
+}
+
+/// Generates whatever the camera can see
+pub struct CameraPlugin;
+
+impl bevy::app::Plugin for CameraPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            Update,
+            calculate_chunks_to_generate.before(generate_regions),
+        );
     }
 }
 
 fn calculate_chunks_to_generate(
     camera: Query<(&GlobalTransform, &Camera)>,
-    mut generated_chunks: ResMut<GeneratedChunks>,
-    mut event_writer: EventWriter<GenerateChunk>,
+    mut event_writer: EventWriter<GenerateRegion>,
 ) {
     let (camera_transform, camera) = camera.single();
     let Some(window_viewport) = camera.logical_viewport_rect() else {
@@ -35,26 +48,40 @@ fn calculate_chunks_to_generate(
     .reduce(|a, b| Rect::union(&a, b))
     .unwrap();
 
-    for chunk_x in (viewport.min.x / CHUNK_SIZE as f32).floor() as i32
-        ..(viewport.max.x / CHUNK_SIZE as f32).ceil() as i32
-    {
-        for chunk_y in (viewport.min.y / CHUNK_SIZE as f32).floor() as i32
-            ..(viewport.max.y / CHUNK_SIZE as f32).ceil() as i32
+    event_writer.send(GenerateRegion(viewport));
+}
+
+/// Make sure every chunk touching this rect (in world coordinates) is generated
+#[derive(Event)]
+pub struct GenerateRegion(pub Rect);
+
+pub fn generate_regions(
+    mut regions: EventReader<GenerateRegion>,
+    mut generated_chunks: ResMut<GeneratedChunks>,
+    mut event_writer: EventWriter<GenerateChunk>,
+) {
+    for GenerateRegion(region) in regions.read() {
+        for chunk_x in (region.min.x / CHUNK_SIZE as f32).floor() as i32
+            ..(region.max.x / CHUNK_SIZE as f32).ceil() as i32
         {
-            let chunk_pos = IVec2::new(chunk_x, chunk_y);
-            if generated_chunks.0.contains(&chunk_pos) {
-                continue;
+            for chunk_y in (region.min.y / CHUNK_SIZE as f32).floor() as i32
+                ..(region.max.y / CHUNK_SIZE as f32).ceil() as i32
+            {
+                let chunk_pos = IVec2::new(chunk_x, chunk_y);
+                if generated_chunks.0.contains(&chunk_pos) {
+                    continue;
+                }
+                generated_chunks.0.insert(chunk_pos);
+                event_writer.send(GenerateChunk(chunk_pos));
             }
-            generated_chunks.0.insert(chunk_pos);
-            event_writer.send(GenerateChunk(chunk_pos));
         }
     }
 }
 
-const CHUNK_SIZE: i32 = 64;
+pub const CHUNK_SIZE: i32 = 64;
 
-#[derive(Resource)]
-pub struct GeneratedChunks(HashSet<IVec2>);
+#[derive(Resource, Default, Clone)]
+pub struct GeneratedChunks(pub HashSet<IVec2>);
 
 impl GeneratedChunks {
     pub fn is_generated(&self, pos: IVec2) -> bool {
@@ -67,7 +94,15 @@ impl GeneratedChunks {
 pub struct GenerateChunk(IVec2);
 
 impl GenerateChunk {
+    pub fn pos(&self) -> IVec2 {
+        self.0
+    }
+
     pub fn rect(&self) -> IRect {
         IRect::from_corners(self.0 * CHUNK_SIZE, (self.0 + IVec2::splat(1)) * CHUNK_SIZE)
     }
 }
+
+/// Only the terrain of an already generated chunk, for when its ents come from a save
+#[derive(Event)]
+pub struct GenerateTerrain(pub IVec2);
@@ -0,0 +1,210 @@
+//! Order builders work through construction sites and upgrades in.
+//!
+//! New sites go to the end, the player can move them up or down in the panel.
+//! Builders fill the first site in the queue they can bring something to,
+//! once it has everything claimed the rest of them go on to the next one.
+
+use bevy::prelude::*;
+
+use crate::{
+    buttons,
+    game::{EntType, NeedsResource, Placeholder, SimulationSet},
+    removed::{AppExt as _, Removed, RemovedSet},
+};
+
+pub struct Plugin;
+
+impl bevy::app::Plugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ConstructionQueue>();
+        app.keep_removed::<NeedsResource>();
+        app.add_systems(
+            FixedUpdate,
+            update_queue.after(RemovedSet).before(SimulationSet),
+        );
+    }
+}
+
+/// Queue panel, not needed when running headless
+pub struct UiPlugin;
+
+impl bevy::app::Plugin for UiPlugin {
+    fn build(&self, app: &mut App) {
+        buttons::register::<MoveInQueue>(app);
+        app.add_systems(
+            Update,
+            (move_in_queue, update_queue_rows, update_progress_texts).chain(),
+        );
+    }
+}
+
+/// Everything waiting for builders, first one gets built first
+#[derive(Resource, Default, Debug)]
+pub struct ConstructionQueue(Vec<Entity>);
+
+impl ConstructionQueue {
+    pub fn iter(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.0.iter().copied()
+    }
+
+    pub fn position(&self, entity: Entity) -> Option<usize> {
+        self.0.iter().position(|&queued| queued == entity)
+    }
+
+    /// Swaps the entity with the one before it, or after it when not `up`
+    pub fn move_by_one(&mut self, entity: Entity, up: bool) {
+        let Some(index) = self.position(entity) else {
+            return;
+        };
+        let other = if up {
+            index.checked_sub(1)
+        } else {
+            Some(index + 1).filter(|&other| other < self.0.len())
+        };
+        if let Some(other) = other {
+            self.0.swap(index, other);
+        }
+    }
+}
+
+/// Sites and upgrades are queued once they are paid for and leave it when done or cancelled
+fn update_queue(
+    added: Query<Entity, Added<NeedsResource>>,
+    removed: Res<Removed<NeedsResource>>,
+    mut queue: ResMut<ConstructionQueue>,
+) {
+    for entity in removed.iter() {
+        if let Some(index) = queue.position(entity) {
+            queue.0.remove(index);
+        }
+    }
+    for entity in added.iter() {
+        if queue.position(entity).is_none() {
+            queue.0.push(entity);
+        }
+    }
+}
+
+#[derive(Debug, Event, Component, Copy, Clone)]
+struct MoveInQueue {
+    entity: Entity,
+    up: bool,
+}
+
+fn move_in_queue(mut events: EventReader<MoveInQueue>, mut queue: ResMut<ConstructionQueue>) {
+    for event in events.read() {
+        queue.move_by_one(event.entity, event.up);
+    }
+}
+
+#[derive(Component)]
+struct QueuePanel(TextStyle);
+
+#[derive(Component)]
+struct ProgressText(Entity);
+
+/// Rows are rebuilt whenever the queue changes, progress is updated in place
+fn update_queue_rows(
+    queue: Res<ConstructionQueue>,
+    panels: Query<(Entity, &QueuePanel)>,
+    sites: Query<(Option<&Placeholder>, Option<&EntType>)>,
+    mut commands: Commands,
+) {
+    if !queue.is_changed() {
+        return;
+    }
+    for (panel, QueuePanel(text_style)) in panels.iter() {
+        let mut panel = commands.entity(panel);
+        panel.despawn_descendants();
+        panel.with_children(|panel| {
+            for entity in queue.iter() {
+                let name = match sites.get(entity) {
+                    Ok((Some(placeholder), _)) => placeholder.0.name().to_string(),
+                    Ok((None, Some(ent_type))) => format!("{} upgrade", ent_type.name()),
+                    _ => continue,
+                };
+                spawn_row(panel, text_style, entity, name);
+            }
+        });
+    }
+}
+
+fn spawn_row(panel: &mut ChildBuilder, text_style: &TextStyle, entity: Entity, name: String) {
+    panel
+        .spawn(NodeBundle {
+            style: Style {
+                align_items: AlignItems::Center,
+                ..default()
+            },
+            ..default()
+        })
+        .with_children(|row| {
+            for up in [true, false] {
+                row.spawn((
+                    ButtonBundle {
+                        style: Style {
+                            width: Val::Px(32.0),
+                            height: Val::Px(32.0),
+                            border: UiRect::all(Val::Px(3.0)),
+                            margin: UiRect::all(Val::Px(2.0)),
+                            justify_content: JustifyContent::Center,
+                            align_items: AlignItems::Center,
+                            ..default()
+                        },
+                        ..default()
+                    },
+                    MoveInQueue { entity, up },
+                ))
+                .with_children(|button| {
+                    button.spawn(TextBundle::from_section(
+                        if up { "^" } else { "v" },
+                        text_style.clone(),
+                    ));
+                });
+            }
+            row.spawn(
+                TextBundle::from_section(name, text_style.clone()).with_style(Style {
+                    margin: UiRect::horizontal(Val::Px(10.0)),
+                    ..default()
+                }),
+            );
+            row.spawn((
+                TextBundle::from_section("", text_style.clone()),
+                ProgressText(entity),
+            ));
+        });
+}
+
+/// Resources delivered out of the total cost
+fn update_progress_texts(
+    mut texts: Query<(&mut Text, &ProgressText)>,
+    needs: Query<&NeedsResource>,
+) {
+    for (mut text, progress) in texts.iter_mut() {
+        let Ok(needs) = needs.get(progress.0) else {
+            continue;
+        };
+        let total = needs.1.total();
+        let progress = format!("{}/{}", total - needs.0.total(), total);
+        if text.sections[0].value != progress {
+            text.sections[0].value = progress;
+        }
+    }
+}
+
+/// Column the queue rows are kept in
+pub fn spawn_panel(parent: &mut ChildBuilder, text_style: &TextStyle) {
+    parent.spawn((
+        NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                left: Val::Px(10.0),
+                top: Val::Px(10.0),
+                flex_direction: FlexDirection::Column,
+                ..default()
+            },
+            ..default()
+        },
+        QueuePanel(text_style.clone()),
+    ));
+}
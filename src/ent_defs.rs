@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    prelude::*,
+    utils::BoxedFuture,
+};
+use serde::Deserialize;
+
+use crate::{
+    game::EntType,
+    placement::PlacementRule,
+    resource_kind::Resources,
+    tile_map::{Rotation, Shape},
+};
+
+const PATH: &str = "ents.defs.ron";
+
+/// Keeps [EntDefs] in sync with the asset file, so edits show up without restarting the game
+/// (when bevy is built with the `file_watcher` feature).
+///
+/// The simulation itself starts with the defs embedded into the binary and does not need this.
+pub struct Plugin;
+
+impl bevy::app::Plugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<EntDefs>();
+        app.register_asset_loader(EntDefsLoader);
+        app.add_systems(Startup, load_ent_defs);
+        app.add_systems(Update, update_ent_defs);
+    }
+}
+
+/// How a kind of ent looks, costs and what it does
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EntDef {
+    /// Footprint in tiles
+    pub size: (i32, i32),
+    /// Rows of the footprint from `y = 0` up, `#` for tiles the building covers and `.` for ones it doesn't.
+    /// The whole `size` is covered without it.
+    pub footprint: Option<Vec<String>>,
+    /// Tiles next to the footprint that have to be roads, any side can face a road without them
+    pub entrances: Vec<(i32, i32)>,
+    /// Checked on top of fitting on free tiles next to a road
+    pub rules: Vec<PlacementRule>,
+    /// Elevation of the model above the ground
+    pub height: f32,
+    pub color: (f32, f32, f32),
+    pub texture: Option<String>,
+    pub mesh: EntMesh,
+    pub max_upgrades: usize,
+    /// Cost of the first upgrade, each level after that costs it times the level.
+    /// Buildings without it can't be upgraded.
+    pub upgrade_cost: Option<Resources>,
+    /// Ents without a cost can't be placed by the player
+    pub cost: Option<Resources>,
+    pub population: usize,
+    /// Ents spawned right after it's built
+    pub spawn: Option<(EntType, usize)>,
+    /// Total capacity of all kinds of resources
+    pub storage: Option<i32>,
+    /// How much a unit can carry
+    pub inventory: Option<i32>,
+    /// Units step diagonally too
+    pub diagonal_movement: bool,
+}
+
+impl Default for EntDef {
+    fn default() -> Self {
+        Self {
+            size: (1, 1),
+            footprint: None,
+            entrances: Vec::new(),
+            rules: Vec::new(),
+            height: 0.0,
+            color: (1.0, 1.0, 1.0),
+            texture: None,
+            mesh: EntMesh::Building,
+            max_upgrades: 0,
+            upgrade_cost: None,
+            cost: None,
+            population: 0,
+            spawn: None,
+            storage: None,
+            inventory: None,
+            diagonal_movement: false,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+pub enum EntMesh {
+    /// Box growing a level with every upgrade
+    Building,
+    /// Lies on the ground, covering the footprint
+    Flat,
+    /// Small walking sprite
+    Unit,
+}
+
+impl EntDef {
+    /// Every ent covers at least one tile
+    pub fn check(&self) -> Result<(), String> {
+        if self.size.0 < 1 || self.size.1 < 1 {
+            return Err(format!("size {:?} covers no tiles", self.size));
+        }
+        if self
+            .shape(Rotation::default())
+            .is_some_and(|shape| shape.0.is_empty())
+        {
+            return Err("footprint covers no tiles".to_owned());
+        }
+        Ok(())
+    }
+
+    pub fn size(&self) -> IVec2 {
+        IVec2::new(self.size.0, self.size.1)
+    }
+
+    /// Tiles the footprint covers after rotating it, none if it covers the whole size
+    pub fn shape(&self, rotation: Rotation) -> Option<Shape> {
+        let rows = self.footprint.as_ref()?;
+        let tiles = rows
+            .iter()
+            .enumerate()
+            .flat_map(|(y, row)| {
+                row.chars()
+                    .enumerate()
+                    .filter(|&(_, c)| c == '#')
+                    .map(move |(x, _)| IVec2::new(x as i32, y as i32))
+            })
+            .map(|tile| rotation.tile(tile, self.size()))
+            .collect();
+        Some(Shape(tiles))
+    }
+
+    pub fn entrances(&self, rotation: Rotation) -> impl Iterator<Item = IVec2> + '_ {
+        self.entrances
+            .iter()
+            .map(move |&(x, y)| rotation.tile(IVec2::new(x, y), self.size()))
+    }
+
+    /// What upgrading to `level` costs, none past `max_upgrades`
+    pub fn level_cost(&self, level: i32) -> Option<Resources> {
+        if level > self.max_upgrades as i32 {
+            return None;
+        }
+        self.upgrade_cost.map(|cost| cost * level)
+    }
+
+    pub fn color(&self) -> Color {
+        Color::rgb(self.color.0, self.color.1, self.color.2)
+    }
+}
+
+#[derive(Asset, TypePath, Resource, Debug, Clone, Deserialize)]
+#[serde(transparent)]
+pub struct EntDefs(HashMap<EntType, EntDef>);
+
+impl EntDefs {
+    /// Defs the game was built with
+    pub fn builtin() -> Self {
+        Self::parse(include_bytes!("../assets/ents.defs.ron")).unwrap()
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        let defs: Self = ron::de::from_bytes(bytes).map_err(|e| e.to_string())?;
+        for (ent_type, def) in defs.iter() {
+            def.check()
+                .map_err(|e| format!("{}: {e}", ent_type.name()))?;
+        }
+        Ok(defs)
+    }
+
+    /// None for ent types no def was loaded or registered for, like ones from a mod that is missing
+    pub fn get(&self, ent_type: EntType) -> Option<&EntDef> {
+        self.0.get(&ent_type)
+    }
+
+    pub fn contains(&self, ent_type: EntType) -> bool {
+        self.0.contains_key(&ent_type)
+    }
+
+    pub fn insert(&mut self, ent_type: EntType, def: EntDef) {
+        self.0.insert(ent_type, def);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (EntType, &EntDef)> {
+        self.0.iter().map(|(&ent_type, def)| (ent_type, def))
+    }
+}
+
+struct EntDefsLoader;
+
+impl AssetLoader for EntDefsLoader {
+    type Asset = EntDefs;
+    type Settings = ();
+    type Error = String;
+
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<EntDefs, String>> {
+        Box::pin(async move {
+            let mut bytes = Vec::new();
+            reader
+                .read_to_end(&mut bytes)
+                .await
+                .map_err(|e| e.to_string())?;
+            EntDefs::parse(&bytes)
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["defs.ron"]
+    }
+}
+
+#[derive(Resource)]
+struct EntDefsHandle(Handle<EntDefs>);
+
+fn load_ent_defs(asset_server: Res<AssetServer>, mut commands: Commands) {
+    commands.insert_resource(EntDefsHandle(asset_server.load(PATH)));
+}
+
+fn update_ent_defs(
+    mut events: EventReader<AssetEvent<EntDefs>>,
+    handle: Res<EntDefsHandle>,
+    assets: Res<Assets<EntDefs>>,
+    mut defs: ResMut<EntDefs>,
+) {
+    let id = handle.0.id();
+    let loaded = events
+        .read()
+        .any(|event| event.is_loaded_with_dependencies(id) || event.is_modified(id));
+    if let (true, Some(loaded)) = (loaded, assets.get(id)) {
+        info!("Loaded {PATH}");
+        // Ents registered by mods in code are not in the file, keep them
+        for (ent_type, def) in loaded.iter() {
+            defs.insert(ent_type, def.clone());
+        }
+    }
+}
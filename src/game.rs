@@ -1,45 +1,119 @@
-use std::marker::PhantomData;
+use std::{marker::PhantomData, sync::Mutex};
 
 use bevy::{
     core_pipeline::tonemapping::Tonemapping,
-    ecs::system::{EntityCommand, EntityCommands},
+    ecs::system::{Command, CommandQueue, EntityCommand, EntityCommands, SystemState},
     prelude::*,
-    render::{
-        mesh::shape::Plane,
-        texture::{ImageAddressMode, ImageLoaderSettings, ImageSampler, ImageSamplerDescriptor},
-    },
+    render::mesh::shape::Plane,
     utils::{HashMap, HashSet},
     window::PrimaryWindow,
 };
-use rand::{seq::IteratorRandom, thread_rng, Rng};
+use rand::{rngs::StdRng, seq::IteratorRandom, thread_rng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
 
-const INITIAL_MONEY: i32 = 50;
+const INITIAL_MONEY: Resources = Resources::new(40, 20, 0);
 
 use crate::{
-    buttons, cursor, meshes,
-    pathfind::{self, AppExt, Blocking, Pathfinding},
-    tile_map::{Pos, Size, TileMap},
+    buildings::{AppExt as _, Building, Buildings},
+    buttons,
+    construction_queue::ConstructionQueue,
+    cursor,
+    ent_defs::{EntDef, EntDefs, EntMesh},
+    history::{Action, History, Perform, Site, UpgradeCommands},
+    jobs::{Claim, JobBoard, JobKind, JobPriorities},
+    meshes,
+    pathfind::{self, AppExt, Blocking, PathQuery, PathTarget, Pathfinding},
+    placement::{Placement, Rejection},
+    removed::{AppExt as _, Removed, RemovedSet},
+    resource_kind::{self, Kind, ResourceKind, Resources},
+    terrain::{Terrain, TerrainChunk},
+    tile_map::{footprint, Pos, Rotation, Shape, Size, TileFlag, TileMap, UpdateTiles},
     ui,
+    unit_state::{
+        BringingResource, ChoosingResource, Harvesting, Storing, TakingResource, UnitState,
+        UnitStateCommands,
+    },
 };
 
 pub const MOVE_DIRECTIONS: [IVec2; 4] = [IVec2::X, IVec2::Y, IVec2::NEG_X, IVec2::NEG_Y];
+/// Only taken by ents with [DiagonalMovement]
+pub const DIAGONAL_DIRECTIONS: [IVec2; 4] = [
+    IVec2::new(1, 1),
+    IVec2::new(-1, 1),
+    IVec2::new(-1, -1),
+    IVec2::new(1, -1),
+];
 
 pub struct GamePlugin;
 
+/// Same seed always generates the same map
+#[derive(Resource, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct WorldSeed(pub u64);
+
+impl WorldSeed {
+    pub fn random() -> Self {
+        Self(thread_rng().gen())
+    }
+
+    /// `--seed <number>` on native, `?seed=<number>` on web
+    pub fn from_args() -> Option<Self> {
+        #[cfg(not(target_arch = "wasm32"))]
+        let seed = {
+            let mut args = std::env::args().skip_while(|arg| arg != "--seed").skip(1);
+            args.next()
+        };
+        #[cfg(target_arch = "wasm32")]
+        let seed = web_sys::window()
+            .and_then(|window| window.location().search().ok())
+            .and_then(|query| {
+                query
+                    .trim_start_matches('?')
+                    .split('&')
+                    .find_map(|param| param.strip_prefix("seed=").map(str::to_owned))
+            });
+        let seed = seed?;
+        match seed.parse() {
+            Ok(seed) => Some(Self(seed)),
+            Err(e) => {
+                warn!("Invalid seed {seed:?}: {e}");
+                None
+            }
+        }
+    }
+
+    /// Rng depending only on the seed and the position,
+    /// so that the order things are generated in does not matter
+    fn rng_at(&self, pos: IVec2) -> StdRng {
+        StdRng::seed_from_u64(
+            self.0
+                ^ (pos.x as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+                ^ (pos.y as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F),
+        )
+    }
+}
+
 #[derive(Resource)]
 struct Noise(noise::OpenSimplex);
 
 impl Noise {
+    fn new(seed: WorldSeed) -> Self {
+        Self(noise::OpenSimplex::new((seed.0 ^ (seed.0 >> 32)) as u32))
+    }
     fn get(&self, pos: Vec2) -> f32 {
         noise::NoiseFn::get(&self.0, [pos.x as f64, pos.y as f64]) as f32
     }
 }
 
+fn reseed_noise(seed: Res<WorldSeed>, mut noise: ResMut<Noise>) {
+    info!("World seed: {}", seed.0);
+    *noise = Noise::new(*seed);
+}
+
 #[derive(Component)]
-struct InventoryEntities(Vec<Entity>);
+struct InventoryEntities(Vec<(Entity, ResourceKind)>);
 
 #[derive(Component)]
-struct StorageLevelChild(Entity);
+pub struct StorageLevelChild(pub Entity);
 
 const BASE_HEIGHT: f32 = 1.0;
 
@@ -56,9 +130,9 @@ fn update_storage_visuals(
 ) {
     for (upgrade, storage, child) in storages.iter() {
         let mut child_transform = levels.get_mut(child.0).unwrap();
-        child_transform.translation.y = storage.current as f32 / storage.max as f32
+        child_transform.translation.y = storage.current.total() as f32 / storage.max as f32
             * (upgrade.map_or(BASE_HEIGHT, |upgrade| {
-                (upgrade.current_level + 1) as f32 * EntType::Storage.upgrade_height()
+                (upgrade.current_level + 1) as f32 * EntType::STORAGE.upgrade_height()
             }) - 0.1);
     }
 }
@@ -72,46 +146,202 @@ pub enum WinState {
 
 fn start_crabrave(
     monuments: Query<&BuildingUpgradeComponent<MonumentUpgrade>, Without<NeedsResource>>,
-    mut win_text: Query<&mut Style, With<WinText>>,
     mut next_state: ResMut<NextState<WinState>>,
 ) {
     if monuments.iter().any(|upgrade| upgrade.current_level == 3) {
         next_state.set(WinState::CrabRave);
-        win_text.single_mut().display = Display::DEFAULT;
     }
 }
 
+fn show_win_text(mut win_text: Query<&mut Style, With<WinText>>) {
+    win_text.single_mut().display = Display::DEFAULT;
+}
+
+/// Colony logic only, does not need a window or a gpu so it can run on top of [MinimalPlugins].
+///
+/// World is only generated where asked for with [crate::chunks::GenerateRegion].
+/// Colony advances in [FixedUpdate] so it plays the same no matter the frame rate.
+pub struct SimulationPlugin {
+    /// Simulation ticks per second
+    pub tick_rate: f64,
+}
+
+impl Default for SimulationPlugin {
+    fn default() -> Self {
+        Self { tick_rate: 30.0 }
+    }
+}
+
+/// Systems advancing the colony by one tick
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SimulationSet;
+
+impl Plugin for SimulationPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins((
+            crate::tile_map::Plugin,
+            crate::pathfind::Plugin,
+            crate::chunks::Plugin,
+            crate::jobs::Plugin,
+            crate::construction_queue::Plugin,
+            crate::history::Plugin,
+            crate::unit_state::Plugin,
+        ));
+
+        app.insert_resource(Time::<Fixed>::from_hz(self.tick_rate));
+        app.configure_sets(
+            FixedUpdate,
+            SimulationSet
+                .after(crate::tile_map::update_tile_map)
+                .after(crate::pathfind::update_ents)
+                .after(crate::pathfind::PathfindingSet)
+                .after(RemovedSet),
+        );
+
+        let seed = *app.world.get_resource_or_insert_with(WorldSeed::random);
+        app.insert_resource(Noise::new(seed));
+        app.add_systems(
+            Update,
+            (
+                reseed_noise.run_if(resource_changed::<WorldSeed>()),
+                generate_chunks,
+            )
+                .chain()
+                .after(crate::chunks::generate_regions),
+        );
+
+        app.add_systems(PostUpdate, start_crabrave.run_if(in_state(WinState::NoWin)));
+        app.add_state::<WinState>();
+
+        app.insert_resource(EntDefs::builtin());
+        app.add_plugins(crate::buildings::Plugin);
+        app.insert_resource(Money(INITIAL_MONEY));
+
+        app.register_pathfinding_towards::<Harvestable>();
+        app.register_pathfinding_towards::<StorageThatHasSpace>();
+        app.add_systems(FixedUpdate, update_storages.in_set(SimulationSet));
+        app.add_systems(
+            FixedUpdate,
+            (
+                (ent_movement::<Harvesting, Harvestable>, ent_harvest)
+                    .run_if(crate::jobs::enabled(JobKind::Harvest)),
+                (
+                    claim_storage,
+                    ent_movement::<Storing, StorageThatHasSpace>,
+                    ent_store,
+                )
+                    .run_if(crate::jobs::enabled(JobKind::Store)),
+            )
+                .in_set(SimulationSet),
+        );
+        app.keep_removed::<Storage>()
+            .keep_removed::<NeedsResource>();
+        app.add_systems(
+            FixedUpdate,
+            post_store_jobs.before(claim_storage).in_set(SimulationSet),
+        );
+        app.add_systems(FixedUpdate, update_movement.in_set(SimulationSet));
+        app.add_systems(FixedUpdate, walk_to.in_set(SimulationSet));
+        app.add_systems(FixedUpdate, give_up_upgrades.in_set(SimulationSet));
+
+        app.add_systems(
+            FixedUpdate,
+            (
+                post_build_jobs,
+                builders_back_to_building,
+                choose_resource_to_take,
+            )
+                .chain()
+                .in_set(SimulationSet),
+        );
+        app.add_systems(FixedUpdate, actual_building.in_set(SimulationSet));
+        register_resource_kind::<resource_kind::Wood>(app);
+        register_resource_kind::<resource_kind::Stone>(app);
+        register_resource_kind::<resource_kind::Gold>(app);
+
+        app.add_systems(FixedUpdate, spawn_ents.in_set(SimulationSet));
+        app.add_systems(PostUpdate, ent_types);
+
+        register_upgrade::<InventoryUpgrade>(app);
+        register_upgrade::<BuilderUpgrade>(app);
+
+        register_buildings(app);
+    }
+}
+
+fn register_buildings(app: &mut App) {
+    app.register_building(Building::new(EntType::BASE).with_bundle(|| Road));
+    app.register_building(
+        Building::new(EntType::ROAD)
+            .walkable()
+            .with_bundle(|| Road)
+            .with_button("icons/road.png", Some(KeyCode::Key1), []),
+    );
+    app.register_building(
+        Building::new(EntType::HOUSE)
+            .with_upgrade::<ProvidePopulation>()
+            .with_button("icons/house.png", Some(KeyCode::Key2), [EntType::ROAD]),
+    );
+    app.register_building(
+        Building::new(EntType::BUILDER_ACADEMY)
+            .with_bundle(|| CanUpgrade::<BuilderUpgrade> {
+                upgrades_left: 5,
+                phantom_data: PhantomData,
+            })
+            .with_upgrade::<BuilderUpgrade>()
+            .with_button("icons/builders.png", Some(KeyCode::Key3), [EntType::HOUSE]),
+    );
+    app.register_building(
+        Building::new(EntType::UPGRADE_INVENTORY)
+            .with_bundle(|| CanUpgrade::<InventoryUpgrade> {
+                upgrades_left: 5,
+                phantom_data: PhantomData,
+            })
+            .with_upgrade::<InventoryUpgrade>()
+            .with_button("icons/gold.png", Some(KeyCode::Key4), [EntType::HOUSE]),
+    );
+    app.register_building(
+        Building::new(EntType::STORAGE)
+            .with_upgrade::<Storage>()
+            .with_button("icons/storage.png", Some(KeyCode::Key5), [EntType::HOUSE]),
+    );
+    app.register_building(
+        Building::new(EntType::MONUMENT)
+            .with_upgrade::<MonumentUpgrade>()
+            .with_button(
+                "icons/bavy.png",
+                Some(KeyCode::Key6),
+                [
+                    EntType::BUILDER_ACADEMY,
+                    EntType::UPGRADE_INVENTORY,
+                    EntType::STORAGE,
+                ],
+            ),
+    );
+}
+
 impl Plugin for GamePlugin {
     fn build(&self, app: &mut App) {
-        app.insert_resource(Noise(noise::OpenSimplex::new(thread_rng().gen())));
-        app.add_systems(Update, generate_chunks);
+        app.add_plugins(SimulationPlugin::default());
+
         app.add_systems(Update, tooltip);
 
-        app.add_systems(PostUpdate, start_crabrave.run_if(in_state(WinState::NoWin)));
+        app.add_systems(OnEnter(WinState::CrabRave), show_win_text);
         app.add_systems(Update, crabrave.run_if(in_state(WinState::CrabRave)));
-        app.add_state::<WinState>();
 
         app.add_systems(Update, update_storage_visuals);
 
-        app.insert_resource(EntCosts({
-            let mut costs = HashMap::new();
-            // costs.insert(EntType::Harvester, 5);
-            costs.insert(EntType::House, 10);
-            costs.insert(EntType::Road, 1);
-            costs.insert(EntType::UpgradeInventory, 50);
-            costs.insert(EntType::Storage, 100);
-            costs.insert(EntType::BuilderAcademy, 50);
-            costs.insert(EntType::Monument, 1000);
-            costs
-        }));
-
         app.add_systems(Startup, setup_ui);
         app.add_systems(Update, (unlock_buttons, button_actions));
         app.add_systems(Update, (activate_buttons, disable_buttons));
         crate::buttons::register::<ButtonAction>(app);
         app.add_systems(Startup, (setup_camera, setup_materials));
+        app.add_systems(
+            Update,
+            reload_ent_materials
+                .run_if(resource_changed::<EntDefs>().and_then(not(resource_added::<EntDefs>()))),
+        );
         // app.add_systems(Startup, spawn_a_LOT_of_entities);
-        app.insert_resource(Money(INITIAL_MONEY));
         app.add_systems(
             Update,
             (
@@ -122,48 +352,57 @@ impl Plugin for GamePlugin {
             ),
         );
         app.add_systems(Update, scale_hovered);
-        app.add_systems(Update, hovering.run_if(in_state(PlayerState::Normal)));
         app.add_systems(
             Update,
-            (place_ent.after(update_placing_preview), cancel_placing).run_if(
-                |state: Res<State<PlayerState>>| matches!(state.get(), PlayerState::Placing(..)),
-            ),
+            hovering.run_if(|state: Res<State<PlayerState>>| {
+                matches!(
+                    state.get(),
+                    PlayerState::Normal | PlayerState::Demolishing | PlayerState::Moving(None)
+                )
+            }),
         );
-        app.add_systems(Update, scaffolding);
-        app.register_pathfinding_towards::<Harvestable>();
-        app.register_pathfinding_towards::<StorageThatHasSpace>();
-        app.add_systems(Update, (update_storages, visualize_storage));
         app.add_systems(
             Update,
-            (
-                ent_movement::<Harvesting, Harvestable>,
-                ent_movement::<Storing, StorageThatHasSpace>,
-                ent_harvest,
-                ent_store,
-            ),
+            place_ent
+                .after(update_placing_preview)
+                .run_if(|state: Res<State<PlayerState>>| {
+                    matches!(state.get(), PlayerState::Placing(..))
+                }),
+        );
+        app.add_systems(
+            Update,
+            cancel_placing.run_if(not(in_state(PlayerState::Normal))),
+        );
+        app.add_systems(
+            Update,
+            right_click_to_cancel_placeholder
+                .after(hovering)
+                .run_if(in_state(PlayerState::Normal)),
+        );
+        app.add_systems(
+            Update,
+            demolish_on_click
+                .after(hovering)
+                .run_if(in_state(PlayerState::Demolishing)),
         );
-        app.add_systems(Update, inventory_entities);
-        app.add_systems(Update, (update_transforms, update_resource_transforms));
-        app.add_systems(Update, update_movement);
-
-        app.register_pathfinding_towards::<NonEmptyStorage>();
-        app.register_pathfinding_towards::<NeedsResource>();
         app.add_systems(
             Update,
             (
-                ent_movement::<TakingResource, NonEmptyStorage>,
-                ent_movement::<BringingResource, NeedsResource>,
-                take_resource,
-                bring_resource,
-                actual_building,
-            ),
+                pick_building_to_move.after(hovering),
+                move_building_on_click.after(update_placing_preview),
+            )
+                .run_if(|state: Res<State<PlayerState>>| {
+                    matches!(state.get(), PlayerState::Moving(..))
+                }),
         );
-
-        app.add_systems(Update, spawn_ents);
-        app.add_systems(PostUpdate, ent_types);
-
-        register_upgrade::<InventoryUpgrade>(app);
-        register_upgrade::<BuilderUpgrade>(app);
+        app.add_systems(
+            Update,
+            (scaffolding, placeholder_visuals, harvestable_visuals),
+        );
+        app.add_systems(Update, visualize_storage);
+        app.add_systems(Update, inventory_entities);
+        app.add_systems(Update, (update_transforms, update_resource_transforms));
+        app.add_systems(PostUpdate, ent_visuals);
 
         app.add_state::<PlayerState>();
         app.add_systems(Update, update_time_text.run_if(in_state(WinState::NoWin)));
@@ -175,13 +414,27 @@ impl Plugin for GamePlugin {
             }),
         );
         app.insert_resource(HavePlaced(false));
-        app.add_systems(Update, stop_placing_on_mouse_release);
-        app.add_systems(Update, update_placing_preview);
+        app.init_resource::<RoadDrag>();
+        app.add_systems(
+            Update,
+            (
+                drag_roads,
+                stop_placing_on_mouse_release,
+                update_road_preview,
+            )
+                .chain(),
+        );
+        app.init_resource::<PlacementRotation>();
+        app.add_systems(Update, (rotate_placement, update_placing_preview).chain());
         app.add_systems(Update, bavy_monument);
+    }
 
-        register_building_upgrade::<Storage>(app);
-        register_building_upgrade::<ProvidePopulation>(app);
-        register_building_upgrade::<MonumentUpgrade>(app);
+    fn finish(&self, app: &mut App) {
+        // Mods register their buildings after this plugin is built
+        let visuals = app.world.resource_mut::<Buildings>().take_upgrade_visuals();
+        for register in visuals {
+            register(app);
+        }
     }
 }
 
@@ -236,14 +489,18 @@ fn bavy_monument(
 
 impl BuildingUpgrade for ProvidePopulation {
     fn add_systems(app: &mut App) {
-        app.add_systems(Update, upgrade_houses);
+        app.add_systems(
+            FixedUpdate,
+            upgrade_houses
+                .after(perform_building_upgrades::<Self>)
+                .in_set(SimulationSet),
+        );
     }
-    const BASE_COST: i32 = 20;
 }
 
 fn update_resource_transforms(mut q: Query<(&mut Transform, &Harvestable), Changed<Harvestable>>) {
     for (mut transform, harvestable) in q.iter_mut() {
-        transform.translation.y = harvestable.0 as f32 - 1.0
+        transform.translation.y = harvestable.amount as f32 - 1.0
     }
 }
 
@@ -254,16 +511,26 @@ fn inventory_entities(
 ) {
     for (ent, mut stack, inv) in ents.iter_mut() {
         let stack = &mut stack.0;
-        while stack.len() > inv.current as _ {
-            commands.entity(stack.pop().unwrap()).despawn();
+        let wanted: Vec<ResourceKind> = inv
+            .current
+            .iter()
+            .flat_map(|(kind, amount)| std::iter::repeat_n(kind, amount.max(0) as usize))
+            .collect();
+        let keep = stack
+            .iter()
+            .zip(&wanted)
+            .take_while(|((_, have), want)| have == *want)
+            .count();
+        while stack.len() > keep {
+            commands.entity(stack.pop().unwrap().0).despawn();
         }
-        while stack.len() < inv.current as _ {
-            stack.push(
+        while stack.len() < wanted.len() {
+            let kind = wanted[stack.len()];
+            stack.push((
                 commands
                     .spawn(PbrBundle {
                         mesh: ent_materials.inventory_thing_mesh.clone(),
-                        material: ent_materials
-                            .inventory_thing_material
+                        material: ent_materials.inventory_thing_material[&kind]
                             .get(stack.len())
                             .cloned()
                             .unwrap_or_default(),
@@ -275,7 +542,8 @@ fn inventory_entities(
                     })
                     .set_parent(ent)
                     .id(),
-            );
+                kind,
+            ));
         }
     }
 }
@@ -307,7 +575,10 @@ fn scaffolding(
     }
     for entity in removed.read() {
         if let Some(child) = scaffolds.remove(&entity) {
-            commands.entity(child).despawn();
+            // Whole building might have been despawned already (e.g. when loading a save)
+            if let Some(mut child) = commands.get_entity(child) {
+                child.despawn();
+            }
         }
     }
 }
@@ -322,7 +593,7 @@ fn upgrade_houses(
             spawn.amount += 5;
         } else {
             commands.entity(event.entity).insert(Spawn {
-                ent_type: EntType::Harvester,
+                ent_type: EntType::HARVESTER,
                 amount: 5,
             });
         }
@@ -330,10 +601,17 @@ fn upgrade_houses(
 }
 
 #[derive(Component)]
-struct PlacementPreview;
+pub struct PlacementPreview;
 
-#[derive(Component)]
-struct PlacementBlocked(bool);
+/// Why the preview can't be placed where it is, none while there is nothing to place
+#[derive(Component, Default)]
+struct PlacementRejections(Option<Vec<Rejection>>);
+
+impl PlacementRejections {
+    fn allow(&self) -> bool {
+        self.0.as_ref().is_some_and(Vec::is_empty)
+    }
+}
 
 #[derive(Component)]
 struct StorageThatHasSpace;
@@ -347,7 +625,7 @@ fn visualize_storage(
     mut commands: Commands,
 ) {
     for (entity, storage, children) in storages.iter() {
-        let new_text = format!("{}/{}", storage.current, storage.max);
+        let new_text = format!("{}/{}", storage.current.total(), storage.max);
         if let Some(child) = children
             .map(|children| children.iter())
             .into_iter()
@@ -380,24 +658,20 @@ fn visualize_storage(
     }
 }
 
+/// Storage that has some of the `K` resource
+#[derive(Component)]
+pub struct Stocks<K>(PhantomData<K>);
+
+/// Still needs some of the `K` resource to be built
 #[derive(Component)]
-struct NonEmptyStorage;
+pub struct Needs<K>(PhantomData<K>);
 
 fn update_storages(
-    q: Query<
-        (
-            Entity,
-            &Storage,
-            Has<StorageThatHasSpace>,
-            Has<NonEmptyStorage>,
-        ),
-        Changed<Storage>,
-    >,
+    q: Query<(Entity, &Storage, Has<StorageThatHasSpace>), Changed<Storage>>,
     mut commands: Commands,
 ) {
-    for (entity, storage, had_space, was_non_empty) in q.iter() {
-        let has_space = storage.current < storage.max;
-        let now_non_empty = storage.current != 0;
+    for (entity, storage, had_space) in q.iter() {
+        let has_space = storage.current.total() < storage.max;
         if has_space != had_space {
             if has_space {
                 commands.entity(entity).insert(StorageThatHasSpace);
@@ -405,94 +679,139 @@ fn update_storages(
                 commands.entity(entity).remove::<StorageThatHasSpace>();
             }
         }
-        if was_non_empty != now_non_empty {
-            if now_non_empty {
-                commands.entity(entity).insert(NonEmptyStorage);
+    }
+}
+
+fn update_kind_markers<K: Kind>(
+    storages: Query<(Entity, &Storage, Has<Stocks<K>>), Changed<Storage>>,
+    needs: Query<(Entity, &NeedsResource, Has<Needs<K>>), Changed<NeedsResource>>,
+    no_longer_needs: Res<Removed<NeedsResource>>,
+    marked: Query<(), (With<Needs<K>>, Without<NeedsResource>)>,
+    mut commands: Commands,
+) {
+    // Before the new needs, an upgrade may have started again since the last one finished
+    for entity in no_longer_needs.iter() {
+        if marked.contains(entity) {
+            commands.entity(entity).remove::<Needs<K>>();
+        }
+    }
+    for (entity, storage, had) in storages.iter() {
+        let has = storage.current[K::KIND] > 0;
+        if has != had {
+            if has {
+                commands.entity(entity).insert(Stocks::<K>(PhantomData));
+            } else {
+                commands.entity(entity).remove::<Stocks<K>>();
+            }
+        }
+    }
+    for (entity, needs, had) in needs.iter() {
+        let has = needs.0[K::KIND] > 0;
+        if has != had {
+            if has {
+                commands.entity(entity).insert(Needs::<K>(PhantomData));
             } else {
-                commands.entity(entity).remove::<NonEmptyStorage>();
+                commands.entity(entity).remove::<Needs<K>>();
             }
         }
     }
 }
 
+/// Builders carry one kind of resource at a time, so every kind gets its own pathfinding
+fn register_resource_kind<K: Kind>(app: &mut App) {
+    app.register_pathfinding_towards::<Stocks<K>>();
+    app.register_pathfinding_towards::<Needs<K>>();
+    app.keep_removed::<NeedsResource>();
+    app.add_systems(
+        FixedUpdate,
+        (
+            update_kind_markers::<K>,
+            ent_movement::<TakingResource<K>, Stocks<K>>,
+            ent_movement::<BringingResource<K>, Needs<K>>,
+            take_resource::<K>,
+            bring_resource::<K>,
+            give_up_resource::<K>,
+        )
+            .in_set(SimulationSet),
+    );
+}
+
 fn update_placing_preview(
     mut preview: Query<
         (
+            Entity,
             &mut Pos,
             &mut Size,
             &mut Handle<Mesh>,
             &mut Handle<StandardMaterial>,
             &mut Visibility,
-            &mut PlacementBlocked,
+            &mut PlacementRejections,
+            &mut Rotation,
         ),
         With<PlacementPreview>,
     >,
     ent_materials: Res<EntMaterials>,
-    roads: Query<(), Or<(With<GhostRoad>, With<Road>)>>,
-    blocking: Query<(), Or<(With<Blocking>, With<BlockingGhost>)>>,
-    tile_map: Res<TileMap>,
+    defs: Res<EntDefs>,
+    placement: Placement,
     cursor: Query<&cursor::WorldPos>,
     state: Res<State<PlayerState>>,
+    moved: Query<&EntType>,
+    road_drag: Res<RoadDrag>,
+    placement_rotation: Res<PlacementRotation>,
+    money: Res<Money>,
     mut commands: Commands,
 ) {
-    let ent_type = match state.get() {
-        &PlayerState::Placing(ent_type) => Some(ent_type),
-        _ => None,
+    let (ent_type, moving) = match *state.get() {
+        // The dragged road has its own preview
+        PlayerState::Placing(EntType::ROAD) if road_drag.start.is_some() => (None, None),
+        PlayerState::Placing(ent_type) => (Some(ent_type), None),
+        PlayerState::Moving(Some(building)) => (moved.get(building).ok().copied(), Some(building)),
+        _ => (None, None),
     };
     match preview.get_single_mut() {
-        Ok((mut pos, mut size, mut mesh, mut material, mut visibility, mut blocked)) => {
-            if let Some(ent_type) = ent_type {
-                let cell = cursor.single().0.floor().as_ivec2() - ent_type.size() / 2;
+        Ok((
+            preview,
+            mut pos,
+            mut size,
+            mut mesh,
+            mut material,
+            mut visibility,
+            mut rejections,
+            mut rotation,
+        )) => {
+            if let Some((ent_type, def)) =
+                ent_type.and_then(|ent_type| Some((ent_type, defs.get(ent_type)?)))
+            {
+                rotation.set_if_neq(placement_rotation.0);
+                let ent_size = rotation.size(def.size());
+                let cell = cursor.single().0.floor().as_ivec2() - ent_size / 2;
                 pos.0 = cell;
-                size.0 = ent_type.size();
+                size.0 = ent_size;
+                match def.shape(*rotation) {
+                    Some(shape) => commands.entity(preview).insert(shape),
+                    None => commands.entity(preview).remove::<Shape>(),
+                };
                 *mesh = ent_materials
                     .meshes
                     .get(&ent_type)
                     .cloned()
                     .unwrap_or_default();
 
-                let rect = IRect::from_corners(cell, cell + ent_type.size() - IVec2::splat(1));
-
-                fn iterate_rect(rect: IRect) -> impl Iterator<Item = IVec2> {
-                    (rect.min.x..=rect.max.x)
-                        .flat_map(move |x| (rect.min.y..=rect.max.y).map(move |y| IVec2::new(x, y)))
-                }
-
-                let is_blocking = |cell| {
-                    tile_map
-                        .entities_at(cell)
-                        .any(|entity| blocking.get(entity).is_ok())
-                };
-
-                let is_road = |cell| {
-                    tile_map
-                        .entities_at(cell)
-                        .any(|entity| roads.get(entity).is_ok())
-                };
-
-                blocked.0 = !match ent_type {
-                    EntType::Road => {
-                        !iterate_rect(rect).any(|cell| is_blocking(cell) || is_road(cell))
-                            && iterate_rect(rect.inset(1))
-                                .filter(|&cell| !rect.contains(cell))
-                                .any(is_road)
+                let mut checked = placement.check(ent_type, cell, *rotation, moving);
+                if let (Some(_), Some(cost)) = (moving, def.cost) {
+                    if !can_afford_move(money.0, cost) {
+                        checked.push(Rejection::NotEnoughMoney(cost));
                     }
-                    _ => {
-                        !iterate_rect(rect).any(is_road)
-                            && !iterate_rect(rect.inset(1)).any(is_blocking)
-                            && iterate_rect(rect.inset(1))
-                                .filter(|&cell| !rect.contains(cell))
-                                .any(is_road)
-                    }
-                };
+                }
+                rejections.0 = Some(checked);
                 *material = ent_materials
                     .materials
                     .get(&(
                         ent_type,
-                        if blocked.0 {
-                            EntState::BlockedPreview
-                        } else {
+                        if rejections.allow() {
                             EntState::Preview
+                        } else {
+                            EntState::BlockedPreview
                         },
                     ))
                     .cloned()
@@ -500,7 +819,7 @@ fn update_placing_preview(
 
                 *visibility = Visibility::Visible;
             } else {
-                blocked.0 = true;
+                rejections.0 = None;
                 *visibility = Visibility::Hidden;
             }
         }
@@ -512,20 +831,35 @@ fn update_placing_preview(
                 },
                 Pos(IVec2::ZERO),
                 Size(IVec2::splat(1)),
+                Rotation::default(),
                 PlacementPreview,
-                PlacementBlocked(true),
+                PlacementRejections::default(),
             ));
         }
     }
 }
 
-#[derive(Resource)]
-struct HavePlaced(bool);
+/// How the building being placed is turned, kept between placements
+#[derive(Resource, Default)]
+struct PlacementRotation(Rotation);
 
-fn init_have_placed(mut placed: ResMut<HavePlaced>) {
-    placed.0 = false;
+fn rotate_placement(
+    keyboard: Res<Input<KeyCode>>,
+    state: Res<State<PlayerState>>,
+    mut rotation: ResMut<PlacementRotation>,
+) {
+    let placing = matches!(
+        state.get(),
+        PlayerState::Placing(..) | PlayerState::Moving(Some(..))
+    );
+    if placing && keyboard.just_pressed(KeyCode::R) {
+        rotation.0 = rotation.0.rotated();
+    }
 }
 
+#[derive(Resource)]
+struct HavePlaced(bool);
+
 fn stop_placing_on_mouse_release(
     placed: Res<HavePlaced>,
     mouse: Res<Input<MouseButton>>,
@@ -539,47 +873,47 @@ fn stop_placing_on_mouse_release(
 
 fn stop_placing_if_not_enough_money(
     money: Res<Money>,
-    costs: Res<EntCosts>,
+    defs: Res<EntDefs>,
     state: Res<State<PlayerState>>,
     mut next_state: ResMut<NextState<PlayerState>>,
 ) {
     let &PlayerState::Placing(ent_type) = state.get() else {
         return;
     };
-    let Some(&ent_cost) = costs.0.get(&ent_type) else {
+    let Some(ent_cost) = defs.get(ent_type).and_then(|def| def.cost) else {
         return;
     };
-    if money.0 < ent_cost {
+    if !money.0.covers(ent_cost) {
         next_state.set(PlayerState::Normal);
     }
 }
 
 fn register_upgrade<U: Upgrade>(app: &mut App) {
+    app.keep_removed::<CanUpgrade<U>>();
     app.add_systems(
-        Update,
+        FixedUpdate,
         (
-            start_assigning_upgrades::<U>,
-            assign_upgrades::<U>,
-            ent_movement::<GoingForUpgrade<U>, CanUpgrade<U>>,
+            (post_upgrade_jobs::<U>, claim_upgrades::<U>).chain(),
             receive_upgrade::<U>,
-        ),
+        )
+            .in_set(SimulationSet),
     );
-    register_building_upgrade::<U>(app);
-    app.register_pathfinding_towards::<CanUpgrade<U>>();
 }
 
 trait Upgrade: Component {
-    fn new() -> Self;
     fn new_ent_type() -> EntType;
 }
 
 fn receive_upgrade<U: Upgrade>(
-    ents: Query<(Entity, &Pos), With<GoingForUpgrade<U>>>,
+    ents: Query<(Entity, &Pos, &UnitState)>,
     mut upgrade_shops: Query<&mut CanUpgrade<U>>,
     tile_map: Res<TileMap>,
     mut commands: Commands,
 ) {
-    for (ent, ent_pos) in ents.iter() {
+    for (ent, ent_pos, &state) in ents.iter() {
+        if state != UnitState::GoingForUpgrade(U::new_ent_type()) {
+            continue;
+        }
         for dir in MOVE_DIRECTIONS {
             let upgrade_shop = tile_map
                 .entities_at(ent_pos.0 + dir)
@@ -590,11 +924,6 @@ fn receive_upgrade<U: Upgrade>(
                     upgrade.upgrades_left -= 1;
                     commands.entity(ent).despawn_recursive();
                     commands.spawn((Pos(ent_pos.0), U::new_ent_type()));
-                    // commands
-                    //     .entity(ent)
-                    //     .insert(U::new())
-                    //     .remove::<(GoingForUpgrade<U>, GoingForAnyUpgrade)>()
-                    //     .insert(Harvesting); // TODO maybe other?
                     if upgrade.upgrades_left == 0 {
                         commands
                             .entity(upgrade_shop_entity)
@@ -607,44 +936,79 @@ fn receive_upgrade<U: Upgrade>(
     }
 }
 
-fn start_assigning_upgrades<U: Upgrade>(
-    q: Query<(Entity, &CanUpgrade<U>), Added<CanUpgrade<U>>>,
+/// One job per upgrade left
+fn post_upgrade_jobs<U: Upgrade>(
+    shops: Query<(Entity, &Pos, &CanUpgrade<U>), Changed<CanUpgrade<U>>>,
+    removed: Res<Removed<CanUpgrade<U>>>,
+    mut board: ResMut<JobBoard>,
+) {
+    for entity in removed.iter() {
+        board.remove(entity, JobKind::Upgrade);
+    }
+    for (entity, pos, can_upgrade) in shops.iter() {
+        board.post(
+            entity,
+            JobKind::Upgrade,
+            pos.0,
+            can_upgrade.upgrades_left as i32,
+        );
+    }
+}
+
+/// Harvesters drop what they are doing for the closest upgrade, if the player wants upgrades first
+fn claim_upgrades<U: Upgrade>(
+    ents: Query<(Entity, &Pos, &UnitState), (With<CanReceiveUpgrades>, With<Idle>, Without<U>)>,
+    shops: Query<(), With<CanUpgrade<U>>>,
+    priorities: Res<JobPriorities>,
+    mut board: ResMut<JobBoard>,
     mut commands: Commands,
 ) {
-    for (entity, can_upgrade) in q.iter() {
-        commands.entity(entity).insert(NeedToAssignUpgrades::<U> {
-            unassigned: can_upgrade.upgrades_left,
-            phantom_data: PhantomData,
-        });
+    if !priorities.prefers(JobKind::Upgrade, JobKind::Harvest) {
+        return;
+    }
+    for (ent, pos, &state) in ents.iter() {
+        if !matches!(state, UnitState::Harvesting | UnitState::Storing) {
+            continue;
+        }
+        let Some(shop) = board.find(&priorities, JobKind::Upgrade, pos.0, |shop| {
+            shops.contains(shop)
+        }) else {
+            break;
+        };
+        board.claim(shop, JobKind::Upgrade, 1);
+        commands
+            .entity(ent)
+            .transition(state, UnitState::GoingForUpgrade(U::new_ent_type()))
+            .insert((
+                Claim {
+                    target: shop,
+                    kind: JobKind::Upgrade,
+                    amount: 1,
+                },
+                WalkTo(PathTarget::Entity(shop)),
+            ));
     }
 }
-fn assign_upgrades<U: Upgrade>(
-    ents: Query<
-        Entity,
-        (
-            With<CanReceiveUpgrades>,
-            Without<U>,
-            Without<GoingForAnyUpgrade>,
-        ),
-    >,
-    mut upgrade_shops: Query<(Entity, &mut NeedToAssignUpgrades<U>)>,
+
+/// Back to work when the upgrade can't be reached or ran out before the crab got there
+fn give_up_upgrades(
+    ents: Query<(Entity, &Pos, &UnitState, &Claim), (With<Idle>, Without<WalkTo>)>,
+    board: Res<JobBoard>,
+    tile_map: Res<TileMap>,
     mut commands: Commands,
 ) {
-    let mut ents_to_upgrade = ents.iter();
-    for (shop_entity, mut shops) in upgrade_shops.iter_mut() {
-        if shops.unassigned == 0 {
-            commands
-                .entity(shop_entity)
-                .remove::<NeedToAssignUpgrades<U>>();
-        } else if let Some(ent) = ents_to_upgrade.next() {
-            shops.unassigned -= 1;
+    for (ent, pos, &state, claim) in ents.iter() {
+        if !matches!(state, UnitState::GoingForUpgrade(_)) {
+            continue;
+        }
+        let next_to_it = MOVE_DIRECTIONS
+            .into_iter()
+            .any(|dir| tile_map.entities_at(pos.0 + dir).any(|e| e == claim.target));
+        if !next_to_it || board.get(claim.target, JobKind::Upgrade).is_none() {
             commands
                 .entity(ent)
-                .insert((GoingForAnyUpgrade, GoingForUpgrade::<U>(PhantomData)))
-                .remove::<Harvesting>()
-                .remove::<Storing>(); // TODO what if I have more state??
-        } else {
-            break;
+                .transition(state, UnitState::Harvesting)
+                .remove::<Claim>();
         }
     }
 }
@@ -653,13 +1017,18 @@ fn assign_upgrades<U: Upgrade>(
 struct CanReceiveUpgrades;
 
 #[derive(Component)]
-struct Spawn {
-    ent_type: EntType,
-    amount: usize,
+pub struct Spawn {
+    pub ent_type: EntType,
+    pub amount: usize,
 }
 
+/// Demolished building put back with what it had stored, its crabs already live somewhere else
+#[derive(Component)]
+pub struct Rebuilt(pub Resources);
+
+/// Building the crab was spawned by
 #[derive(Component)]
-struct Home(Entity);
+pub struct Home(pub Entity);
 
 fn spawn_ents(
     mut spawners: Query<(Entity, &Pos, Option<&Size>, &mut Spawn)>,
@@ -691,37 +1060,32 @@ fn spawn_ents(
 }
 
 #[derive(Component)]
-struct ProvidePopulation(usize);
-
-#[derive(Component)]
-struct CanUpgrade<T> {
-    upgrades_left: usize,
-    phantom_data: PhantomData<T>,
-}
+pub struct ProvidePopulation(usize);
 
 #[derive(Component)]
-struct NeedToAssignUpgrades<T> {
-    unassigned: usize,
-    phantom_data: PhantomData<T>,
+pub struct CanUpgrade<T> {
+    pub upgrades_left: usize,
+    pub phantom_data: PhantomData<T>,
 }
 
 #[derive(Component)]
-struct InventoryUpgrade;
+pub struct InventoryUpgrade;
 
 impl Upgrade for InventoryUpgrade {
-    fn new() -> Self {
-        Self
-    }
     fn new_ent_type() -> EntType {
-        EntType::GoldHarvester
+        EntType::GOLD_HARVESTER
     }
 }
 
 impl<U: Upgrade> BuildingUpgrade for U {
     fn add_systems(app: &mut App) {
-        app.add_systems(Update, assign_more_upgrades::<U>);
+        app.add_systems(
+            FixedUpdate,
+            assign_more_upgrades::<U>
+                .after(perform_building_upgrades::<Self>)
+                .in_set(SimulationSet),
+        );
     }
-    const BASE_COST: i32 = 100;
 }
 
 struct InsertOrModify<C> {
@@ -766,13 +1130,6 @@ fn assign_more_upgrades<U: Upgrade>(
     mut commands: Commands,
 ) {
     for event in events.read() {
-        commands.entity(event.entity).insert_or_modify(
-            NeedToAssignUpgrades::<U> {
-                unassigned: 5,
-                phantom_data: PhantomData,
-            },
-            |existing| existing.unassigned += 5,
-        );
         commands.entity(event.entity).insert_or_modify(
             CanUpgrade::<U> {
                 upgrades_left: 5,
@@ -784,23 +1141,14 @@ fn assign_more_upgrades<U: Upgrade>(
 }
 
 #[derive(Component)]
-struct BuilderUpgrade;
+pub struct BuilderUpgrade;
 
 impl Upgrade for BuilderUpgrade {
-    fn new() -> Self {
-        Self
-    }
     fn new_ent_type() -> EntType {
-        EntType::Builder
+        EntType::BUILDER
     }
 }
 
-#[derive(Component)]
-struct GoingForAnyUpgrade;
-
-#[derive(Component)]
-struct GoingForUpgrade<T>(PhantomData<T>);
-
 #[derive(Component)]
 struct CanBuild;
 
@@ -811,7 +1159,7 @@ pub struct BuildingUpgradeComponent<T> {
 }
 
 impl<T> BuildingUpgradeComponent<T> {
-    fn new() -> Self {
+    pub fn new() -> Self {
         Self {
             current_level: 0,
             phantom_data: PhantomData,
@@ -819,111 +1167,217 @@ impl<T> BuildingUpgradeComponent<T> {
     }
 }
 
+impl<T> Default for BuildingUpgradeComponent<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub trait BuildingUpgrade: Send + Sync + 'static {
     fn add_systems(app: &mut App);
-    const BASE_COST: i32;
 }
 
-fn register_building_upgrade<T: BuildingUpgrade>(app: &mut App) {
-    app.add_systems(Update, tooltip_upgrade::<T>);
-    app.add_systems(Update, make_hoverable::<T>);
-    app.add_systems(Update, perform_building_upgrades::<T>);
-    app.add_systems(PostUpdate, click_to_upgrade_building::<T>);
-    app.add_systems(Update, update_upgrade_transforms::<T>);
+pub fn register_building_upgrade<T: BuildingUpgrade>(app: &mut App) {
+    app.add_systems(
+        FixedUpdate,
+        perform_building_upgrades::<T>.in_set(SimulationSet),
+    );
     app.add_event::<BuildingUpgradeEvent<T>>();
     T::add_systems(app);
 }
 
+pub fn register_building_upgrade_visuals<T: BuildingUpgrade>(app: &mut App) {
+    app.add_systems(
+        Update,
+        tooltip_upgrade::<T>.run_if(in_state(PlayerState::Normal)),
+    );
+    app.add_systems(Update, (make_hoverable::<T>, stop_hovering_upgraded::<T>));
+    app.add_systems(
+        PostUpdate,
+        (
+            click_to_upgrade_building::<T>,
+            right_click_to_cancel_upgrade::<T>,
+        )
+            .run_if(in_state(PlayerState::Normal)),
+    );
+    app.add_systems(Update, update_upgrade_transforms::<T>);
+}
+
 fn tooltip_upgrade<T: BuildingUpgrade>(
     mut q: Query<&mut Text, With<Tooltip>>,
-    hovered: Query<&BuildingUpgradeComponent<T>, (With<Hovered>, Without<NeedsResource>)>,
+    hovered: Query<
+        (&EntType, &BuildingUpgradeComponent<T>),
+        (With<Hovered>, Without<NeedsResource>),
+    >,
+    defs: Res<EntDefs>,
 ) {
-    if let Some(upgrade) = hovered.iter().next() {
-        let cost = (upgrade.current_level + 1) * T::BASE_COST;
-        q.single_mut().sections[0].value = cost.to_string();
+    let Some((&ent_type, upgrade)) = hovered.iter().next() else {
+        return;
+    };
+    let cost = defs
+        .get(ent_type)
+        .and_then(|def| def.level_cost(upgrade.current_level + 1));
+    if let Some(cost) = cost {
+        q.single_mut().sections[0].value = cost.to_string();
     }
 }
 
 fn make_hoverable<T: BuildingUpgrade>(
-    q: Query<Entity, Added<BuildingUpgradeComponent<T>>>,
+    q: Query<(Entity, &EntType, &BuildingUpgradeComponent<T>), Added<BuildingUpgradeComponent<T>>>,
+    defs: Res<EntDefs>,
     mut commands: Commands,
 ) {
-    for entity in q.iter() {
-        commands.entity(entity).insert(ScaleOnHover);
+    for (entity, &ent_type, upgrade) in q.iter() {
+        let max_upgrades = defs.get(ent_type).map_or(0, |def| def.max_upgrades);
+        if upgrade.current_level < max_upgrades as _ {
+            commands.entity(entity).insert(ScaleOnHover);
+        }
     }
 }
 
 #[derive(Component)]
-pub struct BuildingUpgradeToPerform<T>(PhantomData<T>);
+pub struct BuildingUpgradeToPerform<T>(pub PhantomData<T>);
 
 fn perform_building_upgrades<T: BuildingUpgrade>(
     buildings: Query<
-        (
-            Entity,
-            &EntType,
-            &BuildingUpgradeComponent<T>,
-            &NeedsResource,
-        ),
+        (Entity, &NeedsResource),
         (Changed<NeedsResource>, With<BuildingUpgradeToPerform<T>>),
     >,
     mut commands: Commands,
     mut events: EventWriter<BuildingUpgradeEvent<T>>,
 ) {
-    for (entity, ent_type, upgrade, needs) in buildings.iter() {
-        if needs.0 == 0 {
+    for (entity, needs) in buildings.iter() {
+        if needs.0.is_empty() {
             commands.entity(entity).remove::<NeedsResource>();
             events.send(BuildingUpgradeEvent {
                 entity,
                 phantom_data: PhantomData,
             });
-            if upgrade.current_level >= ent_type.max_upgrades() as _ {
-                commands.entity(entity).remove::<ScaleOnHover>();
-            }
+        }
+    }
+}
+
+fn stop_hovering_upgraded<T: BuildingUpgrade>(
+    mut events: EventReader<BuildingUpgradeEvent<T>>,
+    buildings: Query<(&EntType, &BuildingUpgradeComponent<T>)>,
+    defs: Res<EntDefs>,
+    mut commands: Commands,
+) {
+    for event in events.read() {
+        let Ok((&ent_type, upgrade)) = buildings.get(event.entity) else {
+            continue;
+        };
+        let max_upgrades = defs.get(ent_type).map_or(0, |def| def.max_upgrades);
+        if upgrade.current_level >= max_upgrades as _ {
+            commands.entity(event.entity).remove::<ScaleOnHover>();
         }
     }
 }
 
 fn click_to_upgrade_building<T: BuildingUpgrade>(
     input: Res<Input<MouseButton>>,
-    mut buildings: Query<
-        (Entity, &EntType, &mut BuildingUpgradeComponent<T>),
-        (Without<NeedsResource>, With<Hovered>),
+    buildings: Query<
+        Entity,
+        (
+            With<BuildingUpgradeComponent<T>>,
+            Without<NeedsResource>,
+            With<Hovered>,
+        ),
     >,
-    mut money: ResMut<Money>,
     mut commands: Commands,
 ) {
     if !input.just_pressed(MouseButton::Left) {
         return;
     }
-    let Some((building, ent_type, mut upgrades)) = buildings.iter_mut().next() else {
+    let Some(building) = buildings.iter().next() else {
         return;
     };
-    if upgrades.current_level >= ent_type.max_upgrades() as _ {
+    commands.add(Perform(Action::Upgrade(
+        building,
+        UpgradeCommands::of::<T>(),
+    )));
+}
+
+fn right_click_to_cancel_upgrade<T: BuildingUpgrade>(
+    input: Res<Input<MouseButton>>,
+    buildings: Query<
+        Entity,
+        (
+            With<BuildingUpgradeToPerform<T>>,
+            With<NeedsResource>,
+            With<Hovered>,
+        ),
+    >,
+    mut commands: Commands,
+) {
+    if !input.just_pressed(MouseButton::Right) {
         return;
     }
-    let cost = (upgrades.current_level + 1) * T::BASE_COST;
-    if money.0 < cost {
-        return;
+    for building in buildings.iter() {
+        commands.add(Perform(Action::CancelUpgrade(
+            building,
+            UpgradeCommands::of::<T>(),
+        )));
+    }
+}
+
+/// Pay for the next level of the building, builders will bring the resources after that.
+///
+/// Nothing happens if there is not enough money or the building can't be upgraded right now.
+pub struct StartBuildingUpgrade<T>(pub PhantomData<T>);
+
+impl<T: BuildingUpgrade> EntityCommand for StartBuildingUpgrade<T> {
+    fn apply(self, id: Entity, world: &mut World) {
+        let entity = world.entity(id);
+        if entity.contains::<NeedsResource>() {
+            return;
+        }
+        let (Some(&ent_type), Some(upgrades)) = (
+            entity.get::<EntType>(),
+            entity.get::<BuildingUpgradeComponent<T>>(),
+        ) else {
+            return;
+        };
+        let Some(cost) = world
+            .resource::<EntDefs>()
+            .get(ent_type)
+            .and_then(|def| def.level_cost(upgrades.current_level + 1))
+        else {
+            return;
+        };
+        let mut money = world.resource_mut::<Money>();
+        if !money.0.covers(cost) {
+            return;
+        }
+        money.0 -= cost;
+        let mut entity = world.entity_mut(id);
+        entity.insert((
+            NeedsResource(cost, cost),
+            BuildingUpgradeToPerform::<T>(PhantomData),
+        ));
+        entity
+            .get_mut::<BuildingUpgradeComponent<T>>()
+            .unwrap()
+            .current_level += 1;
     }
-    money.0 -= cost;
-    commands.entity(building).insert((
-        NeedsResource(cost, cost),
-        BuildingUpgradeToPerform::<T>(PhantomData),
-    ));
-    upgrades.current_level += 1;
 }
 
+/// Sent once builders have brought everything the next level of the building needs
 #[derive(Event)]
-struct BuildingUpgradeEvent<T> {
-    entity: Entity,
+pub struct BuildingUpgradeEvent<T> {
+    pub entity: Entity,
     phantom_data: PhantomData<T>,
 }
 
 impl BuildingUpgrade for Storage {
     fn add_systems(app: &mut App) {
-        app.add_systems(Update, building_upgrade_storage);
+        app.add_systems(
+            FixedUpdate,
+            building_upgrade_storage
+                .after(perform_building_upgrades::<Self>)
+                .in_set(SimulationSet),
+        );
     }
-    const BASE_COST: i32 = 200;
 }
 
 fn building_upgrade_storage(
@@ -937,234 +1391,200 @@ fn building_upgrade_storage(
     }
 }
 
-struct MonumentUpgrade;
+pub struct MonumentUpgrade;
 
 impl BuildingUpgrade for MonumentUpgrade {
     fn add_systems(_app: &mut App) {}
-    const BASE_COST: i32 = 1000;
 }
 
 fn ent_types(
-    q: Query<(Entity, &Pos, &EntType), Added<EntType>>,
-    ent_materials: Res<EntMaterials>,
+    q: Query<(Entity, &EntType, Option<&Rotation>, Option<&Rebuilt>), Added<EntType>>,
+    defs: Res<EntDefs>,
     mut commands: Commands,
 ) {
-    for (entity, pos, ent_type) in q.iter() {
+    for (entity, &ent_type, rotation, rebuilt) in q.iter() {
+        let Some(def) = defs.get(ent_type) else {
+            warn!("{} is not defined", ent_type.name());
+            continue;
+        };
+        let rotation = rotation.copied().unwrap_or_default();
+        let mut entity_commands = commands.entity(entity);
+        if let Some(max) = def.storage {
+            entity_commands.insert(Storage {
+                current: match rebuilt {
+                    Some(rebuilt) => rebuilt.0,
+                    None if ent_type == EntType::BASE => INITIAL_MONEY,
+                    None => Resources::ZERO,
+                },
+                max,
+            });
+        }
+        if let Some(max) = def.inventory {
+            entity_commands.insert(Inventory {
+                current: Resources::ZERO,
+                max,
+            });
+        }
+        if def.population > 0 {
+            entity_commands.insert(ProvidePopulation(def.population));
+        }
+        if let (Some((ent_type, amount)), None) = (def.spawn, rebuilt) {
+            entity_commands.insert(Spawn { ent_type, amount });
+        }
+        entity_commands.remove::<Rebuilt>();
+        if def.diagonal_movement {
+            entity_commands.insert(DiagonalMovement);
+        }
+        entity_commands.insert(Size(rotation.size(def.size())));
+        if let Some(shape) = def.shape(rotation) {
+            entity_commands.insert(shape);
+        }
+        // Buildings get the rest from their registration
         match ent_type {
-            EntType::Monument => {
-                commands.entity(entity).insert((
-                    Blocking,
-                    {
-                        let mut up = BuildingUpgradeComponent::<MonumentUpgrade>::new();
-                        up.current_level = 0;
-                        up
-                    },
-                    BavyBirds(vec![]),
-                ));
-            }
-            EntType::Storage => {
-                let level = commands
-                    .spawn(PbrBundle {
-                        mesh: ent_materials.level_mesh.clone(),
-                        material: ent_materials.level_material.clone(),
-                        transform: Transform::from_scale(Vec3::new(
-                            ent_type.size().x as f32,
-                            1.0,
-                            ent_type.size().y as f32,
-                        ))
-                        .with_translation(
-                            (pos.0.as_vec2() + ent_type.size().as_vec2() / 2.0)
-                                .extend(0.0)
-                                .xzy(),
-                        ),
-                        ..default()
-                    })
-                    .id();
-                commands.entity(entity).insert((
-                    Storage {
-                        current: 0,
-                        max: 50,
-                    },
-                    Blocking,
-                    BuildingUpgradeComponent::<Storage>::new(),
-                    StorageLevelChild(level),
-                ));
-            }
-            EntType::Road => {
-                commands.entity(entity).insert(Road);
-            }
-            EntType::Harvester => {
-                commands.entity(entity).insert((
-                    CanMove,
-                    Inventory { current: 0, max: 1 },
-                    InventoryEntities(vec![]),
-                    Idle,
-                    CanHavest,
-                    UsesPopulation,
-                    Harvesting,
-                    CanReceiveUpgrades,
-                ));
+            EntType::HARVESTER => {
+                commands
+                    .entity(entity)
+                    .insert((CanMove, Idle, CanHavest, UsesPopulation, CanReceiveUpgrades))
+                    .start_in(UnitState::Harvesting);
             }
-            EntType::GoldHarvester => {
-                commands.entity(entity).insert((
-                    CanMove,
-                    Inventory {
-                        current: 0,
-                        max: 10,
-                    },
-                    InventoryEntities(vec![]),
-                    Idle,
-                    CanHavest,
-                    UsesPopulation,
-                    Harvesting,
-                    Gold,
-                ));
+            EntType::GOLD_HARVESTER => {
+                commands
+                    .entity(entity)
+                    .insert((CanMove, Idle, CanHavest, UsesPopulation, Gold))
+                    .start_in(UnitState::Harvesting);
             }
-            EntType::UpgradeInventory => {
-                commands.entity(entity).insert((
-                    Blocking,
-                    CanUpgrade::<InventoryUpgrade> {
-                        upgrades_left: 5,
-                        phantom_data: PhantomData,
-                    },
-                    BuildingUpgradeComponent::<InventoryUpgrade>::new(),
-                ));
+            EntType::BUILDER => {
+                commands
+                    .entity(entity)
+                    .insert((CanMove, Idle, CanBuild, CanHavest))
+                    .start_in(UnitState::ChoosingResource);
             }
-            EntType::BuilderAcademy => {
-                commands.entity(entity).insert((
-                    Blocking,
-                    CanUpgrade::<BuilderUpgrade> {
-                        upgrades_left: 5,
-                        phantom_data: PhantomData,
-                    },
-                    BuildingUpgradeComponent::<BuilderUpgrade>::new(),
-                ));
+            _ => {}
+        }
+    }
+}
+
+fn ent_visuals(
+    q: Query<(Entity, &Pos, &EntType), Added<EntType>>,
+    ent_materials: Res<EntMaterials>,
+    defs: Res<EntDefs>,
+    mut commands: Commands,
+) {
+    for (entity, pos, ent_type) in q.iter() {
+        let Some(def) = defs.get(*ent_type) else {
+            continue;
+        };
+        match *ent_type {
+            EntType::MONUMENT => {
+                commands.entity(entity).insert(BavyBirds(vec![]));
             }
-            EntType::Base => {
+            EntType::STORAGE | EntType::BASE => {
                 let level = commands
                     .spawn(PbrBundle {
                         mesh: ent_materials.level_mesh.clone(),
                         material: ent_materials.level_material.clone(),
                         transform: Transform::from_scale(Vec3::new(
-                            ent_type.size().x as f32,
+                            def.size().x as f32,
                             1.0,
-                            ent_type.size().y as f32,
+                            def.size().y as f32,
                         ))
                         .with_translation(
-                            (pos.0.as_vec2() + ent_type.size().as_vec2() / 2.0)
+                            (pos.0.as_vec2() + def.size().as_vec2() / 2.0)
                                 .extend(0.0)
                                 .xzy(),
                         ),
                         ..default()
                     })
                     .id();
-                commands.entity(entity).insert((
-                    Storage {
-                        current: INITIAL_MONEY,
-                        max: 100,
-                    },
-                    Blocking,
-                    ProvidePopulation(5),
-                    StorageLevelChild(level),
-                    Road,
-                ));
+                commands.entity(entity).insert(StorageLevelChild(level));
             }
-            EntType::House => {
-                commands.entity(entity).insert((
-                    Blocking,
-                    ProvidePopulation(5),
-                    Spawn {
-                        ent_type: EntType::Harvester,
-                        amount: 5,
-                    },
-                    BuildingUpgradeComponent::<ProvidePopulation>::new(),
-                ));
-            }
-            EntType::Builder => {
-                commands.entity(entity).insert((
-                    CanMove,
-                    Idle,
-                    CanBuild,
-                    Inventory { current: 0, max: 5 },
-                    InventoryEntities(vec![]),
-                    TakingResource,
-                ));
+            EntType::HARVESTER | EntType::GOLD_HARVESTER | EntType::BUILDER => {
+                commands.entity(entity).insert(InventoryEntities(vec![]));
             }
+            _ => {}
         }
-        commands.entity(entity).insert((
-            MaterialMeshBundle {
-                mesh: ent_materials
-                    .meshes
-                    .get(ent_type)
-                    .cloned()
-                    .unwrap_or_default(),
-                material: ent_materials
-                    .materials
-                    .get(&(*ent_type, EntState::Normal))
-                    .cloned()
-                    .unwrap_or_default(),
-                transform: Transform::from_xyz(0.0, ent_type.height(), 0.0),
-                ..default()
-            },
-            Size(ent_type.size()),
-        ));
+        commands.entity(entity).insert(MaterialMeshBundle {
+            mesh: ent_materials
+                .meshes
+                .get(ent_type)
+                .cloned()
+                .unwrap_or_default(),
+            material: ent_materials
+                .materials
+                .get(&(*ent_type, EntState::Normal))
+                .cloned()
+                .unwrap_or_default(),
+            transform: Transform::from_xyz(0.0, def.height, 0.0),
+            ..default()
+        });
     }
 }
 
 #[derive(Component)]
-struct Storage {
-    current: i32,
-    max: i32,
+pub struct Storage {
+    pub current: Resources,
+    /// Total of all kinds
+    pub max: i32,
+}
+
+/// Ground is flat and fertile around the base, further out it rises and falls
+fn elevation(noise: &Noise, pos: IVec2) -> f32 {
+    let mut elevation = 0.0;
+    let mut scale = 80.0;
+    let mut amplitude = 1.0;
+    for octave in 0..4 {
+        let offset = Vec2::splat(2000.0 + 100.0 * octave as f32);
+        elevation += noise.get(pos.as_vec2() / scale + offset) * amplitude;
+        scale /= 2.0;
+        amplitude /= 2.0;
+    }
+    elevation * (pos.as_vec2().length() / 30.0).min(1.0)
 }
 
 fn generate_chunks(
     noise: Res<Noise>,
-    ent_materials: Res<EntMaterials>,
+    defs: Res<EntDefs>,
+    mut tile_map: ResMut<TileMap>,
     mut events: EventReader<crate::chunks::GenerateChunk>,
+    mut terrain_events: EventReader<crate::chunks::GenerateTerrain>,
     mut commands: Commands,
 ) {
+    let generate_terrain = |tile_map: &mut TileMap, chunk| {
+        let terrain = TerrainChunk::generate(chunk, |pos| elevation(&noise, pos));
+        tile_map.terrain_mut().insert(chunk, terrain);
+    };
+    for event in terrain_events.read() {
+        generate_terrain(&mut tile_map, event.0);
+    }
+
     let mut pixels = Vec::new();
 
     for event in events.read() {
+        generate_terrain(&mut tile_map, event.pos());
         let rect = event.rect();
         for x in rect.min.x..rect.max.x {
             for y in rect.min.y..rect.max.y {
                 let pos = IVec2::new(x, y);
                 if (pos.x == 0 || pos.y == 0) && pos.length_squared() == 25 {
-                    commands.spawn((Pos(pos), EntType::Builder));
+                    commands.spawn((Pos(pos), EntType::BUILDER));
                 }
-                if pos == IVec2::ZERO - EntType::Base.size() / 2 {
-                    commands.spawn((Pos(pos), EntType::Base));
+                if Some(pos) == defs.get(EntType::BASE).map(|def| -def.size() / 2) {
+                    commands.spawn((Pos(pos), EntType::BASE));
                 }
-                if pos.length_squared() > 100 {
-                    pixels.push((
-                        MaterialMeshBundle {
-                            // TODO?
-                            // color: Color::hsl(
-                            //     thread_rng().gen_range({
-                            //         let off = 20.0;
-                            //         120.0 - off..120.0 + off
-                            //     }),
-                            //     0.7,
-                            //     0.2,
-                            // ),
-                            mesh: ent_materials.harvestable_mesh.clone(),
-                            material: ent_materials.harvestable_material.clone(),
-                            transform: Transform::from_rotation(Quat::from_rotation_y(
-                                thread_rng().gen_range(0.0..2.0 * std::f32::consts::PI),
-                            )),
-                            ..default()
-                        },
-                        Pos(pos),
-                        Harvestable(
-                            (Vec2::new(x as f32, y as f32).length() / 20.0
-                                + noise.get(pos.as_vec2() / 5.0) * 5.0)
-                                .max(0.0) as i32
-                                + 1,
-                        ),
-                        Blocking,
-                    ));
+                if pos.length_squared() <= 100 {
+                    continue;
                 }
+                let Some(kind) = deposit_kind(&noise, pos, tile_map.terrain().get(pos)) else {
+                    continue;
+                };
+                pixels.push(harvestable_bundle(
+                    pos,
+                    kind,
+                    (Vec2::new(x as f32, y as f32).length() / 20.0
+                        + noise.get(pos.as_vec2() / 5.0) * 5.0)
+                        .max(0.0) as i32
+                        + 1,
+                ));
             }
         }
     }
@@ -1172,29 +1592,92 @@ fn generate_chunks(
     commands.spawn_batch(pixels);
 }
 
+/// Forest grows on fertile ground with patches of stone, rock has stone and
+/// patches of gold away from the base. Water and sand stay empty.
+fn deposit_kind(noise: &Noise, pos: IVec2, terrain: Terrain) -> Option<ResourceKind> {
+    let pos = pos.as_vec2();
+    match terrain {
+        Terrain::Water | Terrain::Sand => None,
+        Terrain::Fertile => Some(if noise.get(pos / 15.0 - Vec2::splat(1000.0)) > 0.3 {
+            ResourceKind::Stone
+        } else {
+            ResourceKind::Wood
+        }),
+        Terrain::Rock => Some(
+            if pos.length() > 40.0 && noise.get(pos / 10.0 + Vec2::splat(1000.0)) > 0.2 {
+                ResourceKind::Gold
+            } else {
+                ResourceKind::Stone
+            },
+        ),
+    }
+}
+
+pub fn harvestable_bundle(pos: IVec2, kind: ResourceKind, amount: i32) -> impl Bundle {
+    (Pos(pos), Harvestable { kind, amount }, Blocking)
+}
+
+fn harvestable_visuals(
+    q: Query<(Entity, &Pos, &Harvestable), Added<Harvestable>>,
+    seed: Res<WorldSeed>,
+    ent_materials: Res<EntMaterials>,
+    mut commands: Commands,
+) {
+    let visuals: Vec<_> = q
+        .iter()
+        .map(|(entity, pos, harvestable)| {
+            (
+                entity,
+                MaterialMeshBundle {
+                    // TODO?
+                    // color: Color::hsl(
+                    //     thread_rng().gen_range({
+                    //         let off = 20.0;
+                    //         120.0 - off..120.0 + off
+                    //     }),
+                    //     0.7,
+                    //     0.2,
+                    // ),
+                    mesh: ent_materials.harvestable_mesh.clone(),
+                    material: ent_materials.harvestable_material[&harvestable.kind].clone(),
+                    transform: Transform::from_xyz(0.0, harvestable.amount as f32 - 1.0, 0.0)
+                        .with_rotation(Quat::from_rotation_y(
+                            seed.rng_at(pos.0)
+                                .gen_range(0.0..2.0 * std::f32::consts::PI),
+                        )),
+                    ..default()
+                },
+            )
+        })
+        .collect();
+    commands.insert_or_spawn_batch(visuals);
+}
+
 #[derive(Component)]
 struct CanHavest;
 
 #[derive(Component)]
-struct Inventory {
-    current: i32,
-    max: i32,
+pub struct Inventory {
+    pub current: Resources,
+    /// Total of all kinds
+    pub max: i32,
 }
 
+/// Carrying what the crab harvested itself, see [ent_store]
 #[derive(Component)]
-struct Harvesting;
-
-#[derive(Component)]
-struct Storing;
+struct Harvested;
 
 fn ent_store(
-    mut ents: Query<(Entity, &Pos, &mut Inventory), (With<Idle>, With<Storing>)>,
+    mut ents: Query<
+        (Entity, &Pos, &mut Inventory, Has<CanBuild>, Has<Harvested>),
+        (With<Idle>, With<Storing>),
+    >,
     mut storage: Query<&mut Storage>,
     tile_map: Res<TileMap>,
     mut money: ResMut<Money>,
     mut commands: Commands,
 ) {
-    for (ent, ent_pos, mut inventory) in ents.iter_mut() {
+    for (ent, ent_pos, mut inventory, builder, harvested) in ents.iter_mut() {
         for storage_entity in MOVE_DIRECTIONS
             .into_iter()
             .flat_map(|dir| tile_map.entities_at(ent_pos.0 + dir))
@@ -1202,31 +1685,223 @@ fn ent_store(
             let Ok(mut storage) = storage.get_mut(storage_entity) else {
                 continue;
             };
-            let amount_to_store = inventory.current.min(storage.max - storage.current).max(0);
-            inventory.current -= amount_to_store;
-            storage.current += amount_to_store;
-            money.0 += amount_to_store;
-            if inventory.current == 0 {
-                commands.entity(ent).remove::<Storing>().insert(Harvesting);
+            for kind in ResourceKind::ALL {
+                let amount_to_store = inventory.current[kind]
+                    .min(storage.max - storage.current.total())
+                    .max(0);
+                inventory.current[kind] -= amount_to_store;
+                storage.current[kind] += amount_to_store;
+                // Builders only bring back what was already paid for, unless they harvested it
+                if !builder || harvested {
+                    money.0[kind] += amount_to_store;
+                }
+            }
+            // Whatever is left is for the next storage with space
+            commands.entity(ent).remove::<Claim>();
+            if inventory.current.is_empty() {
+                commands.entity(ent).remove::<Harvested>();
+                let next = if builder {
+                    UnitState::ChoosingResource
+                } else {
+                    UnitState::Harvesting
+                };
+                commands.entity(ent).transition(UnitState::Storing, next);
                 break;
             }
         }
     }
 }
 
-#[derive(Component)]
-struct TakingResource;
+/// Storages want the space they have left filled
+fn post_store_jobs(
+    storages: Query<(Entity, &Pos, &Storage), Changed<Storage>>,
+    removed: Res<Removed<Storage>>,
+    mut board: ResMut<JobBoard>,
+) {
+    for entity in removed.iter() {
+        board.remove(entity, JobKind::Store);
+    }
+    for (entity, pos, storage) in storages.iter() {
+        board.post(
+            entity,
+            JobKind::Store,
+            pos.0,
+            storage.max - storage.current.total(),
+        );
+    }
+}
 
-#[derive(Component)]
-struct BringingResource;
+/// Crabs with something to store claim space in the closest storage that has some left, they
+/// follow the flow field to any storage with space if all of it is claimed
+fn claim_storage(
+    ents: Query<
+        (Entity, &Pos, &Inventory),
+        (With<Idle>, With<Storing>, Without<Claim>, Without<WalkTo>),
+    >,
+    storages: Query<(), With<Storage>>,
+    priorities: Res<JobPriorities>,
+    mut board: ResMut<JobBoard>,
+    mut commands: Commands,
+) {
+    for (ent, pos, inventory) in ents.iter() {
+        let Some(storage) = board.find(&priorities, JobKind::Store, pos.0, |storage| {
+            storages.contains(storage)
+        }) else {
+            break;
+        };
+        let amount = inventory.current.total();
+        board.claim(storage, JobKind::Store, amount);
+        commands.entity(ent).insert((
+            Claim {
+                target: storage,
+                kind: JobKind::Store,
+                amount,
+            },
+            WalkTo(PathTarget::Entity(storage)),
+        ));
+    }
+}
+
+/// Builders harvest while the player puts harvesting before building, once it doesn't come first
+/// anymore they store what they have and go back to building
+fn builders_back_to_building(
+    builders: Query<(Entity, &Inventory), (With<CanBuild>, With<Idle>, With<Harvesting>)>,
+    priorities: Res<JobPriorities>,
+    mut commands: Commands,
+) {
+    if harvest_before_building(&priorities) {
+        return;
+    }
+    for (entity, inventory) in builders.iter() {
+        let next = if inventory.current.is_empty() {
+            UnitState::ChoosingResource
+        } else {
+            UnitState::Storing
+        };
+        commands
+            .entity(entity)
+            .transition(UnitState::Harvesting, next);
+    }
+}
+
+fn harvest_before_building(priorities: &JobPriorities) -> bool {
+    priorities.enabled(JobKind::Harvest) && priorities.prefers(JobKind::Harvest, JobKind::Build)
+}
+
+/// Construction sites and upgrades in progress want every resource they still need delivered
+fn post_build_jobs(
+    sites: Query<(Entity, &Pos, &NeedsResource), Changed<NeedsResource>>,
+    removed: Res<Removed<NeedsResource>>,
+    mut board: ResMut<JobBoard>,
+) {
+    for entity in removed.iter() {
+        board.remove(entity, JobKind::Build);
+    }
+    for (entity, pos, needs) in sites.iter() {
+        board.post(entity, JobKind::Build, pos.0, needs.0.total());
+    }
+}
+
+/// Builders claim the first construction site in the queue they can bring something to and go for the kind it needs
+/// that is needed the most overall and is actually stored somewhere
+fn choose_resource_to_take(
+    builders: Query<(Entity, &Inventory), (With<Idle>, With<ChoosingResource>)>,
+    carried: Query<&Inventory, With<CanBuild>>,
+    needs: Query<&NeedsResource>,
+    storages: Query<&Storage>,
+    priorities: Res<JobPriorities>,
+    queue: Res<ConstructionQueue>,
+    mut board: ResMut<JobBoard>,
+    mut commands: Commands,
+) {
+    if builders.is_empty() {
+        return;
+    }
+    let mut wanted = needs
+        .iter()
+        .fold(Resources::ZERO, |sum, needs| sum + needs.0);
+    for inventory in carried.iter() {
+        wanted -= inventory.current;
+    }
+    let stored = storages
+        .iter()
+        .fold(Resources::ZERO, |sum, storage| sum + storage.current);
+    let kind_to_take = |site: Entity, wanted: &Resources| {
+        let needs = needs.get(site).ok()?;
+        ResourceKind::ALL
+            .into_iter()
+            .filter(|&kind| needs.0[kind] > 0 && wanted[kind] > 0 && stored[kind] > 0)
+            .max_by_key(|&kind| wanted[kind].min(needs.0[kind]))
+    };
+    for (entity, inventory) in builders.iter() {
+        // Still carrying something, like after loading a save
+        if let Some((kind, _)) = inventory.current.iter().find(|&(_, amount)| amount > 0) {
+            commands
+                .entity(entity)
+                .transition(UnitState::ChoosingResource, UnitState::Bringing(kind));
+            continue;
+        }
+        if harvest_before_building(&priorities) {
+            commands
+                .entity(entity)
+                .transition(UnitState::ChoosingResource, UnitState::Harvesting);
+            continue;
+        }
+        let Some(site) = board.first(&priorities, JobKind::Build, queue.iter(), |site| {
+            kind_to_take(site, &wanted).is_some()
+        }) else {
+            continue;
+        };
+        let kind = kind_to_take(site, &wanted).unwrap();
+        wanted[kind] -= inventory.max;
+        board.claim(site, JobKind::Build, inventory.max);
+        commands
+            .entity(entity)
+            .transition(UnitState::ChoosingResource, UnitState::Taking(kind))
+            .insert(Claim {
+                target: site,
+                kind: JobKind::Build,
+                amount: inventory.max,
+            });
+    }
+}
+
+/// Nobody needs the resource anymore or there is none left to take
+fn give_up_resource<K: Kind>(
+    taking: Query<Entity, (With<Idle>, With<TakingResource<K>>)>,
+    bringing: Query<Entity, (With<Idle>, With<BringingResource<K>>)>,
+    stocked: Query<(), With<Stocks<K>>>,
+    needed: Query<(), With<Needs<K>>>,
+    mut commands: Commands,
+) {
+    if needed.is_empty() || stocked.is_empty() {
+        for entity in taking.iter() {
+            commands
+                .entity(entity)
+                .transition(UnitState::Taking(K::KIND), UnitState::ChoosingResource)
+                .remove::<Claim>();
+        }
+    }
+    if needed.is_empty() {
+        for entity in bringing.iter() {
+            commands
+                .entity(entity)
+                .transition(UnitState::Bringing(K::KIND), UnitState::Storing)
+                .remove::<Claim>();
+        }
+    }
+}
 
-fn take_resource(
-    mut ents: Query<(Entity, &Pos, &mut Inventory), (With<Idle>, With<TakingResource>)>,
+fn take_resource<K: Kind>(
+    mut ents: Query<
+        (Entity, &Pos, &mut Inventory, Option<&Claim>),
+        (With<Idle>, With<TakingResource<K>>),
+    >,
     mut storage: Query<&mut Storage>,
     tile_map: Res<TileMap>,
     mut commands: Commands,
 ) {
-    for (ent, ent_pos, mut inventory) in ents.iter_mut() {
+    for (ent, ent_pos, mut inventory, claim) in ents.iter_mut() {
         for storage_entity in MOVE_DIRECTIONS
             .into_iter()
             .flat_map(|dir| tile_map.entities_at(ent_pos.0 + dir))
@@ -1234,98 +1909,106 @@ fn take_resource(
             let Ok(mut storage) = storage.get_mut(storage_entity) else {
                 continue;
             };
-            let amount_to_take = storage
-                .current
-                .min(inventory.max - inventory.current)
+            let amount_to_take = storage.current[K::KIND]
+                .min(inventory.max - inventory.current.total())
                 .max(0);
-            inventory.current += amount_to_take;
-            storage.current -= amount_to_take;
-            if inventory.current == inventory.max {
-                commands
-                    .entity(ent)
-                    .remove::<TakingResource>()
-                    .insert(BringingResource);
+            inventory.current[K::KIND] += amount_to_take;
+            storage.current[K::KIND] -= amount_to_take;
+            if inventory.current.total() == inventory.max {
                 break;
             }
         }
+        // Don't wait around for a full load if the storage ran out
+        if inventory.current[K::KIND] > 0 {
+            let mut ent = commands.entity(ent);
+            ent.transition(UnitState::Taking(K::KIND), UnitState::Bringing(K::KIND));
+            if let Some(claim) = claim {
+                ent.insert(WalkTo(PathTarget::Entity(claim.target)));
+            }
+        }
     }
 }
 
 fn actual_building(
-    query: Query<(Entity, &NeedsResource, &Pos, &Size, &Placeholder), Changed<NeedsResource>>,
+    query: Query<(Entity, &NeedsResource, &Pos, &Rotation, &Placeholder), Changed<NeedsResource>>,
     mut commands: Commands,
 ) {
-    for (entity, needs, pos, size, placeholder) in query.iter() {
-        if needs.0 == 0 {
+    for (entity, needs, pos, &rotation, placeholder) in query.iter() {
+        if needs.0.is_empty() {
             commands.entity(entity).despawn();
-            commands.spawn((Pos(pos.0), Size(size.0), placeholder.0));
+            commands.spawn((Pos(pos.0), rotation, placeholder.0));
         }
     }
 }
 
-fn bring_resource(
-    mut ents: Query<(Entity, &Pos, &mut Inventory), (With<Idle>, With<BringingResource>)>,
+fn bring_resource<K: Kind>(
+    mut ents: Query<(Entity, &Pos, &mut Inventory), (With<Idle>, With<BringingResource<K>>)>,
     mut needs: Query<&mut NeedsResource>,
     tile_map: Res<TileMap>,
     mut commands: Commands,
 ) {
     for (ent, ent_pos, mut inventory) in ents.iter_mut() {
-        if inventory.current == 0 {
+        if inventory.current[K::KIND] == 0 {
             commands
                 .entity(ent)
-                .remove::<BringingResource>()
-                .insert(TakingResource);
+                .transition(UnitState::Bringing(K::KIND), UnitState::ChoosingResource)
+                .remove::<Claim>();
             continue;
         }
         let placeholder = MOVE_DIRECTIONS
             .into_iter()
             .flat_map(|dir| tile_map.entities_at(ent_pos.0 + dir))
-            .find(|&entity| needs.get(entity).is_ok());
+            .find(|&entity| needs.get(entity).is_ok_and(|needs| needs.0[K::KIND] > 0));
         if let Some(placeholder) = placeholder {
             let mut need = needs.get_mut(placeholder).unwrap();
-            let amount_to_bring = need.0.min(inventory.current);
-            inventory.current -= amount_to_bring;
-            need.0 -= amount_to_bring;
+            let amount_to_bring = need.0[K::KIND].min(inventory.current[K::KIND]);
+            inventory.current[K::KIND] -= amount_to_bring;
+            need.0[K::KIND] -= amount_to_bring;
         }
     }
 }
 
 fn ent_harvest(
     mut ents: Query<
-        (Entity, &Pos, &mut Inventory),
+        (Entity, &Pos, &mut Inventory, Has<Harvested>),
         (With<CanHavest>, With<Idle>, With<Harvesting>),
     >,
     mut harvestables: Query<(Entity, &mut Harvestable)>,
     tile_map: Res<TileMap>,
     mut commands: Commands,
 ) {
-    for (ent, ent_pos, mut inventory) in ents.iter_mut() {
+    for (ent, ent_pos, mut inventory, harvested) in ents.iter_mut() {
         let try_to_harvest = MOVE_DIRECTIONS
             .into_iter()
             .flat_map(|dir| tile_map.entities_at(ent_pos.0 + dir))
             .find(|&entity| harvestables.get(entity).is_ok());
         let try_to_harvest = try_to_harvest.map(|entity| harvestables.get_mut(entity).unwrap());
         if let Some((entity, mut harvestable)) = try_to_harvest {
-            if harvestable.0 > 0 && inventory.current < inventory.max {
-                harvestable.0 -= 1;
-                inventory.current += 1;
-                if harvestable.0 == 0 {
+            if harvestable.amount > 0 && inventory.current.total() < inventory.max {
+                harvestable.amount -= 1;
+                inventory.current[harvestable.kind] += 1;
+                if !harvested {
+                    commands.entity(ent).insert(Harvested);
+                }
+                if harvestable.amount == 0 {
                     commands.entity(entity).despawn();
-                    inventory.current += harvestable.0;
                 }
             }
         }
-        if inventory.current >= inventory.max {
-            commands.entity(ent).insert(Storing).remove::<Harvesting>();
+        if inventory.current.total() >= inventory.max {
+            commands
+                .entity(ent)
+                .transition(UnitState::Harvesting, UnitState::Storing);
         }
     }
 }
 
+/// Crabs walk twice as fast on it
 #[derive(Component)]
-struct Road;
+pub struct Road;
 
 #[derive(Component)]
-struct GhostRoad;
+pub struct GhostRoad;
 
 #[derive(Component)]
 pub struct BlockingGhost;
@@ -1333,19 +2016,16 @@ pub struct BlockingGhost;
 fn update_movement(
     mut q: Query<(Entity, &mut Pos, &mut Moving)>,
     time: Res<Time>,
-    roads: Query<With<Road>>,
     tile_map: Res<TileMap>,
     mut commands: Commands,
 ) {
     for (entity, mut pos, mut moving) in q.iter_mut() {
-        let move_time = if tile_map
-            .entities_at(pos.0)
-            .any(|entity| roads.get(entity).is_ok())
-        {
-            0.1
-        } else {
-            0.2
-        };
+        let mut move_time = if tile_map.is_road(pos.0) { 0.1 } else { 0.2 };
+        let delta = moving.next_pos - pos.0;
+        if delta.x != 0 && delta.y != 0 {
+            move_time *= std::f32::consts::SQRT_2;
+        }
+        moving.prev_t = moving.t;
         moving.t += time.delta_seconds() / move_time;
         if moving.t > 1.0 {
             commands.entity(entity).remove::<Moving>().try_insert(Idle);
@@ -1367,35 +2047,64 @@ fn update_upgrade_transforms<U: BuildingUpgrade>(
             Without<BuildingUpgradeComponent<MonumentUpgrade>>,
         ),
     >,
+    defs: Res<EntDefs>,
 ) {
-    for (mut transform, ent_type, needs, upgrade) in q.iter_mut() {
-        transform.translation.y =
-            ent_type.height() + upgrade.current_level as f32 * ent_type.upgrade_height();
+    for (mut transform, &ent_type, needs, upgrade) in q.iter_mut() {
+        let Some(def) = defs.get(ent_type) else {
+            continue;
+        };
+        transform.translation.y =
+            def.height + upgrade.current_level as f32 * ent_type.upgrade_height();
         if let Some(needs) = needs {
-            transform.translation.y -= ent_type.upgrade_height() * needs.0 as f32 / needs.1 as f32;
+            transform.translation.y -=
+                ent_type.upgrade_height() * needs.0.total() as f32 / needs.1.total() as f32;
         }
     }
 }
 
 fn update_transforms(
     mut q: Query<
-        (&mut Transform, &Pos, Option<&Size>, Option<&Moving>),
+        (
+            &mut Transform,
+            &Pos,
+            Option<&Size>,
+            Option<&Moving>,
+            Has<DiagonalMovement>,
+            Option<&Rotation>,
+        ),
         Or<(
             Changed<Pos>,
-            Changed<Moving>,
+            With<Moving>,
             Changed<Size>,
+            Changed<Rotation>,
             Added<Transform>,
+            With<DiagonalMovement>,
         )>,
     >,
     time: Res<Time>,
+    fixed_time: Res<Time<Fixed>>,
 ) {
-    for (mut transform, pos, size, moving) in q.iter_mut() {
+    for (mut transform, pos, size, moving, smooth, rotation) in q.iter_mut() {
+        if let Some(rotation) = rotation {
+            transform.rotation = rotation.quat();
+        }
         let from = pos.0;
         let size = size.map_or(IVec2::splat(1), |size| size.0);
-        let (to, t) = moving.map_or((from, 0.0), |moving| (moving.next_pos, moving.t));
-        transform.translation = (from.as_vec2().lerp(to.as_vec2(), t) + size.as_vec2() / 2.0)
+        let (to, t) = moving.map_or((from, 0.0), |moving| {
+            let t = moving.prev_t + (moving.t - moving.prev_t) * fixed_time.overstep_percentage();
+            (moving.next_pos, t.min(1.0))
+        });
+        let translation = (from.as_vec2().lerp(to.as_vec2(), t) + size.as_vec2() / 2.0)
             .extend(transform.translation.y)
             .xzy();
+        transform.translation = if smooth && transform.translation.distance(translation) < 2.0 {
+            // Rounds off the corners where straight and diagonal steps meet
+            transform
+                .translation
+                .lerp(translation, (time.delta_seconds() * 15.0).min(1.0))
+        } else {
+            translation
+        };
         if let Some(moving) = moving {
             let delta = moving.next_pos - pos.0;
             if delta != IVec2::ZERO {
@@ -1414,10 +2123,16 @@ struct Idle;
 #[derive(Component)]
 pub struct CanMove;
 
+/// Steps diagonally too, as long as that doesn't cut the corner of anything blocking
+#[derive(Component)]
+pub struct DiagonalMovement;
+
 #[derive(Component)]
 pub struct Moving {
     pub next_pos: IVec2,
     pub t: f32,
+    /// `t` as of the previous tick, rendering interpolates between the two
+    pub prev_t: f32,
 }
 
 fn crabrave(mut crabs: Query<&mut Transform, With<CanMove>>, time: Res<Time>) {
@@ -1428,110 +2143,845 @@ fn crabrave(mut crabs: Query<&mut Transform, With<CanMove>>, time: Res<Time>) {
     }
 }
 
-fn ent_movement<EntState: Component, SearchingFor: Component>(
-    win_state: Res<State<WinState>>,
-    pathfind_ents: Res<pathfind::Ents>,
-    ents: Query<(Entity, &Pos), (With<CanMove>, With<Idle>, With<EntState>)>,
-    blocking: Query<(&Pos, &Size), With<Blocking>>,
-    tile_map: Res<TileMap>,
-    pathfinding: Res<Pathfinding<SearchingFor>>,
+/// Ticks an ent waits for a full tile to clear up before walking around it
+const PATIENCE: u32 = 60;
+
+/// Ticks the ent has been waiting for the tile it wants to walk onto to clear up
+#[derive(Component)]
+pub struct Waiting(pub u32);
+
+/// Starts walking onto the tile next to it once there is room there.
+///
+/// Crabs that waited long enough step next to it instead, a tile never gets more crabs than fit.
+fn start_step(
+    commands: &mut Commands,
+    pathfind_ents: &mut pathfind::Ents,
+    tile_map: &TileMap,
+    entity: Entity,
+    pos: IVec2,
+    next_pos: IVec2,
+    waiting: Option<&Waiting>,
+) {
+    let waited = waiting.map_or(0, |waiting| waiting.0);
+    let next_pos = if pathfind_ents.has_room(next_pos, tile_map.is_road(next_pos)) {
+        Some(next_pos)
+    } else if waited >= PATIENCE {
+        sidestep(pathfind_ents, tile_map, pos, next_pos)
+    } else {
+        None
+    };
+    let Some(next_pos) = next_pos else {
+        commands.entity(entity).try_insert(Waiting(waited + 1));
+        return;
+    };
+    pathfind_ents.reserve(entity, next_pos);
+    commands
+        .entity(entity)
+        .try_insert(Moving {
+            next_pos,
+            t: 0.0,
+            prev_t: 0.0,
+        })
+        .remove::<(Idle, Waiting)>();
+}
+
+/// Straight step onto a tile with room, the one closest to where the ent wanted to go
+fn sidestep(
+    pathfind_ents: &pathfind::Ents,
+    tile_map: &TileMap,
+    pos: IVec2,
+    towards: IVec2,
+) -> Option<IVec2> {
+    MOVE_DIRECTIONS
+        .into_iter()
+        .map(|dir| pos + dir)
+        .filter(|&next_pos| {
+            !tile_map.is_blocking(next_pos)
+                && pathfind_ents.has_room(next_pos, tile_map.is_road(next_pos))
+        })
+        .min_by_key(|next_pos| next_pos.distance_squared(towards))
+}
+
+/// Not walking anywhere this tick, so the next wait starts over
+fn stop_waiting(commands: &mut Commands, entity: Entity, waiting: Option<&Waiting>) {
+    if waiting.is_some() {
+        commands.entity(entity).remove::<Waiting>();
+    }
+}
+
+fn ent_movement<EntState: Component, SearchingFor: Component>(
+    win_state: Res<State<WinState>>,
+    mut pathfind_ents: ResMut<pathfind::Ents>,
+    ents: Query<
+        (Entity, &Pos, Has<DiagonalMovement>, Option<&Waiting>),
+        (With<CanMove>, With<Idle>, With<EntState>, Without<WalkTo>),
+    >,
+    blocking: Query<(&Pos, &Size), With<Blocking>>,
+    tile_map: Res<TileMap>,
+    pathfinding: Res<Pathfinding<SearchingFor>>,
+    mut commands: Commands,
+) {
+    if matches!(win_state.get(), WinState::CrabRave) {
+        return;
+    }
+    for (entity, ent_pos, diagonal, waiting) in ents.iter() {
+        if let Some(dir) = pathfinding.pathfind(&pathfind_ents, &tile_map, ent_pos.0, diagonal) {
+            if dir.distance > 1 {
+                start_step(
+                    &mut commands,
+                    &mut pathfind_ents,
+                    &tile_map,
+                    entity,
+                    ent_pos.0,
+                    ent_pos.0 + dir.dir,
+                    waiting,
+                );
+            } else {
+                stop_waiting(&mut commands, entity, waiting);
+            }
+        } else if let Some((pos, size)) = tile_map
+            .entities_at(ent_pos.0)
+            .find_map(|entity| blocking.get(entity).ok())
+        {
+            let dx = match (ent_pos.0.x - 1 - pos.0.x).cmp(&(pos.0.x + size.0.x - ent_pos.0.x)) {
+                std::cmp::Ordering::Less => -1,
+                std::cmp::Ordering::Equal => 0,
+                std::cmp::Ordering::Greater => 1,
+            };
+            // Straight out, a diagonal step would cut the building's corner
+            let dy = match (ent_pos.0.y - 1 - pos.0.y).cmp(&(pos.0.y + size.0.y - ent_pos.0.y)) {
+                _ if dx != 0 => 0,
+                std::cmp::Ordering::Less => -1,
+                std::cmp::Ordering::Equal => 0,
+                std::cmp::Ordering::Greater => 1,
+            };
+            if dx != 0 || dy != 0 {
+                start_step(
+                    &mut commands,
+                    &mut pathfind_ents,
+                    &tile_map,
+                    entity,
+                    ent_pos.0,
+                    ent_pos.0 + IVec2::new(dx, dy),
+                    waiting,
+                );
+            } else {
+                stop_waiting(&mut commands, entity, waiting);
+            }
+        } else {
+            stop_waiting(&mut commands, entity, waiting);
+        }
+    }
+}
+
+/// Sends the ent to one specific target instead of the closest one its state looks for.
+///
+/// Removed once the ent is next to the target, or if the target can't be reached.
+#[derive(Component)]
+pub struct WalkTo(pub PathTarget);
+
+fn walk_to(
+    ents: Query<(Entity, &Pos, &WalkTo, Option<&Waiting>), (With<CanMove>, With<Idle>)>,
+    mut paths: PathQuery,
+    mut pathfind_ents: ResMut<pathfind::Ents>,
+    tile_map: Res<TileMap>,
+    mut commands: Commands,
+) {
+    for (entity, ent_pos, walk_to, waiting) in ents.iter() {
+        match paths.pathfind_with_room(&pathfind_ents, ent_pos.0, &walk_to.0) {
+            Some(dir) if dir.distance > 1 => {
+                start_step(
+                    &mut commands,
+                    &mut pathfind_ents,
+                    &tile_map,
+                    entity,
+                    ent_pos.0,
+                    ent_pos.0 + dir.dir,
+                    waiting,
+                );
+            }
+            _ => {
+                commands.entity(entity).remove::<(WalkTo, Waiting)>();
+            }
+        }
+    }
+}
+
+/// Resources still needed and the total cost
+#[derive(Component)]
+pub struct NeedsResource(pub Resources, pub Resources);
+
+#[derive(Component)]
+pub struct Placeholder(pub EntType);
+
+fn place_ent(
+    input: Res<Input<MouseButton>>,
+    mut commands: Commands,
+    mut money: ResMut<Money>,
+    preview: Query<(&Pos, &Rotation, &PlacementRejections)>,
+    defs: Res<EntDefs>,
+    state: Res<State<PlayerState>>,
+    mut placed: ResMut<HavePlaced>,
+    mut history: ResMut<History>,
+) {
+    let &PlayerState::Placing(ent_type) = state.get() else {
+        unreachable!();
+    };
+    // Roads are dragged out in lines instead
+    if ent_type == EntType::ROAD {
+        return;
+    }
+    let Ok((pos, &rotation, rejections)) = preview.get_single() else {
+        return;
+    };
+
+    if !rejections.allow() {
+        return;
+    }
+    if input.just_pressed(MouseButton::Left) || input.pressed(MouseButton::Left) {
+        // Ents without a cost can't be placed
+        let Some((def, cost)) = defs.get(ent_type).and_then(|def| Some((def, def.cost?))) else {
+            return;
+        };
+        placed.0 = true;
+        money.0 -= cost;
+        let entity = spawn_placeholder(
+            &mut commands,
+            def,
+            ent_type,
+            pos.0,
+            rotation,
+            NeedsResource(cost, cost),
+        );
+        history.push(Action::Place(vec![Site {
+            entity,
+            ent_type,
+            pos: pos.0,
+            rotation,
+        }]));
+    }
+}
+
+/// Road line or area the player is dragging out
+#[derive(Resource, Default)]
+struct RoadDrag {
+    start: Option<IVec2>,
+    /// What the roads that can be placed cost together
+    cost: Option<Resources>,
+    /// Why there is no road going on the cell under the cursor
+    rejections: Vec<Rejection>,
+}
+
+/// Farthest a dragged road line reaches from where the drag started
+pub const MAX_ROAD_LINE: i32 = 64;
+/// Farthest a dragged road area reaches from where the drag started
+pub const MAX_ROAD_AREA: i32 = 24;
+
+/// Cells from `from` to `to` in the order roads get placed: along the side of the rectangle
+/// with fewer blocked cells and then the other one, or the whole rectangle for `area`.
+/// `to` is moved closer when it is farther than [MAX_ROAD_LINE] or [MAX_ROAD_AREA].
+pub fn road_cells(placement: &Placement, from: IVec2, to: IVec2, area: bool) -> Vec<IVec2> {
+    let reach = IVec2::splat(if area { MAX_ROAD_AREA } else { MAX_ROAD_LINE });
+    let to = to.clamp(from - reach, from + reach);
+    let rect = IRect::from_corners(from, to);
+    if area {
+        let mut cells: Vec<_> = (rect.min.x..=rect.max.x)
+            .flat_map(|x| (rect.min.y..=rect.max.y).map(move |y| IVec2::new(x, y)))
+            .collect();
+        // Every cell has a neighbor closer to the start, placed before it
+        cells.sort_by_key(|&cell| {
+            let d = (cell - from).abs();
+            d.x + d.y
+        });
+        return cells;
+    }
+    let line = |corner: IVec2| {
+        let step = |a: IVec2, b: IVec2| {
+            let dir = (b - a).signum();
+            (1..=(b - a).abs().max_element()).map(move |i| a + dir * i)
+        };
+        std::iter::once(from)
+            .chain(step(from, corner))
+            .chain(step(corner, to))
+            .collect::<Vec<_>>()
+    };
+    let blocked = |cells: &[IVec2]| {
+        cells
+            .iter()
+            .filter(|&&cell| !can_place_road(placement, cell))
+            .count()
+    };
+    let horizontal_first = line(IVec2::new(to.x, from.y));
+    let vertical_first = line(IVec2::new(from.x, to.y));
+    if blocked(&vertical_first) < blocked(&horizontal_first) {
+        vertical_first
+    } else {
+        horizontal_first
+    }
+}
+
+/// Whether it connects is left to [plan_roads]
+fn can_place_road(placement: &Placement, cell: IVec2) -> bool {
+    road_rejections(placement, cell).is_empty()
+}
+
+/// The tile map doesn't have the roads of the drag yet, so [Rejection::NoRoad] is left out
+fn road_rejections(placement: &Placement, cell: IVec2) -> Vec<Rejection> {
+    let mut rejections = placement.check(EntType::ROAD, cell, Rotation::default(), None);
+    rejections.retain(|&rejection| rejection != Rejection::NoRoad);
+    rejections
+}
+
+/// Which of the cells get a road, with why the others don't. A road has to touch an existing one
+/// or one placed before it, cells that already are roads are left out. Everything else is
+/// checked like for any other ent.
+pub fn plan_roads(placement: &Placement, cells: &[IVec2]) -> Vec<(IVec2, Vec<Rejection>)> {
+    let tile_map = placement.tile_map();
+    let mut planned = HashSet::new();
+    let is_road = |cell: IVec2, planned: &HashSet<IVec2>| {
+        tile_map.is_road(cell)
+            || tile_map.has(cell, TileFlag::PlannedRoad)
+            || planned.contains(&cell)
+    };
+    let mut plan = Vec::new();
+    for &cell in cells {
+        if is_road(cell, &planned) {
+            continue;
+        }
+        let mut rejections = road_rejections(placement, cell);
+        if !MOVE_DIRECTIONS
+            .iter()
+            .any(|&dir| is_road(cell + dir, &planned))
+        {
+            rejections.push(Rejection::NoRoad);
+        }
+        if rejections.is_empty() {
+            planned.insert(cell);
+        }
+        plan.push((cell, rejections));
+    }
+    plan
+}
+
+/// Pays for and places the roads [plan_roads] allows from `from` to `to`,
+/// as many as there is money for. They are undone together.
+pub struct PlaceRoads {
+    pub from: IVec2,
+    pub to: IVec2,
+    pub area: bool,
+}
+
+impl Command for PlaceRoads {
+    fn apply(self, world: &mut World) {
+        let mut placement = SystemState::<Placement>::new(world);
+        let placement = placement.get(world);
+        let cells = road_cells(&placement, self.from, self.to, self.area);
+        let plan = plan_roads(&placement, &cells);
+        let Some((def, cost)) = world
+            .resource::<EntDefs>()
+            .get(EntType::ROAD)
+            .and_then(|def| Some((def, def.cost?)))
+        else {
+            return;
+        };
+        let mut queue = CommandQueue::default();
+        let mut commands = Commands::new(&mut queue, world);
+        let mut money = world.resource::<Money>().0;
+        let mut sites = Vec::new();
+        for (cell, _) in plan
+            .into_iter()
+            .filter(|(_, rejections)| rejections.is_empty())
+        {
+            if !money.covers(cost) {
+                break;
+            }
+            money -= cost;
+            let entity = spawn_placeholder(
+                &mut commands,
+                def,
+                EntType::ROAD,
+                cell,
+                Rotation::default(),
+                NeedsResource(cost, cost),
+            );
+            sites.push(Site {
+                entity,
+                ent_type: EntType::ROAD,
+                pos: cell,
+                rotation: Rotation::default(),
+            });
+        }
+        world.resource_mut::<Money>().0 = money;
+        queue.apply(world);
+        if !sites.is_empty() {
+            world.resource_mut::<History>().push(Action::Place(sites));
+        }
+    }
+}
+
+fn drag_roads(
+    input: Res<Input<MouseButton>>,
+    keyboard: Res<Input<KeyCode>>,
+    cursor: Query<&cursor::WorldPos>,
+    state: Res<State<PlayerState>>,
+    ui_handling: Res<ui::UiHandling>,
+    mut drag: ResMut<RoadDrag>,
+    mut placed: ResMut<HavePlaced>,
+    mut commands: Commands,
+) {
+    if *state.get() != PlayerState::Placing(EntType::ROAD) {
+        *drag = default();
+        return;
+    }
+    let Ok(cursor) = cursor.get_single() else {
+        return;
+    };
+    let cell = cursor.0.floor().as_ivec2();
+    if input.just_pressed(MouseButton::Left) && !ui_handling.is_pointer_over_ui {
+        drag.start = Some(cell);
+    }
+    if input.just_released(MouseButton::Left) {
+        if let Some(start) = drag.start.take() {
+            placed.0 = true;
+            commands.add(PlaceRoads {
+                from: start,
+                to: cell,
+                area: keyboard.pressed(KeyCode::ControlLeft),
+            });
+        }
+        drag.cost = None;
+        drag.rejections.clear();
+    }
+}
+
+#[derive(Component)]
+struct RoadPreview;
+
+/// One preview per cell of the dragged road, blocked where it can't be placed.
+/// Only planned again when the drag changes, previews left over are hidden to be used later.
+fn update_road_preview(
+    mut previews: Query<
+        (&mut Pos, &mut Handle<StandardMaterial>, &mut Visibility),
+        With<RoadPreview>,
+    >,
+    mut drag: ResMut<RoadDrag>,
+    mut previewed: Local<Option<(IVec2, IVec2, bool)>>,
+    keyboard: Res<Input<KeyCode>>,
+    cursor: Query<&cursor::WorldPos>,
+    placement: Placement,
+    ent_materials: Res<EntMaterials>,
+    defs: Res<EntDefs>,
+    mut commands: Commands,
+) {
+    let dragged = match (drag.start, cursor.get_single()) {
+        (Some(start), Ok(cursor)) => Some((
+            start,
+            cursor.0.floor().as_ivec2(),
+            keyboard.pressed(KeyCode::ControlLeft),
+        )),
+        _ => None,
+    };
+    if dragged == *previewed {
+        return;
+    }
+    *previewed = dragged;
+    let plan = match dragged {
+        Some((start, end, area)) => {
+            plan_roads(&placement, &road_cells(&placement, start, end, area))
+        }
+        None => Vec::new(),
+    };
+    if let Some((_, end, _)) = dragged {
+        let roads = plan
+            .iter()
+            .filter(|(_, rejections)| rejections.is_empty())
+            .count() as i32;
+        drag.cost = defs
+            .get(EntType::ROAD)
+            .and_then(|def| def.cost)
+            .map(|cost| cost * roads);
+        drag.rejections = plan
+            .iter()
+            .find(|&&(cell, _)| cell == end)
+            .map(|(_, rejections)| rejections.clone())
+            .unwrap_or_default();
+    }
+    let material = |valid: bool| {
+        let state = if valid {
+            EntState::Preview
+        } else {
+            EntState::BlockedPreview
+        };
+        ent_materials
+            .materials
+            .get(&(EntType::ROAD, state))
+            .cloned()
+            .unwrap_or_default()
+    };
+    let mut plan = plan
+        .into_iter()
+        .map(|(cell, rejections)| (cell, rejections.is_empty()));
+    for (mut pos, mut handle, mut visibility) in previews.iter_mut() {
+        match plan.next() {
+            Some((cell, valid)) => {
+                pos.0 = cell;
+                *handle = material(valid);
+                *visibility = Visibility::Inherited;
+            }
+            None => *visibility = Visibility::Hidden,
+        }
+    }
+    for (cell, valid) in plan {
+        commands.spawn((
+            MaterialMeshBundle {
+                mesh: ent_materials
+                    .meshes
+                    .get(&EntType::ROAD)
+                    .cloned()
+                    .unwrap_or_default(),
+                material: material(valid),
+                ..default()
+            },
+            Pos(cell),
+            Size(IVec2::ONE),
+            RoadPreview,
+        ));
+    }
+}
+
+pub fn spawn_placeholder(
+    commands: &mut Commands,
+    def: &EntDef,
+    ent_type: EntType,
+    pos: IVec2,
+    rotation: Rotation,
+    needs: NeedsResource,
+) -> Entity {
+    let mut entity = commands.spawn((
+        Pos(pos),
+        Size(rotation.size(def.size())),
+        rotation,
+        Placeholder(ent_type),
+        needs,
+    ));
+    if let Some(shape) = def.shape(rotation) {
+        entity.insert(shape);
+    }
+    if let EntType::ROAD = ent_type {
+        entity.insert(GhostRoad);
+    } else {
+        entity.insert(BlockingGhost);
+    }
+    entity.add(UpdateTiles);
+    entity.id()
+}
+
+/// Part of the cost of a building the player gets back when demolishing it
+pub const DEMOLISH_REFUND_PERCENT: i32 = 50;
+
+pub fn demolish_refund(cost: Resources) -> Resources {
+    let mut refund = Resources::ZERO;
+    for (kind, amount) in cost.iter() {
+        refund[kind] = amount * DEMOLISH_REFUND_PERCENT / 100;
+    }
+    refund
+}
+
+/// Moving pays the cost again, the refund for the old spot comes in first
+fn can_afford_move(money: Resources, cost: Resources) -> bool {
+    (money + demolish_refund(cost)).covers(cost)
+}
+
+/// Built buildings other than the base can be demolished, placeholders are cancelled instead
+fn can_demolish(world: &World, id: Entity) -> bool {
+    let Some(entity) = world.get_entity(id) else {
+        return false;
+    };
+    let Some(&ent_type) = entity.get::<EntType>() else {
+        return false;
+    };
+    ent_type != EntType::BASE
+        && !entity.contains::<Placeholder>()
+        && world.resource::<Buildings>().get(ent_type).is_some()
+}
+
+/// Removes a building, refunding part of its cost.
+///
+/// Refund and whatever was stored in it go to the closest storages with space,
+/// what doesn't fit is dropped where the building stood for harvesters to pick up.
+/// Crabs living there move to the closest other house.
+pub struct Demolish;
+
+impl EntityCommand for Demolish {
+    fn apply(self, id: Entity, world: &mut World) {
+        if !can_demolish(world, id) {
+            return;
+        }
+        let entity = world.entity(id);
+        let ent_type = *entity.get::<EntType>().unwrap();
+        let tiles = footprint_tiles(entity);
+        let cost = world
+            .resource::<EntDefs>()
+            .get(ent_type)
+            .and_then(|def| def.cost);
+        let refund = demolish_refund(cost.unwrap_or(Resources::ZERO));
+        let mut money_back = refund;
+        let mut returned = refund
+            + entity
+                .get::<Storage>()
+                .map_or(Resources::ZERO, |s| s.current);
+        // An upgrade in progress is cancelled first
+        if let Some(needs) = entity.get::<NeedsResource>() {
+            money_back += needs.1;
+            returned += needs.1 - needs.0;
+        }
+        if let Some(level) = entity.get::<StorageLevelChild>() {
+            let level = level.0;
+            world.entity_mut(level).despawn_recursive();
+        }
+        world.entity_mut(id).despawn_recursive();
+        UpdateTiles.apply(id, world);
+        world.resource_mut::<Money>().0 += money_back;
+        return_resources(world, &tiles, returned);
+        release_claims(world, id);
+
+        let houses: Vec<_> = world
+            .query_filtered::<(Entity, &Pos), With<ProvidePopulation>>()
+            .iter(world)
+            .map(|(house, house_pos)| (house, house_pos.0))
+            .collect();
+        let mut homeless = world.query::<(Entity, &Pos, &mut Home)>();
+        let mut no_home = Vec::new();
+        for (crab, crab_pos, mut home) in homeless.iter_mut(world) {
+            if home.0 != id {
+                continue;
+            }
+            match houses
+                .iter()
+                .min_by_key(|(_, house_pos)| (*house_pos - crab_pos.0).abs().max_element())
+            {
+                Some(&(house, _)) => home.0 = house,
+                None => no_home.push(crab),
+            }
+        }
+        for crab in no_home {
+            world.entity_mut(crab).remove::<Home>();
+        }
+    }
+}
+
+fn footprint_tiles(entity: EntityRef) -> Vec<IVec2> {
+    let pos = entity.get::<Pos>().map_or(IVec2::ZERO, |pos| pos.0);
+    footprint(pos, entity.get::<Size>(), entity.get::<Shape>()).collect()
+}
+
+/// Puts resources back into the closest storages with space,
+/// what doesn't fit is dropped on the footprint tiles for harvesters to pick up
+fn return_resources(world: &mut World, tiles: &[IVec2], mut resources: Resources) {
+    // Defs always cover a tile, see [EntDef::check]
+    let Some(&pos) = tiles.first() else {
+        return;
+    };
+    let mut storages: Vec<_> = world
+        .query::<(&Pos, &mut Storage)>()
+        .iter_mut(world)
+        .collect();
+    storages.sort_by_key(|(storage_pos, _)| (storage_pos.0 - pos).abs().max_element());
+    for (_, mut storage) in storages {
+        for kind in ResourceKind::ALL {
+            let amount = resources[kind]
+                .min(storage.max - storage.current.total())
+                .max(0);
+            storage.current[kind] += amount;
+            resources[kind] -= amount;
+        }
+    }
+    // Harvesters count it as income again when they store it
+    let mut tiles = tiles.iter().copied();
+    let mut tile = pos;
+    for (kind, amount) in resources.iter() {
+        if amount <= 0 {
+            continue;
+        }
+        tile = tiles.next().unwrap_or(tile);
+        let dropped = world.spawn(harvestable_bundle(tile, kind, amount)).id();
+        UpdateTiles.apply(dropped, world);
+        let money = &mut world.resource_mut::<Money>().0;
+        money[kind] = (money[kind] - amount).max(0);
+    }
+}
+
+/// Takes resources out of the storages closest to `pos` again, the other way around from
+/// [return_resources]. Nothing is taken if they don't hold all of it.
+pub fn take_back_resources(world: &mut World, pos: IVec2, mut resources: Resources) -> bool {
+    let mut storages: Vec<_> = world
+        .query::<(&Pos, &mut Storage)>()
+        .iter_mut(world)
+        .collect();
+    let held = storages
+        .iter()
+        .fold(Resources::ZERO, |held, (_, storage)| held + storage.current);
+    if !held.covers(resources) {
+        return false;
+    }
+    storages.sort_by_key(|(storage_pos, _)| (storage_pos.0 - pos).abs().max_element());
+    for (_, mut storage) in storages {
+        for kind in ResourceKind::ALL {
+            let amount = resources[kind].min(storage.current[kind]);
+            storage.current[kind] -= amount;
+            resources[kind] -= amount;
+        }
+    }
+    true
+}
+
+/// Crabs working for the entity look for other work
+fn release_claims(world: &mut World, target: Entity) {
+    let crabs: Vec<_> = world
+        .query::<(Entity, &Claim)>()
+        .iter(world)
+        .filter(|(_, claim)| claim.target == target)
+        .map(|(crab, _)| crab)
+        .collect();
+    for crab in crabs {
+        world.entity_mut(crab).remove::<(Claim, WalkTo)>();
+    }
+}
+
+/// Pays back what construction of the entity cost and returns what builders delivered so far
+fn refund_construction(world: &mut World, id: Entity) -> Option<()> {
+    let entity = world.entity(id);
+    let needs = entity.get::<NeedsResource>()?;
+    let (left, cost) = (needs.0, needs.1);
+    let tiles = footprint_tiles(entity);
+    world.resource_mut::<Money>().0 += cost;
+    return_resources(world, &tiles, cost - left);
+    release_claims(world, id);
+    Some(())
+}
+
+/// Removes a placeholder nothing was built on yet, see [refund_construction]
+pub struct CancelPlaceholder;
+
+impl EntityCommand for CancelPlaceholder {
+    fn apply(self, id: Entity, world: &mut World) {
+        let is_placeholder = world
+            .get_entity(id)
+            .is_some_and(|entity| entity.contains::<Placeholder>());
+        if !is_placeholder {
+            return;
+        }
+        refund_construction(world, id);
+        world.entity_mut(id).despawn_recursive();
+        UpdateTiles.apply(id, world);
+    }
+}
+
+/// Takes the building back to the level it had before the upgrade was started, see [refund_construction]
+pub struct CancelBuildingUpgrade<T>(pub PhantomData<T>);
+
+impl<T: BuildingUpgrade> EntityCommand for CancelBuildingUpgrade<T> {
+    fn apply(self, id: Entity, world: &mut World) {
+        // The marker stays on after the upgrade is done
+        let upgrading = world.get_entity(id).is_some_and(|entity| {
+            entity.contains::<BuildingUpgradeToPerform<T>>() && entity.contains::<NeedsResource>()
+        });
+        if !upgrading {
+            return;
+        }
+        refund_construction(world, id);
+        let mut entity = world.entity_mut(id);
+        entity.remove::<(NeedsResource, BuildingUpgradeToPerform<T>)>();
+        if let Some(mut upgrades) = entity.get_mut::<BuildingUpgradeComponent<T>>() {
+            upgrades.current_level -= 1;
+        }
+    }
+}
+
+/// Demolishes the building and places it again at `to`, paying its cost minus the refund.
+/// Upgrades are not moved along.
+///
+/// Nothing happens if there is not enough money or the building doesn't fit there.
+pub struct MoveBuilding {
+    pub to: IVec2,
+    pub rotation: Rotation,
+}
+
+impl EntityCommand for MoveBuilding {
+    fn apply(self, id: Entity, world: &mut World) {
+        if !can_demolish(world, id) {
+            return;
+        }
+        let ent_type = *world.entity(id).get::<EntType>().unwrap();
+        let Some((def, cost)) = world
+            .resource::<EntDefs>()
+            .get(ent_type)
+            .and_then(|def| Some((def.clone(), def.cost?)))
+        else {
+            return;
+        };
+        let rejections = SystemState::<Placement>::new(world).get(world).check(
+            ent_type,
+            self.to,
+            self.rotation,
+            Some(id),
+        );
+        if !can_afford_move(world.resource::<Money>().0, cost) || !rejections.is_empty() {
+            return;
+        }
+        Demolish.apply(id, world);
+        world.resource_mut::<Money>().0 -= cost;
+        let mut queue = CommandQueue::default();
+        let mut commands = Commands::new(&mut queue, world);
+        spawn_placeholder(
+            &mut commands,
+            &def,
+            ent_type,
+            self.to,
+            self.rotation,
+            NeedsResource(cost, cost),
+        );
+        queue.apply(world);
+    }
+}
+
+fn placeholder_visuals(
+    q: Query<(Entity, &Placeholder), Added<Placeholder>>,
+    ent_materials: Res<EntMaterials>,
     mut commands: Commands,
 ) {
-    if matches!(win_state.get(), WinState::CrabRave) {
-        return;
-    }
-    for (entity, ent_pos) in ents.iter() {
-        if let Some(dir) = pathfinding.pathfind(&pathfind_ents, ent_pos.0) {
-            if dir.distance > 1 {
-                commands
-                    .entity(entity)
-                    .try_insert(Moving {
-                        next_pos: ent_pos.0 + dir.dir,
-                        t: 0.0,
-                    })
-                    .remove::<Idle>();
-            }
-        } else if let Some((pos, size)) = tile_map
-            .entities_at(ent_pos.0)
-            .find_map(|entity| blocking.get(entity).ok())
-        {
-            let dx = match (ent_pos.0.x - 1 - pos.0.x).cmp(&(pos.0.x + size.0.x - ent_pos.0.x)) {
-                std::cmp::Ordering::Less => -1,
-                std::cmp::Ordering::Equal => 0,
-                std::cmp::Ordering::Greater => 1,
-            };
-            let dy = match (ent_pos.0.y - 1 - pos.0.y).cmp(&(pos.0.y + size.0.y - ent_pos.0.y)) {
-                std::cmp::Ordering::Less => -1,
-                std::cmp::Ordering::Equal => 0,
-                std::cmp::Ordering::Greater => 1,
-            };
-            if dx != 0 || dy != 0 {
-                commands
-                    .entity(entity)
-                    .try_insert(Moving {
-                        next_pos: ent_pos.0 + IVec2::new(dx, dy),
-                        t: 0.0,
-                    })
-                    .remove::<Idle>();
-            }
-        }
+    for (entity, placeholder) in q.iter() {
+        commands.entity(entity).insert(MaterialMeshBundle {
+            mesh: ent_materials
+                .meshes
+                .get(&placeholder.0)
+                .cloned()
+                .unwrap_or_default(),
+            material: ent_materials
+                .materials
+                .get(&(placeholder.0, EntState::Placeholder))
+                .cloned()
+                .unwrap_or_default(),
+            ..default()
+        });
     }
 }
 
-#[derive(Component)]
-pub struct NeedsResource(i32, i32);
-
-#[derive(Component)]
-pub struct Placeholder(pub EntType);
-
-fn place_ent(
+fn right_click_to_cancel_placeholder(
     input: Res<Input<MouseButton>>,
-    ent_materials: Res<EntMaterials>,
+    placeholders: Query<(Entity, &Placeholder, &Pos, &Rotation), With<Hovered>>,
     mut commands: Commands,
-    mut money: ResMut<Money>,
-    preview: Query<(&Pos, &PlacementBlocked)>,
-    costs: Res<EntCosts>,
-    state: Res<State<PlayerState>>,
-    mut placed: ResMut<HavePlaced>,
 ) {
-    let &PlayerState::Placing(ent_type) = state.get() else {
-        unreachable!();
-    };
-    let Ok((pos, blocked)) = preview.get_single() else {
-        return;
-    };
-
-    if blocked.0 {
+    if !input.just_pressed(MouseButton::Right) {
         return;
     }
-    if input.just_pressed(MouseButton::Left) || input.pressed(MouseButton::Left) {
-        placed.0 = true;
-        let cost = costs.0[&ent_type];
-        money.0 -= cost;
-        let mut entity = commands.spawn((
-            MaterialMeshBundle {
-                mesh: ent_materials
-                    .meshes
-                    .get(&ent_type)
-                    .cloned()
-                    .unwrap_or_default(),
-                material: ent_materials
-                    .materials
-                    .get(&(ent_type, EntState::Placeholder))
-                    .cloned()
-                    .unwrap_or_default(),
-                ..default()
-            },
-            Pos(pos.0),
-            Size(ent_type.size()),
-            Placeholder(ent_type),
-            NeedsResource(cost, cost),
-        ));
-        if let EntType::Road = ent_type {
-            entity.insert(GhostRoad);
-        } else {
-            entity.insert(BlockingGhost);
-        }
+    for (entity, placeholder, pos, &rotation) in placeholders.iter() {
+        commands.add(Perform(Action::CancelPlace(Site {
+            entity,
+            ent_type: placeholder.0,
+            pos: pos.0,
+            rotation,
+        })));
     }
 }
 
@@ -1549,10 +2999,72 @@ enum PlayerState {
     #[default]
     Normal,
     Placing(EntType),
+    /// Clicking buildings demolishes them
+    Demolishing,
+    /// Picking the building to move, then where it goes
+    Moving(Option<Entity>),
 }
 
-#[derive(Resource)]
-struct EntCosts(HashMap<EntType, i32>);
+fn demolish_on_click(
+    input: Res<Input<MouseButton>>,
+    hovered: Query<(Entity, &EntType, &Pos, Option<&Rotation>), With<Hovered>>,
+    mut commands: Commands,
+) {
+    if !input.just_pressed(MouseButton::Left) {
+        return;
+    }
+    for (entity, &ent_type, pos, rotation) in hovered.iter() {
+        commands.add(Perform(Action::Demolish(
+            Site {
+                entity,
+                ent_type,
+                pos: pos.0,
+                rotation: rotation.copied().unwrap_or_default(),
+            },
+            Resources::ZERO,
+        )));
+    }
+}
+
+fn pick_building_to_move(
+    input: Res<Input<MouseButton>>,
+    hovered: Query<(Entity, &EntType), (With<Hovered>, Without<Placeholder>)>,
+    buildings: Res<Buildings>,
+    state: Res<State<PlayerState>>,
+    mut next_state: ResMut<NextState<PlayerState>>,
+) {
+    if *state.get() != PlayerState::Moving(None) || !input.just_pressed(MouseButton::Left) {
+        return;
+    }
+    if let Some((building, _)) = hovered
+        .iter()
+        .find(|&(_, &ent_type)| ent_type != EntType::BASE && buildings.get(ent_type).is_some())
+    {
+        next_state.set(PlayerState::Moving(Some(building)));
+    }
+}
+
+fn move_building_on_click(
+    input: Res<Input<MouseButton>>,
+    preview: Query<(&Pos, &Rotation, &PlacementRejections)>,
+    state: Res<State<PlayerState>>,
+    mut next_state: ResMut<NextState<PlayerState>>,
+    mut commands: Commands,
+) {
+    let &PlayerState::Moving(Some(building)) = state.get() else {
+        return;
+    };
+    let Ok((pos, &rotation, rejections)) = preview.get_single() else {
+        return;
+    };
+    if input.just_pressed(MouseButton::Left) && rejections.allow() {
+        commands.entity(building).add(MoveBuilding {
+            to: pos.0,
+            rotation,
+        });
+        next_state.set(PlayerState::Normal);
+    }
+}
 
 #[derive(Component)]
 struct UsesPopulation;
@@ -1564,11 +3076,11 @@ fn activate_buttons(
 ) {
     if state.is_changed() {
         for (entity, action) in buttons.iter() {
-            let active = match state.get() {
-                PlayerState::Placing(typ) => Some(typ),
-                _ => None,
-            } == match action {
-                ButtonAction::Spawn(typ) => Some(typ),
+            let active = match (state.get(), action) {
+                (PlayerState::Placing(placing), ButtonAction::Spawn(typ)) => placing == typ,
+                (PlayerState::Demolishing, ButtonAction::Demolish) => true,
+                (PlayerState::Moving(_), ButtonAction::Move) => true,
+                _ => false,
             };
             if active {
                 commands.entity(entity).insert(buttons::Active);
@@ -1582,7 +3094,7 @@ fn activate_buttons(
 fn disable_buttons(
     mut buttons: Query<(&mut buttons::Disabled, &ButtonAction)>,
     money: Res<Money>,
-    costs: Res<EntCosts>,
+    defs: Res<EntDefs>,
     population_providers: Query<&ProvidePopulation>,
     population_users: Query<&UsesPopulation>,
 ) {
@@ -1593,12 +3105,12 @@ fn disable_buttons(
     let current_population = population_users.iter().count();
     for (mut disabled, action) in buttons.iter_mut() {
         match action {
-            ButtonAction::Spawn(typ) => match costs.0.get(typ) {
-                Some(&cost) => {
-                    let has_money = cost <= money.0;
+            ButtonAction::Spawn(typ) => match defs.get(*typ).and_then(|def| def.cost) {
+                Some(cost) => {
+                    let has_money = money.0.covers(cost);
 
-                    let need_population = match typ {
-                        EntType::Harvester => 1,
+                    let need_population = match *typ {
+                        EntType::HARVESTER => 1,
                         _ => 0,
                     };
                     let has_population = need_population == 0
@@ -1607,6 +3119,7 @@ fn disable_buttons(
                 }
                 None => disabled.0 = true,
             },
+            ButtonAction::Demolish | ButtonAction::Move => disabled.0 = false,
         };
     }
 }
@@ -1627,99 +3140,101 @@ fn button_actions(
                     player_state.set(PlayerState::Normal);
                 }
             }
+            ButtonAction::Demolish => {
+                player_state.set(if *current_state.get() == PlayerState::Demolishing {
+                    PlayerState::Normal
+                } else {
+                    PlayerState::Demolishing
+                });
+            }
+            ButtonAction::Move => {
+                player_state.set(if matches!(current_state.get(), PlayerState::Moving(..)) {
+                    PlayerState::Normal
+                } else {
+                    PlayerState::Moving(None)
+                });
+            }
         }
     }
 }
 
+/// Kind of a building or unit, buildings from mods get their own by registering them
+/// with [AppExt::register_building](crate::buildings::AppExt::register_building)
 #[derive(Debug, PartialEq, Eq, Copy, Clone, Hash, Component)]
-pub enum EntType {
-    Harvester,
-    Base,
-    Storage,
-    House,
-    UpgradeInventory,
-    Road,
-    GoldHarvester,
-    Builder,
-    BuilderAcademy,
-    Monument,
-}
+pub struct EntType(&'static str);
 
 impl EntType {
-    fn all() -> impl Iterator<Item = Self> {
-        [
-            Self::Harvester,
-            Self::Base,
-            Self::Storage,
-            Self::House,
-            Self::UpgradeInventory,
-            Self::Road,
-            Self::GoldHarvester,
-            Self::Builder,
-            Self::BuilderAcademy,
-            Self::Monument,
-        ]
-        .into_iter()
-    }
-    fn color(&self) -> Color {
-        match self {
-            EntType::Harvester => Color::BLACK,
-            EntType::Base => Color::WHITE,
-            EntType::Storage => Color::BEIGE,
-            EntType::House => Color::PURPLE,
-            EntType::UpgradeInventory => Color::YELLOW,
-            EntType::Road => Color::GRAY,
-            EntType::GoldHarvester => Color::GOLD.with_l(0.1),
-            EntType::Builder => Color::PINK.with_l(0.2),
-            EntType::BuilderAcademy => Color::WHITE,
-            EntType::Monument => Color::AQUAMARINE,
-        }
-    }
-    fn size(&self) -> IVec2 {
-        match self {
-            EntType::Storage => IVec2::new(4, 3),
-            EntType::Base => IVec2::splat(5),
-            EntType::House => IVec2::splat(2),
-            EntType::UpgradeInventory => IVec2::new(2, 3),
-            EntType::BuilderAcademy => IVec2::new(3, 2),
-            EntType::Monument => IVec2::splat(10),
-            _ => IVec2::splat(1),
-        }
-    }
-
-    fn height(&self) -> f32 {
-        match self {
-            EntType::Harvester | EntType::GoldHarvester | EntType::Builder => 0.1,
-            _ => 0.0,
+    pub const HARVESTER: Self = Self::new("Harvester");
+    pub const BASE: Self = Self::new("Base");
+    pub const STORAGE: Self = Self::new("Storage");
+    pub const HOUSE: Self = Self::new("House");
+    pub const UPGRADE_INVENTORY: Self = Self::new("UpgradeInventory");
+    pub const ROAD: Self = Self::new("Road");
+    pub const GOLD_HARVESTER: Self = Self::new("GoldHarvester");
+    pub const BUILDER: Self = Self::new("Builder");
+    pub const BUILDER_ACADEMY: Self = Self::new("BuilderAcademy");
+    pub const MONUMENT: Self = Self::new("Monument");
+
+    /// Names are what saves and def files refer to, so they must be unique and never change
+    pub const fn new(name: &'static str) -> Self {
+        Self(name)
+    }
+
+    pub fn name(self) -> &'static str {
+        self.0
+    }
+
+    /// Same name always gives the same leaked string, so loading saves over and over does not leak
+    fn intern(name: &str) -> Self {
+        static NAMES: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+        let mut names = NAMES.lock().unwrap();
+        if let Some(&interned) = names.iter().find(|&&interned| interned == name) {
+            return Self(interned);
         }
+        let interned = Box::leak(name.to_owned().into_boxed_str());
+        names.push(interned);
+        Self(interned)
     }
 
     fn upgrade_height(&self) -> f32 {
-        match self {
-            EntType::House => 1.0,
+        match *self {
+            EntType::HOUSE => 1.0,
             _ => 1.0,
         }
     }
+}
 
-    pub fn max_upgrades(&self) -> usize {
-        match self {
-            EntType::House => 9,
-            EntType::BuilderAcademy | EntType::UpgradeInventory => 4,
-            EntType::Storage => 4,
-            EntType::Monument => 3,
-            _ => 0,
-        }
+impl Serialize for EntType {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for EntType {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Ok(Self::intern(&name))
     }
 }
 
-#[derive(Debug, Event, Component, Copy, Clone)]
+#[derive(Debug, Event, Component, Copy, Clone, PartialEq)]
 enum ButtonAction {
     Spawn(EntType),
+    Demolish,
+    Move,
+}
+
+impl ButtonAction {
+    /// Tools next to the build menu: label, tooltip and key
+    const TOOLS: [(Self, &'static str, &'static str, KeyCode); 2] = [
+        (Self::Demolish, "X", "Demolish", KeyCode::X),
+        (Self::Move, "M", "Move", KeyCode::M),
+    ];
 }
 
 fn update_money_text(mut money_text: Query<&mut Text, With<MoneyText>>, money: Res<Money>) {
     for mut money_text in money_text.iter_mut() {
-        money_text.sections[0].value = format!("{}", money.0);
+        money_text.sections[0].value = money.0.to_string();
     }
 }
 
@@ -1784,8 +3299,12 @@ fn tooltip(
     window: Query<&Window, With<PrimaryWindow>>,
     ui_scale: Res<UiScale>,
     hovered: Query<(), (With<Hovered>, With<ScaleOnHover>, Without<NeedsResource>)>,
+    hovered_buildings: Query<&EntType, (With<Hovered>, Without<Placeholder>)>,
     buttons: Query<(&ButtonAction, &Interaction)>,
-    costs: Res<EntCosts>,
+    defs: Res<EntDefs>,
+    state: Res<State<PlayerState>>,
+    road_drag: Res<RoadDrag>,
+    preview: Query<&PlacementRejections>,
 ) {
     let (mut text, mut style) = q.single_mut();
     let Some(mut pos) = window.single().cursor_position() else {
@@ -1797,18 +3316,52 @@ fn tooltip(
     style.bottom = Val::Px(pos.y);
 
     style.display = Display::None;
-    if hovered.iter().next().is_some() {
+    if let Some(cost) = road_drag.cost {
+        text.sections[0].value = std::iter::once(cost.to_string())
+            .chain(road_drag.rejections.iter().map(Rejection::to_string))
+            .collect::<Vec<_>>()
+            .join("\n");
         style.display = default();
-    } else if let Some(cost) = buttons.iter().find_map(|(action, interaction)| {
+    } else if let Some(rejections) = preview
+        .get_single()
+        .ok()
+        .and_then(|rejections| rejections.0.as_ref())
+        .filter(|rejections| !rejections.is_empty())
+    {
+        text.sections[0].value = rejections
+            .iter()
+            .map(Rejection::to_string)
+            .collect::<Vec<_>>()
+            .join("\n");
+        style.display = default();
+    } else if *state.get() == PlayerState::Demolishing {
+        let refund = hovered_buildings
+            .iter()
+            .filter(|&&ent_type| ent_type != EntType::BASE)
+            .find_map(|&ent_type| defs.get(ent_type)?.cost);
+        if let Some(cost) = refund {
+            text.sections[0].value = format!("+{}", demolish_refund(cost));
+            style.display = default();
+        }
+    } else if *state.get() == PlayerState::Normal && hovered.iter().next().is_some() {
+        style.display = default();
+    } else if let Some(tooltip) = buttons.iter().find_map(|(action, interaction)| {
         if let Interaction::Hovered = interaction {
             match action {
-                ButtonAction::Spawn(typ) => costs.0.get(typ).copied(),
+                ButtonAction::Spawn(typ) => defs
+                    .get(*typ)
+                    .and_then(|def| def.cost)
+                    .map(|cost| cost.to_string()),
+                _ => ButtonAction::TOOLS
+                    .iter()
+                    .find(|(tool, ..)| tool == action)
+                    .map(|(_, _, tooltip, _)| tooltip.to_string()),
             }
         } else {
             None
         }
     }) {
-        text.sections[0].value = cost.to_string();
+        text.sections[0].value = tooltip;
         style.display = default();
     }
 }
@@ -1816,7 +3369,7 @@ fn tooltip(
 #[derive(Component)]
 struct WinText;
 
-fn setup_ui(asset_server: Res<AssetServer>, mut commands: Commands) {
+fn setup_ui(asset_server: Res<AssetServer>, buildings: Res<Buildings>, mut commands: Commands) {
     // commands.spawn({
     //     let mut camera = Camera2dBundle::default();
     //     camera.projection.scaling_mode = bevy::render::camera::ScalingMode::FixedVertical(10.0);
@@ -1873,8 +3426,22 @@ fn setup_ui(asset_server: Res<AssetServer>, mut commands: Commands) {
                 ..default()
             })
             .with_children(|info| {
-                info.spawn(NodeBundle::default()).with_children(|time| {
-                    time.spawn((TextBundle::from_section("0", text_style.clone()), TimeText));
+                info.spawn(NodeBundle {
+                    style: Style {
+                        align_items: AlignItems::Center,
+                        ..default()
+                    },
+                    ..default()
+                })
+                .with_children(|time| {
+                    crate::game_speed::spawn_controls(time, &text_style);
+                    time.spawn((
+                        TextBundle::from_section("0", text_style.clone()).with_style(Style {
+                            margin: UiRect::left(Val::Px(10.0)),
+                            ..default()
+                        }),
+                        TimeText,
+                    ));
                     time.spawn(ImageBundle {
                         image: UiImage::new(asset_server.load("icons/time.png")),
                         style: Style {
@@ -1964,7 +3531,19 @@ fn setup_ui(asset_server: Res<AssetServer>, mut commands: Commands) {
                         GoldText,
                     ));
                 });
+                info.spawn(NodeBundle {
+                    style: Style {
+                        flex_direction: FlexDirection::Column,
+                        margin: UiRect::top(Val::Px(10.0)),
+                        ..default()
+                    },
+                    ..default()
+                })
+                .with_children(|priorities| {
+                    crate::jobs::spawn_controls(priorities, &text_style);
+                });
             });
+            crate::construction_queue::spawn_panel(root, &text_style);
             root.spawn(NodeBundle {
                 style: Style {
                     width: Val::Percent(100.0),
@@ -1999,26 +3578,48 @@ fn setup_ui(asset_server: Res<AssetServer>, mut commands: Commands) {
                 ..default()
             })
             .with_children(|bottom| {
-                for (typ, key, deps) in [
-                    (EntType::Road, KeyCode::Key1, vec![]),
-                    (EntType::House, KeyCode::Key2, vec![EntType::Road]),
-                    (EntType::BuilderAcademy, KeyCode::Key3, vec![EntType::House]),
-                    (
-                        EntType::UpgradeInventory,
-                        KeyCode::Key4,
-                        vec![EntType::House],
-                    ),
-                    (EntType::Storage, KeyCode::Key5, vec![EntType::House]),
-                    (
-                        EntType::Monument,
-                        KeyCode::Key6,
-                        vec![
-                            EntType::BuilderAcademy,
-                            EntType::UpgradeInventory,
-                            EntType::Storage,
-                        ],
-                    ),
-                ] {
+                for building in buildings.iter() {
+                    let Some(button) = &building.button else {
+                        continue;
+                    };
+                    let typ = building.ent_type;
+                    let deps = &button.requires;
+                    let mut entity = bottom.spawn((
+                        ButtonBundle {
+                            style: Style {
+                                width: Val::Px(60.0),
+                                height: Val::Px(60.0),
+                                border: UiRect::all(Val::Px(5.0)),
+                                margin: UiRect::all(Val::Px(5.0)),
+                                // horizontally center child text
+                                justify_content: JustifyContent::Center,
+                                // vertically center child text
+                                align_items: AlignItems::Center,
+                                display: if deps.is_empty() {
+                                    default()
+                                } else {
+                                    Display::None
+                                },
+                                ..default()
+                            },
+                            ..default()
+                        },
+                        Dependencies(deps.iter().copied().collect()),
+                        ButtonAction::Spawn(typ),
+                        buttons::Disabled(false),
+                    ));
+                    entity.with_children(|parent| {
+                        parent.spawn(ImageBundle {
+                            image: UiImage::new(asset_server.load(&button.icon)),
+                            ..default()
+                        });
+                        // parent.spawn(TextBundle::from_section(format!("{typ:?}"), default()));
+                    });
+                    if let Some(key) = button.key {
+                        entity.insert(buttons::Keybind(key));
+                    }
+                }
+                for (action, label, _, key) in ButtonAction::TOOLS {
                     bottom
                         .spawn((
                             ButtonBundle {
@@ -2027,49 +3628,34 @@ fn setup_ui(asset_server: Res<AssetServer>, mut commands: Commands) {
                                     height: Val::Px(60.0),
                                     border: UiRect::all(Val::Px(5.0)),
                                     margin: UiRect::all(Val::Px(5.0)),
-                                    // horizontally center child text
                                     justify_content: JustifyContent::Center,
-                                    // vertically center child text
                                     align_items: AlignItems::Center,
-                                    display: if deps.is_empty() {
-                                        default()
-                                    } else {
-                                        Display::None
-                                    },
                                     ..default()
                                 },
                                 ..default()
                             },
-                            Dependencies(deps.into_iter().collect()),
-                            ButtonAction::Spawn(typ),
-                            buttons::Keybind(key),
+                            action,
                             buttons::Disabled(false),
+                            buttons::Keybind(key),
                         ))
-                        .with_children(|button| {
-                            button.spawn(ImageBundle {
-                                image: UiImage::new(asset_server.load(format!(
-                                    "icons/{}.png",
-                                    match typ {
-                                        EntType::House => "house",
-                                        EntType::BuilderAcademy => "builders",
-                                        EntType::Monument => "bavy",
-                                        EntType::Road => "road",
-                                        EntType::Storage => "storage",
-                                        EntType::UpgradeInventory => "gold",
-                                        _ => unreachable!(),
-                                    }
-                                ))),
-                                ..default()
-                            });
-                            // button.spawn(TextBundle::from_section(format!("{typ:?}"), default()));
+                        .with_children(|parent| {
+                            parent.spawn(TextBundle::from_section(
+                                label,
+                                TextStyle {
+                                    font_size: 40.0,
+                                    color: Color::WHITE,
+                                    ..default()
+                                },
+                            ));
                         });
                 }
             });
         });
 }
 
+/// Resources not spent yet, per kind
 #[derive(Resource)]
-pub struct Money(pub i32);
+pub struct Money(pub Resources);
 
 #[derive(Component)]
 pub struct Hovered;
@@ -2112,10 +3698,11 @@ fn scale_hovered(
         (&mut Transform, &EntType, &IsHovered, Has<NeedsResource>),
         (With<ScaleOnHover>, Changed<IsHovered>),
     >,
+    defs: Res<EntDefs>,
 ) {
-    for (mut transform, ent_type, hovered, needs) in entities.iter_mut() {
+    for (mut transform, &ent_type, hovered, needs) in entities.iter_mut() {
         if hovered.0 && !needs {
-            let size = ent_type.size().max_element() as f32;
+            let size = defs.get(ent_type).map_or(1, |def| def.size().max_element()) as f32;
             transform.scale = Vec3::splat((size + 0.5) / size);
         } else {
             transform.scale = Vec3::splat(1.0);
@@ -2124,7 +3711,10 @@ fn scale_hovered(
 }
 
 #[derive(Component)]
-struct Harvestable(i32);
+pub struct Harvestable {
+    pub kind: ResourceKind,
+    pub amount: i32,
+}
 
 #[derive(PartialEq, Eq, Hash)]
 pub enum EntState {
@@ -2136,13 +3726,13 @@ pub enum EntState {
 }
 
 #[derive(Resource)]
-struct EntMaterials {
+pub struct EntMaterials {
     meshes: HashMap<EntType, Handle<Mesh>>,
     materials: HashMap<(EntType, EntState), Handle<StandardMaterial>>,
     harvestable_mesh: Handle<Mesh>,
-    harvestable_material: Handle<StandardMaterial>,
+    harvestable_material: HashMap<ResourceKind, Handle<StandardMaterial>>,
     inventory_thing_mesh: Handle<Mesh>,
-    inventory_thing_material: Vec<Handle<StandardMaterial>>,
+    inventory_thing_material: HashMap<ResourceKind, Vec<Handle<StandardMaterial>>>,
     bavy_mesh: Handle<Mesh>,
     bavy_materials: Vec<Handle<StandardMaterial>>,
     level_mesh: Handle<Mesh>,
@@ -2151,134 +3741,198 @@ struct EntMaterials {
     scaffold_material: Handle<StandardMaterial>,
 }
 
+fn ent_mesh(ent_type: EntType, def: &EntDef) -> Mesh {
+    match def.mesh {
+        EntMesh::Unit => Mesh::from(Plane::from_size(0.75)),
+        EntMesh::Flat => Mesh::from(Plane::from_size(def.size().max_element() as f32)),
+        EntMesh::Building => match def.shape(Rotation::default()) {
+            Some(shape) => meshes::shaped_building_mesh(
+                &shape.0,
+                def.size(),
+                ent_type.upgrade_height(),
+                def.max_upgrades + 1,
+            ),
+            None => {
+                meshes::building_mesh(def.size(), ent_type.upgrade_height(), def.max_upgrades + 1)
+            }
+        },
+    }
+}
+
+fn ent_state_materials(
+    ent_type: EntType,
+    def: &EntDef,
+    asset_server: &AssetServer,
+) -> [(EntState, StandardMaterial); 5] {
+    let material = StandardMaterial {
+        fog_enabled: true,
+        perceptual_roughness: 1.0,
+        metallic: 0.0,
+        reflectance: 0.0,
+        alpha_mode: match ent_type {
+            EntType::BUILDER
+            | EntType::GOLD_HARVESTER
+            | EntType::HARVESTER
+            | EntType::BASE
+            | EntType::STORAGE => AlphaMode::Mask(0.5),
+            _ => AlphaMode::Opaque,
+        },
+        cull_mode: if let EntType::STORAGE | EntType::BASE = ent_type {
+            None
+        } else {
+            default()
+        },
+        base_color_texture: def
+            .texture
+            .as_ref()
+            .map(|texture| asset_server.load(texture)),
+        base_color: def.color(),
+        ..default()
+    };
+    [
+        (EntState::Hovered, {
+            let mut material = material.clone();
+            material.base_color.set_l(0.2);
+            material
+        }),
+        (EntState::Placeholder, {
+            let mut material = material.clone();
+            material.unlit = true;
+            material.alpha_mode = AlphaMode::Blend;
+            material.base_color.set_a(0.5);
+            material
+        }),
+        (EntState::Preview, {
+            let mut material = material.clone();
+            material.unlit = true;
+            material.alpha_mode = AlphaMode::Blend;
+            material.base_color.set_a(0.5);
+            material
+        }),
+        (EntState::BlockedPreview, {
+            let mut material = material.clone();
+            material.base_color_texture = None;
+            material.alpha_mode = AlphaMode::Blend;
+            material.base_color = Color::rgba(1.0, 0.0, 0.0, 0.5);
+            material
+        }),
+        (EntState::Normal, material),
+    ]
+}
+
+/// Meshes and materials that already exist are replaced in place, so edited defs change the look of existing ents too
+fn update_ent_assets(
+    ent_materials: &mut EntMaterials,
+    defs: &EntDefs,
+    mesh_assets: &mut Assets<Mesh>,
+    material_assets: &mut Assets<StandardMaterial>,
+    asset_server: &AssetServer,
+) {
+    fn set<K: Eq + std::hash::Hash, A: Asset>(
+        handles: &mut HashMap<K, Handle<A>>,
+        key: K,
+        assets: &mut Assets<A>,
+        asset: A,
+    ) {
+        match handles.get(&key) {
+            Some(handle) => assets.insert(handle.id(), asset),
+            None => {
+                handles.insert(key, assets.add(asset));
+            }
+        }
+    }
+    for (ent_type, def) in defs.iter() {
+        set(
+            &mut ent_materials.meshes,
+            ent_type,
+            mesh_assets,
+            ent_mesh(ent_type, def),
+        );
+        for (state, material) in ent_state_materials(ent_type, def, asset_server) {
+            set(
+                &mut ent_materials.materials,
+                (ent_type, state),
+                material_assets,
+                material,
+            );
+        }
+    }
+}
+
+fn reload_ent_materials(
+    defs: Res<EntDefs>,
+    mut ent_materials: ResMut<EntMaterials>,
+    mut mesh_assets: ResMut<Assets<Mesh>>,
+    mut material_assets: ResMut<Assets<StandardMaterial>>,
+    asset_server: Res<AssetServer>,
+) {
+    update_ent_assets(
+        &mut ent_materials,
+        &defs,
+        &mut mesh_assets,
+        &mut material_assets,
+        &asset_server,
+    );
+}
+
 fn setup_materials(
     mut mesh_assets: ResMut<Assets<Mesh>>,
     mut material_assets: ResMut<Assets<StandardMaterial>>,
     asset_server: Res<AssetServer>,
+    defs: Res<EntDefs>,
     mut commands: Commands,
 ) {
-    let mut meshes = HashMap::new();
-    let mut materials = HashMap::new();
-    for ent_type in EntType::all() {
-        meshes.insert(
-            ent_type,
-            // mesh_assets.add(Mesh::from(Quad::new(ent_type.size().as_vec2()))),
-            mesh_assets.add(match ent_type {
-                EntType::Builder | EntType::GoldHarvester | EntType::Harvester => {
-                    Mesh::from(Plane::from_size(0.75))
-                }
-                EntType::Road | EntType::Monument => {
-                    Mesh::from(Plane::from_size(ent_type.size().max_element() as f32))
-                }
-                _ => meshes::building_mesh(
-                    ent_type.size(),
-                    ent_type.upgrade_height(),
-                    ent_type.max_upgrades() + 1,
-                ),
-            }),
-        );
-        let material = StandardMaterial {
-            fog_enabled: true,
-            perceptual_roughness: 1.0,
-            metallic: 0.0,
-            reflectance: 0.0,
-            alpha_mode: match ent_type {
-                EntType::Builder
-                | EntType::GoldHarvester
-                | EntType::Harvester
-                | EntType::Base
-                | EntType::Storage => AlphaMode::Mask(0.5),
-                _ => AlphaMode::Opaque,
-            },
-            cull_mode: if let EntType::Storage | EntType::Base = ent_type {
-                None
-            } else {
-                default()
-            },
-            base_color_texture: match ent_type {
-                EntType::Harvester => Some(asset_server.load("crab.png")),
-                EntType::Builder => Some(asset_server.load("builder_crab.png")),
-                EntType::GoldHarvester => Some(asset_server.load("gold_crab.png")),
-                EntType::House => Some(asset_server.load("house.png")),
-                EntType::BuilderAcademy => Some(asset_server.load("builder_academy.png")),
-                EntType::UpgradeInventory => Some(asset_server.load("gold_academy.png")),
-                EntType::Storage => Some(asset_server.load("storage.png")),
-                EntType::Base => Some(asset_server.load("base.png")),
-                EntType::Road | EntType::Monument => None,
-            },
-            base_color: match ent_type {
-                EntType::Builder | EntType::GoldHarvester | EntType::Harvester => Color::WHITE,
-                EntType::House => Color::WHITE,
-                EntType::Monument => Color::DARK_GRAY,
-                _ => ent_type.color(),
-            },
-            ..default()
-        };
-        materials.insert(
-            (ent_type, EntState::Hovered),
-            material_assets.add({
-                let mut material = material.clone();
-                material.base_color.set_l(0.2);
-                material
-            }),
-        );
-        materials.insert(
-            (ent_type, EntState::Placeholder),
-            material_assets.add({
-                let mut material = material.clone();
-                material.unlit = true;
-                material.alpha_mode = AlphaMode::Blend;
-                material.base_color.set_a(0.5);
-                material
-            }),
-        );
-        materials.insert(
-            (ent_type, EntState::Preview),
-            material_assets.add({
-                let mut material = material.clone();
-                material.unlit = true;
-                material.alpha_mode = AlphaMode::Blend;
-                material.base_color.set_a(0.5);
-                material
-            }),
-        );
-        materials.insert(
-            (ent_type, EntState::BlockedPreview),
-            material_assets.add({
-                let mut material = material.clone();
-                material.base_color_texture = None;
-                material.alpha_mode = AlphaMode::Blend;
-                material.base_color = Color::rgba(1.0, 0.0, 0.0, 0.5);
-                material
-            }),
-        );
-        materials.insert((ent_type, EntState::Normal), material_assets.add(material));
-    }
-
-    let ent_materials = EntMaterials {
-        meshes,
-        materials,
+    let mut ent_materials = EntMaterials {
+        meshes: HashMap::new(),
+        materials: HashMap::new(),
         harvestable_mesh: mesh_assets.add(meshes::make_resource()),
-        harvestable_material: material_assets.add(StandardMaterial {
-            alpha_mode: AlphaMode::Mask(0.5),
-            cull_mode: None,
-            base_color_texture: Some(asset_server.load("resource.png")),
-            ..default()
-        }),
-        inventory_thing_material: (0..10)
-            .map(|i| {
-                material_assets.add(StandardMaterial {
-                    base_color: Color::GREEN.with_l(0.2 + i as f32 / 10.0 * 0.5).with_h(
-                        thread_rng().gen_range(Color::GREEN.h() - 50.0..Color::GREEN.h() + 50.0),
-                    ),
-                    fog_enabled: true,
+        harvestable_material: ResourceKind::ALL
+            .into_iter()
+            .map(|kind| {
+                let material = material_assets.add(StandardMaterial {
+                    alpha_mode: AlphaMode::Mask(0.5),
+                    cull_mode: None,
+                    base_color: match kind {
+                        ResourceKind::Wood => Color::WHITE,
+                        ResourceKind::Stone => Color::GRAY,
+                        ResourceKind::Gold => Color::GOLD,
+                    },
+                    base_color_texture: Some(asset_server.load("resource.png")),
                     ..default()
-                })
+                });
+                (kind, material)
+            })
+            .collect(),
+        inventory_thing_material: ResourceKind::ALL
+            .into_iter()
+            .map(|kind| {
+                let color = match kind {
+                    ResourceKind::Wood => Color::GREEN,
+                    ResourceKind::Stone => Color::GRAY,
+                    ResourceKind::Gold => Color::GOLD,
+                };
+                let materials = (0..10)
+                    .map(|i| {
+                        material_assets.add(StandardMaterial {
+                            base_color: color.with_l(0.2 + i as f32 / 10.0 * 0.5).with_h(
+                                (color.h() + thread_rng().gen_range(-50.0..50.0)).rem_euclid(360.0),
+                            ),
+                            fog_enabled: true,
+                            ..default()
+                        })
+                    })
+                    .collect();
+                (kind, materials)
             })
             .collect(),
         inventory_thing_mesh: mesh_assets.add(Plane::from_size(0.25).into()),
-        bavy_mesh: mesh_assets
-            .add(Plane::from_size(EntType::Monument.size().max_element() as f32).into()),
+        bavy_mesh: mesh_assets.add(
+            Plane::from_size(
+                defs.get(EntType::MONUMENT)
+                    .map_or(1, |def| def.size().max_element()) as f32,
+            )
+            .into(),
+        ),
         bavy_materials: (0..3)
             .map(|i| {
                 material_assets.add(StandardMaterial {
@@ -2305,15 +3959,17 @@ fn setup_materials(
             ..default()
         }),
     };
+    update_ent_assets(
+        &mut ent_materials,
+        &defs,
+        &mut mesh_assets,
+        &mut material_assets,
+        &asset_server,
+    );
     commands.insert_resource(ent_materials);
 }
 
-fn setup_camera(
-    mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-    asset_server: Res<AssetServer>,
-) {
+fn setup_camera(mut commands: Commands) {
     commands.spawn({
         let camera = Camera3dBundle {
             transform: Transform::from_xyz(2.0, 80.0, -20.0)
@@ -2354,25 +4010,4 @@ fn setup_camera(
         transform: Transform::from_xyz(-3.0, 50.0, 10.0).looking_at(Vec3::ZERO, Vec3::Y),
         ..default()
     });
-    let ground_texture: Handle<Image> = asset_server.load_with_settings("ground.png", {
-        let sampler_desc = ImageSamplerDescriptor {
-            address_mode_u: ImageAddressMode::Repeat,
-            address_mode_v: ImageAddressMode::Repeat,
-            ..Default::default()
-        };
-
-        move |s: &mut ImageLoaderSettings| {
-            s.sampler = ImageSampler::Descriptor(sampler_desc.clone());
-        }
-    });
-    commands.spawn(PbrBundle {
-        mesh: meshes.add(meshes::make_plane(1000.0)),
-        material: materials.add(StandardMaterial {
-            base_color_texture: Some(ground_texture),
-            perceptual_roughness: 1.0,
-            ..default()
-        }),
-        transform: Transform::from_xyz(0.0, -0.001, 0.0),
-        ..default()
-    });
 }
@@ -0,0 +1,147 @@
+use bevy::prelude::*;
+
+use crate::buttons;
+
+pub struct Plugin;
+
+impl bevy::app::Plugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<GameSpeed>();
+        buttons::register::<SetGameSpeed>(app);
+        app.add_systems(
+            Update,
+            (
+                game_speed_keybinds,
+                set_game_speed,
+                apply_game_speed.run_if(resource_changed::<GameSpeed>()),
+                activate_speed_buttons.run_if(resource_changed::<GameSpeed>()),
+            )
+                .chain(),
+        );
+    }
+}
+
+/// How fast the colony lives, only scales virtual time so camera and ui stay responsive
+#[derive(Resource, Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum GameSpeed {
+    Paused,
+    #[default]
+    Normal,
+    Double,
+    Quadruple,
+}
+
+impl GameSpeed {
+    const ALL: [Self; 4] = [Self::Paused, Self::Normal, Self::Double, Self::Quadruple];
+
+    pub fn multiplier(self) -> f32 {
+        match self {
+            Self::Paused => 0.0,
+            Self::Normal => 1.0,
+            Self::Double => 2.0,
+            Self::Quadruple => 4.0,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::Paused => "||",
+            Self::Normal => "1x",
+            Self::Double => "2x",
+            Self::Quadruple => "4x",
+        }
+    }
+
+    fn index(self) -> usize {
+        Self::ALL.iter().position(|&speed| speed == self).unwrap()
+    }
+}
+
+#[derive(Debug, Event, Component, Copy, Clone)]
+struct SetGameSpeed(GameSpeed);
+
+/// Space -> pause/unpause, minus/equals -> slower/faster
+fn game_speed_keybinds(
+    input: Res<Input<KeyCode>>,
+    speed: Res<GameSpeed>,
+    mut unpaused: Local<Option<GameSpeed>>,
+    mut events: EventWriter<SetGameSpeed>,
+) {
+    let speed = *speed;
+    if speed != GameSpeed::Paused {
+        *unpaused = Some(speed);
+    }
+    if input.just_pressed(KeyCode::Space) {
+        events.send(SetGameSpeed(if speed == GameSpeed::Paused {
+            unpaused.unwrap_or_default()
+        } else {
+            GameSpeed::Paused
+        }));
+    }
+    if input.just_pressed(KeyCode::Minus) {
+        events.send(SetGameSpeed(
+            GameSpeed::ALL[speed.index().saturating_sub(1)],
+        ));
+    }
+    if input.just_pressed(KeyCode::Equals) {
+        events.send(SetGameSpeed(
+            GameSpeed::ALL[(speed.index() + 1).min(GameSpeed::ALL.len() - 1)],
+        ));
+    }
+}
+
+fn set_game_speed(mut events: EventReader<SetGameSpeed>, mut speed: ResMut<GameSpeed>) {
+    if let Some(event) = events.read().last() {
+        speed.set_if_neq(event.0);
+    }
+}
+
+fn apply_game_speed(speed: Res<GameSpeed>, mut time: ResMut<Time<Virtual>>) {
+    if *speed == GameSpeed::Paused {
+        time.pause();
+    } else {
+        time.unpause();
+        time.set_relative_speed(speed.multiplier());
+    }
+}
+
+fn activate_speed_buttons(
+    speed: Res<GameSpeed>,
+    mut buttons: Query<(Entity, &SetGameSpeed, &mut Interaction)>,
+    mut commands: Commands,
+) {
+    for (entity, button, mut interaction) in buttons.iter_mut() {
+        if button.0 == *speed {
+            commands.entity(entity).insert(buttons::Active);
+        } else {
+            commands.entity(entity).remove::<buttons::Active>();
+        }
+        // Make the button redraw
+        interaction.set_changed();
+    }
+}
+
+/// Speed buttons, to be put next to the elapsed time
+pub fn spawn_controls(parent: &mut ChildBuilder, text_style: &TextStyle) {
+    for speed in GameSpeed::ALL {
+        parent
+            .spawn((
+                ButtonBundle {
+                    style: Style {
+                        width: Val::Px(50.0),
+                        height: Val::Px(40.0),
+                        border: UiRect::all(Val::Px(3.0)),
+                        margin: UiRect::horizontal(Val::Px(2.0)),
+                        justify_content: JustifyContent::Center,
+                        align_items: AlignItems::Center,
+                        ..default()
+                    },
+                    ..default()
+                },
+                SetGameSpeed(speed),
+            ))
+            .with_children(|button| {
+                button.spawn(TextBundle::from_section(speed.label(), text_style.clone()));
+            });
+    }
+}
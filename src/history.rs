@@ -0,0 +1,358 @@
+//! Undo and redo of what the player builds, with Ctrl+Z and Ctrl+Y.
+//!
+//! Placing a site or starting an upgrade is taken back with a full refund until builders bring
+//! the first resource, after that it can't be undone anymore. Undoing a cancel pays again,
+//! undoing a demolish takes back the refund and what was stored in it from the storages it went to
+//! and puts the building back without its upgrades.
+
+use std::marker::PhantomData;
+
+use bevy::{
+    ecs::system::{Command, CommandQueue, EntityCommand, SystemState},
+    prelude::*,
+};
+
+use crate::{
+    ent_defs::{EntDef, EntDefs},
+    game::{
+        self, BuildingUpgrade, CancelBuildingUpgrade, CancelPlaceholder, Demolish, EntType, Money,
+        NeedsResource, Rebuilt, StartBuildingUpgrade, Storage,
+    },
+    placement::Placement,
+    resource_kind::Resources,
+    tile_map::{Pos, Rotation},
+};
+
+pub struct Plugin;
+
+impl bevy::app::Plugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<History>();
+    }
+}
+
+/// Keyboard shortcuts, not needed when running headless
+pub struct UiPlugin;
+
+impl bevy::app::Plugin for UiPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, undo_redo_on_keys);
+    }
+}
+
+/// Where a construction site or building is
+#[derive(Debug, Clone, Copy)]
+pub struct Site {
+    pub entity: Entity,
+    pub ent_type: EntType,
+    pub pos: IVec2,
+    pub rotation: Rotation,
+}
+
+/// Starts and cancels upgrades of one kind
+#[derive(Clone, Copy)]
+pub struct UpgradeCommands {
+    start: fn(Entity, &mut World),
+    cancel: fn(Entity, &mut World),
+}
+
+impl UpgradeCommands {
+    pub fn of<T: BuildingUpgrade>() -> Self {
+        Self {
+            start: |id, world| StartBuildingUpgrade::<T>(PhantomData).apply(id, world),
+            cancel: |id, world| CancelBuildingUpgrade::<T>(PhantomData).apply(id, world),
+        }
+    }
+}
+
+pub enum Action {
+    /// Paid for together, like the roads of one drag
+    Place(Vec<Site>),
+    Upgrade(Entity, UpgradeCommands),
+    CancelPlace(Site),
+    CancelUpgrade(Entity, UpgradeCommands),
+    /// Along with what was stored in it when it was demolished
+    Demolish(Site, Resources),
+}
+
+enum Outcome {
+    Done,
+    /// Not enough money or something is in the way, can be tried again later
+    Blocked,
+    /// Construction started or the building is gone, there is nothing to take back anymore
+    Stale,
+}
+
+impl Action {
+    fn entities_mut(&mut self) -> Vec<&mut Entity> {
+        match self {
+            Self::Place(sites) => sites.iter_mut().map(|site| &mut site.entity).collect(),
+            Self::Upgrade(entity, _) | Self::CancelUpgrade(entity, _) => vec![entity],
+            Self::CancelPlace(site) | Self::Demolish(site, _) => vec![&mut site.entity],
+        }
+    }
+
+    fn redo(&mut self, world: &mut World) -> Outcome {
+        match self {
+            Self::Place(sites) => place(world, sites),
+            Self::Upgrade(entity, upgrade) => start_upgrade(world, *entity, *upgrade),
+            Self::CancelPlace(site) => {
+                CancelPlaceholder.apply(site.entity, world);
+                stale_unless(world.get_entity(site.entity).is_none())
+            }
+            Self::CancelUpgrade(entity, upgrade) => {
+                let upgrading = has_needs(world, *entity);
+                (upgrade.cancel)(*entity, world);
+                stale_unless(upgrading && !has_needs(world, *entity))
+            }
+            Self::Demolish(site, stored) => {
+                *stored = world
+                    .get::<Storage>(site.entity)
+                    .map_or(Resources::ZERO, |storage| storage.current);
+                Demolish.apply(site.entity, world);
+                stale_unless(world.get_entity(site.entity).is_none())
+            }
+        }
+    }
+
+    fn undo(&mut self, world: &mut World) -> Outcome {
+        match self {
+            Self::Place(sites) => {
+                if !sites.iter().all(|site| untouched(world, site.entity)) {
+                    return Outcome::Stale;
+                }
+                for site in sites {
+                    CancelPlaceholder.apply(site.entity, world);
+                }
+                Outcome::Done
+            }
+            Self::Upgrade(entity, upgrade) => {
+                if !untouched(world, *entity) {
+                    return Outcome::Stale;
+                }
+                (upgrade.cancel)(*entity, world);
+                Outcome::Done
+            }
+            Self::CancelPlace(site) => place(world, std::slice::from_mut(site)),
+            Self::CancelUpgrade(entity, upgrade) => start_upgrade(world, *entity, *upgrade),
+            Self::Demolish(site, stored) => rebuild(world, site, *stored),
+        }
+    }
+}
+
+fn start_upgrade(world: &mut World, entity: Entity, upgrade: UpgradeCommands) -> Outcome {
+    if world.get_entity(entity).is_none() {
+        return Outcome::Stale;
+    }
+    if has_needs(world, entity) {
+        return Outcome::Blocked;
+    }
+    (upgrade.start)(entity, world);
+    if has_needs(world, entity) {
+        Outcome::Done
+    } else {
+        Outcome::Blocked
+    }
+}
+
+fn stale_unless(done: bool) -> Outcome {
+    if done {
+        Outcome::Done
+    } else {
+        Outcome::Stale
+    }
+}
+
+fn has_needs(world: &World, entity: Entity) -> bool {
+    world
+        .get_entity(entity)
+        .is_some_and(|entity| entity.contains::<NeedsResource>())
+}
+
+/// Paid for and builders didn't bring anything yet
+fn untouched(world: &World, entity: Entity) -> bool {
+    world
+        .get_entity(entity)
+        .and_then(|entity| entity.get::<NeedsResource>())
+        .is_some_and(|needs| needs.0 == needs.1)
+}
+
+/// Def and cost of every site, none if one of them can't be placed
+fn defs(world: &World, sites: &[Site]) -> Option<Vec<(EntDef, Resources)>> {
+    let defs = world.resource::<EntDefs>();
+    sites
+        .iter()
+        .map(|site| {
+            let def = defs.get(site.ent_type)?;
+            Some((def.clone(), def.cost?))
+        })
+        .collect()
+}
+
+/// The spot is still allowed, like when the player places it
+fn can_place(world: &mut World, site: &Site) -> bool {
+    SystemState::<Placement>::new(world)
+        .get(world)
+        .check(site.ent_type, site.pos, site.rotation, None)
+        .is_empty()
+}
+
+/// Pays for the sites and places them again, all of them or none.
+/// They are checked one after another, a road can rely on the one placed before it.
+fn place(world: &mut World, sites: &mut [Site]) -> Outcome {
+    let Some(defs) = defs(world, sites) else {
+        return Outcome::Blocked;
+    };
+    let total = defs
+        .iter()
+        .fold(Resources::ZERO, |total, &(_, cost)| total + cost);
+    if !world.resource::<Money>().0.covers(total) {
+        return Outcome::Blocked;
+    }
+    let mut placed = Vec::new();
+    for (site, (def, cost)) in sites.iter().zip(defs) {
+        if !can_place(world, site) {
+            for entity in placed {
+                CancelPlaceholder.apply(entity, world);
+            }
+            return Outcome::Blocked;
+        }
+        world.resource_mut::<Money>().0 -= cost;
+        let mut queue = CommandQueue::default();
+        let mut commands = Commands::new(&mut queue, world);
+        placed.push(game::spawn_placeholder(
+            &mut commands,
+            &def,
+            site.ent_type,
+            site.pos,
+            site.rotation,
+            NeedsResource(cost, cost),
+        ));
+        queue.apply(world);
+    }
+    for (site, entity) in sites.iter_mut().zip(placed) {
+        replace(world, site.entity, entity);
+        site.entity = entity;
+    }
+    Outcome::Done
+}
+
+/// Takes back the demolish refund and what was stored for the building as it was built
+fn rebuild(world: &mut World, site: &mut Site, stored: Resources) -> Outcome {
+    let Some(cost) = world
+        .resource::<EntDefs>()
+        .get(site.ent_type)
+        .and_then(|def| def.cost)
+    else {
+        return Outcome::Blocked;
+    };
+    let refund = game::demolish_refund(cost);
+    if !world.resource::<Money>().0.covers(refund)
+        || !can_place(world, site)
+        || !game::take_back_resources(world, site.pos, refund + stored)
+    {
+        return Outcome::Blocked;
+    }
+    world.resource_mut::<Money>().0 -= refund;
+    let entity = world
+        .spawn((Pos(site.pos), site.rotation, site.ent_type, Rebuilt(stored)))
+        .id();
+    replace(world, site.entity, entity);
+    site.entity = entity;
+    Outcome::Done
+}
+
+/// Actions the player can undo, and redo after that
+#[derive(Resource, Default)]
+pub struct History {
+    done: Vec<Action>,
+    undone: Vec<Action>,
+}
+
+impl History {
+    /// Doing something new forgets what was undone
+    pub fn push(&mut self, action: Action) {
+        self.done.push(action);
+        self.undone.clear();
+    }
+
+    /// Forgets everything, for when the entities are gone like after loading a save
+    pub fn clear(&mut self) {
+        self.done.clear();
+        self.undone.clear();
+    }
+}
+
+/// Placing again gives the sites new entities
+fn replace(world: &mut World, old: Entity, new: Entity) {
+    let mut history = world.resource_mut::<History>();
+    let history = &mut *history;
+    for action in history.done.iter_mut().chain(&mut history.undone) {
+        for entity in action.entities_mut() {
+            if *entity == old {
+                *entity = new;
+            }
+        }
+    }
+}
+
+/// Does the action and remembers it if it did anything
+pub struct Perform(pub Action);
+
+impl Command for Perform {
+    fn apply(mut self, world: &mut World) {
+        if let Outcome::Done = self.0.redo(world) {
+            world.resource_mut::<History>().push(self.0);
+        }
+    }
+}
+
+/// Takes back the last action, forgetting ones that can't be taken back anymore on the way
+pub struct Undo;
+
+impl Command for Undo {
+    fn apply(self, world: &mut World) {
+        while let Some(mut action) = world.resource_mut::<History>().done.pop() {
+            match action.undo(world) {
+                Outcome::Done => {
+                    world.resource_mut::<History>().undone.push(action);
+                    return;
+                }
+                Outcome::Blocked => {
+                    world.resource_mut::<History>().done.push(action);
+                    return;
+                }
+                Outcome::Stale => {}
+            }
+        }
+    }
+}
+
+/// Does the last undone action again
+pub struct Redo;
+
+impl Command for Redo {
+    fn apply(self, world: &mut World) {
+        let Some(mut action) = world.resource_mut::<History>().undone.pop() else {
+            return;
+        };
+        match action.redo(world) {
+            Outcome::Done => world.resource_mut::<History>().done.push(action),
+            Outcome::Blocked => world.resource_mut::<History>().undone.push(action),
+            // Whatever comes after it was done on top of it
+            Outcome::Stale => world.resource_mut::<History>().undone.clear(),
+        }
+    }
+}
+
+fn undo_redo_on_keys(keyboard: Res<Input<KeyCode>>, mut commands: Commands) {
+    if !keyboard.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]) {
+        return;
+    }
+    let shift = keyboard.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]);
+    if keyboard.just_pressed(KeyCode::Y) || (shift && keyboard.just_pressed(KeyCode::Z)) {
+        commands.add(Redo);
+    } else if keyboard.just_pressed(KeyCode::Z) {
+        commands.add(Undo);
+    }
+}
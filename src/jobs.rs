@@ -0,0 +1,290 @@
+//! Work posted by buildings and claimed by crabs that can do it.
+//!
+//! Construction sites post [JobKind::Build] jobs that builders fill by delivering resources from storages,
+//! upgrade buildings post [JobKind::Upgrade] jobs for harvesters and storages post [JobKind::Store]
+//! jobs for the space they have left. Crabs claim open jobs of the kinds the player put first in
+//! [JobPriorities], builders in the order of the
+//! [ConstructionQueue](crate::construction_queue::ConstructionQueue) and others the closest one.
+
+use bevy::{prelude::*, utils::HashMap};
+use serde::{Deserialize, Serialize};
+
+use crate::{buttons, game::SimulationSet};
+
+pub struct Plugin;
+
+impl bevy::app::Plugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<JobBoard>();
+        app.init_resource::<JobPriorities>();
+        app.add_systems(FixedUpdate, count_claims.before(SimulationSet));
+    }
+}
+
+/// Priority controls, not needed when running headless
+pub struct UiPlugin;
+
+impl bevy::app::Plugin for UiPlugin {
+    fn build(&self, app: &mut App) {
+        buttons::register::<ChangePriority>(app);
+        app.add_systems(Update, (change_priorities, update_priority_texts).chain());
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum JobKind {
+    Build,
+    Upgrade,
+    /// Not posted, harvesters fall back to it when there is nothing more important and builders
+    /// do it if it comes before building
+    Harvest,
+    /// Bringing what a crab carries to a storage
+    Store,
+}
+
+impl JobKind {
+    pub const ALL: [Self; 4] = [Self::Build, Self::Upgrade, Self::Harvest, Self::Store];
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::Build => "Build",
+            Self::Upgrade => "Upgrade",
+            Self::Harvest => "Harvest",
+            Self::Store => "Store",
+        }
+    }
+}
+
+pub const MAX_PRIORITY: u8 = 5;
+
+/// Player set priority of each kind of job, crabs go for the highest one they can do.
+/// Jobs with priority 0 are not done at all.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobPriorities([u8; 4]);
+
+impl Default for JobPriorities {
+    /// Harvesters leave their work for upgrades and builders keep building, like they always did
+    fn default() -> Self {
+        Self([3, 3, 2, 3])
+    }
+}
+
+impl JobPriorities {
+    pub fn get(&self, kind: JobKind) -> u8 {
+        self.0[kind as usize]
+    }
+
+    pub fn set(&mut self, kind: JobKind, priority: u8) {
+        self.0[kind as usize] = priority.min(MAX_PRIORITY);
+    }
+
+    pub fn enabled(&self, kind: JobKind) -> bool {
+        self.get(kind) > 0
+    }
+
+    /// Whether a crab doing `current` should drop it for `other`
+    pub fn prefers(&self, other: JobKind, current: JobKind) -> bool {
+        self.get(other) > self.get(current)
+    }
+}
+
+/// Run condition for systems doing a kind of job
+pub fn enabled(kind: JobKind) -> impl Fn(Res<JobPriorities>) -> bool {
+    move |priorities| priorities.enabled(kind)
+}
+
+pub struct Job {
+    pub pos: IVec2,
+    /// Work left, in whatever unit the kind of job counts in, like resources for building
+    pub wanted: i32,
+    /// Work crabs already claimed
+    pub claimed: i32,
+}
+
+impl Job {
+    pub fn is_open(&self) -> bool {
+        self.claimed < self.wanted
+    }
+}
+
+/// Open jobs by the entity they are for
+#[derive(Resource, Default)]
+pub struct JobBoard {
+    jobs: HashMap<(Entity, JobKind), Job>,
+}
+
+impl JobBoard {
+    /// Posts the job or updates how much work is left, keeping what is claimed
+    pub fn post(&mut self, target: Entity, kind: JobKind, pos: IVec2, wanted: i32) {
+        let job = self.jobs.entry((target, kind)).or_insert(Job {
+            pos,
+            wanted,
+            claimed: 0,
+        });
+        job.pos = pos;
+        job.wanted = wanted;
+    }
+
+    pub fn remove(&mut self, target: Entity, kind: JobKind) {
+        self.jobs.remove(&(target, kind));
+    }
+
+    pub fn get(&self, target: Entity, kind: JobKind) -> Option<&Job> {
+        self.jobs.get(&(target, kind))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Entity, JobKind, &Job)> {
+        self.jobs
+            .iter()
+            .map(|(&(target, kind), job)| (target, kind, job))
+    }
+
+    /// Closest open job of the kind that `filter` accepts, none if the player turned the kind off
+    pub fn find(
+        &self,
+        priorities: &JobPriorities,
+        kind: JobKind,
+        from: IVec2,
+        filter: impl Fn(Entity) -> bool,
+    ) -> Option<Entity> {
+        if !priorities.enabled(kind) {
+            return None;
+        }
+        self.jobs
+            .iter()
+            .filter(|(&(target, job_kind), job)| {
+                job_kind == kind && job.is_open() && filter(target)
+            })
+            .min_by_key(|(&(target, _), job)| ((job.pos - from).abs().max_element(), target))
+            .map(|(&(target, _), _)| target)
+    }
+
+    /// First open job of the kind in `order` that `filter` accepts, none if the player turned the kind off
+    pub fn first(
+        &self,
+        priorities: &JobPriorities,
+        kind: JobKind,
+        mut order: impl Iterator<Item = Entity>,
+        filter: impl Fn(Entity) -> bool,
+    ) -> Option<Entity> {
+        if !priorities.enabled(kind) {
+            return None;
+        }
+        order.find(|&target| self.get(target, kind).is_some_and(Job::is_open) && filter(target))
+    }
+
+    /// Counts work as taken until [Claim]s are counted again next tick
+    pub fn claim(&mut self, target: Entity, kind: JobKind, amount: i32) {
+        if let Some(job) = self.jobs.get_mut(&(target, kind)) {
+            job.claimed += amount;
+        }
+    }
+}
+
+/// Job the crab is working on, removed when it is done or given up
+#[derive(Component, Debug)]
+pub struct Claim {
+    pub target: Entity,
+    pub kind: JobKind,
+    pub amount: i32,
+}
+
+fn count_claims(claims: Query<&Claim>, mut board: ResMut<JobBoard>) {
+    for job in board.jobs.values_mut() {
+        job.claimed = 0;
+    }
+    for claim in claims.iter() {
+        board.claim(claim.target, claim.kind, claim.amount);
+    }
+}
+
+#[derive(Debug, Event, Component, Copy, Clone)]
+struct ChangePriority {
+    kind: JobKind,
+    up: bool,
+}
+
+#[derive(Component)]
+struct PriorityText(JobKind);
+
+fn change_priorities(
+    mut events: EventReader<ChangePriority>,
+    mut priorities: ResMut<JobPriorities>,
+) {
+    for event in events.read() {
+        let priority = priorities.get(event.kind);
+        let priority = if event.up {
+            priority + 1
+        } else {
+            priority.saturating_sub(1)
+        };
+        priorities.set(event.kind, priority);
+    }
+}
+
+fn update_priority_texts(
+    priorities: Res<JobPriorities>,
+    mut texts: Query<(&mut Text, &PriorityText)>,
+) {
+    for (mut text, kind) in texts.iter_mut() {
+        let priority = priorities.get(kind.0).to_string();
+        if text.sections[0].value != priority {
+            text.sections[0].value = priority;
+        }
+    }
+}
+
+/// One row per kind of job with its priority and buttons changing it
+pub fn spawn_controls(parent: &mut ChildBuilder, text_style: &TextStyle) {
+    for kind in JobKind::ALL {
+        parent
+            .spawn(NodeBundle {
+                style: Style {
+                    align_items: AlignItems::Center,
+                    ..default()
+                },
+                ..default()
+            })
+            .with_children(|row| {
+                row.spawn(
+                    TextBundle::from_section(kind.label(), text_style.clone()).with_style(Style {
+                        width: Val::Px(130.0),
+                        ..default()
+                    }),
+                );
+                for up in [false, true] {
+                    row.spawn((
+                        ButtonBundle {
+                            style: Style {
+                                width: Val::Px(40.0),
+                                height: Val::Px(40.0),
+                                border: UiRect::all(Val::Px(3.0)),
+                                margin: UiRect::all(Val::Px(2.0)),
+                                justify_content: JustifyContent::Center,
+                                align_items: AlignItems::Center,
+                                ..default()
+                            },
+                            ..default()
+                        },
+                        ChangePriority { kind, up },
+                    ))
+                    .with_children(|button| {
+                        button.spawn(TextBundle::from_section(
+                            if up { "+" } else { "-" },
+                            text_style.clone(),
+                        ));
+                    });
+                    if !up {
+                        row.spawn((
+                            TextBundle::from_section("", text_style.clone()).with_style(Style {
+                                width: Val::Px(30.0),
+                                justify_content: JustifyContent::Center,
+                                ..default()
+                            }),
+                            PriorityText(kind),
+                        ));
+                    }
+                }
+            });
+    }
+}
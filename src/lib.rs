@@ -0,0 +1,78 @@
+//! The game as a library, so mods can register their own buildings
+//! (see [buildings]) and run it with their plugins added
+
+use bevy::asset::AssetMetaCheck;
+use bevy::prelude::*;
+use bevy::DefaultPlugins;
+
+pub mod audio;
+pub mod buildings;
+pub mod buttons;
+pub mod camera_controls;
+pub mod chunks;
+pub mod construction_queue;
+pub mod cursor;
+pub mod ent_defs;
+pub mod game;
+pub mod game_speed;
+pub mod history;
+pub mod jobs;
+pub mod meshes;
+pub mod pathfind;
+pub mod placement;
+pub mod removed;
+pub mod resource_kind;
+pub mod save;
+pub mod terrain;
+#[cfg(test)]
+mod tests;
+pub mod tile_map;
+pub mod ui;
+pub mod unit_state;
+
+/// The whole game, mods add their plugins to it before running
+pub fn app() -> App {
+    let mut app = App::new();
+    app.insert_resource(Msaa::Off)
+        .insert_resource(ClearColor(Color::rgb(0.4, 0.4, 0.4)))
+        .insert_resource(AssetMetaCheck::Never)
+        .insert_resource(game::WorldSeed::from_args().unwrap_or_else(game::WorldSeed::random))
+        .add_plugins(
+            DefaultPlugins
+                .set(WindowPlugin {
+                    primary_window: Some(Window {
+                        title: "Ents".to_string(),
+                        // Bind to canvas included in `index.html`
+                        canvas: Some("#bevy".to_owned()),
+                        // The canvas size is constrained in index.html and build/web/styles.css
+                        fit_canvas_to_parent: true,
+                        // Tells wasm to override default event handling, like F5 and Ctrl+R
+                        prevent_default_event_handling: true,
+                        ..default()
+                    }),
+                    ..default()
+                })
+                .set(ImagePlugin::default_nearest()),
+        )
+        .add_plugins((
+            bevy_geng_audio::AudioPlugin,
+            game::GamePlugin,
+            game_speed::Plugin,
+            (
+                jobs::UiPlugin,
+                construction_queue::UiPlugin,
+                history::UiPlugin,
+                terrain::UiPlugin,
+            ),
+            ent_defs::Plugin,
+            cursor::Plugin,
+            buttons::Plugin,
+            ui::Plugin,
+            chunks::CameraPlugin,
+            camera_controls::Plugin,
+            audio::Plugin,
+            save::Plugin,
+            unit_state::DebugViewPlugin,
+        ));
+    app
+}
@@ -1,44 +1,42 @@
 use bevy::prelude::*;
 use bevy::render::mesh::shape::Box;
 use bevy::render::{
-    mesh::{Indices, Mesh},
+    mesh::{Indices, Mesh, VertexAttributeValues},
     render_resource::PrimitiveTopology,
 };
 
-pub fn make_plane(size: f32) -> Mesh {
-    // here this is split in the z and x directions if one ever needs asymmetrical subdivision
-    // two Plane struct fields would need to be added instead of the single subdivisions field
-    let z_vertex_count = 2;
-    let x_vertex_count = 2;
-    let num_vertices = (z_vertex_count * x_vertex_count) as usize;
-    let num_indices = ((z_vertex_count - 1) * (x_vertex_count - 1) * 6) as usize;
-    let up = Vec3::Y.to_array();
-
+/// A quad per tile of a `size` by `size` square, `heights` are at the tile corners
+/// and `colors` tint each tile
+pub fn ground_mesh(size: i32, heights: &[f32], colors: &[Color]) -> Mesh {
+    let num_vertices = (size * size * 4) as usize;
     let mut positions: Vec<[f32; 3]> = Vec::with_capacity(num_vertices);
     let mut normals: Vec<[f32; 3]> = Vec::with_capacity(num_vertices);
     let mut uvs: Vec<[f32; 2]> = Vec::with_capacity(num_vertices);
-    let mut indices: Vec<u32> = Vec::with_capacity(num_indices);
-
-    for z in 0..z_vertex_count {
-        for x in 0..x_vertex_count {
-            let tx = x as f32 / (x_vertex_count - 1) as f32;
-            let tz = z as f32 / (z_vertex_count - 1) as f32;
-            let pos = [(-0.5 + tx) * size, 0.0, (-0.5 + tz) * size];
-            positions.push(pos);
-            normals.push(up);
-            uvs.push([pos[0], pos[2]]);
-        }
-    }
+    let mut vertex_colors: Vec<[f32; 4]> = Vec::with_capacity(num_vertices);
+    let mut indices: Vec<u32> = Vec::with_capacity((size * size * 6) as usize);
 
-    for y in 0..z_vertex_count - 1 {
-        for x in 0..x_vertex_count - 1 {
-            let quad = y * x_vertex_count + x;
-            indices.push(quad + x_vertex_count + 1);
-            indices.push(quad + 1);
-            indices.push(quad + x_vertex_count);
-            indices.push(quad);
-            indices.push(quad + x_vertex_count);
-            indices.push(quad + 1);
+    let corner =
+        |x: i32, z: i32| Vec3::new(x as f32, heights[(z * (size + 1) + x) as usize], z as f32);
+    for z in 0..size {
+        for x in 0..size {
+            let quad = positions.len() as u32;
+            let corners = [
+                corner(x, z),
+                corner(x + 1, z),
+                corner(x, z + 1),
+                corner(x + 1, z + 1),
+            ];
+            let normal = (corners[2] - corners[1])
+                .cross(corners[3] - corners[0])
+                .normalize();
+            let color = colors[(z * size + x) as usize].as_linear_rgba_f32();
+            for pos in corners {
+                positions.push(pos.to_array());
+                normals.push(normal.to_array());
+                uvs.push([pos.x, pos.z]);
+                vertex_colors.push(color);
+            }
+            indices.extend([quad + 3, quad + 1, quad + 2, quad, quad + 2, quad + 1]);
         }
     }
 
@@ -47,6 +45,7 @@ pub fn make_plane(size: f32) -> Mesh {
         .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
         .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
         .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_COLOR, vertex_colors)
 }
 
 pub fn make_resource() -> Mesh {
@@ -313,3 +312,46 @@ pub fn building_mesh(size: IVec2, floor_height: f32, floors: usize) -> Mesh {
         .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs)
         .with_indices(Some(indices))
 }
+
+/// [building_mesh] for footprints that don't cover their whole size, one column per tile
+pub fn shaped_building_mesh(
+    tiles: &[IVec2],
+    size: IVec2,
+    floor_height: f32,
+    floors: usize,
+) -> Mesh {
+    let column = building_mesh(IVec2::ONE, floor_height, floors);
+    let attribute = |id| match column.attribute(id) {
+        Some(VertexAttributeValues::Float32x3(values)) => values.clone(),
+        Some(VertexAttributeValues::Float32x2(values)) => {
+            values.iter().map(|&[u, v]| [u, v, 0.0]).collect()
+        }
+        _ => unreachable!(),
+    };
+    let column_positions = attribute(Mesh::ATTRIBUTE_POSITION);
+    let column_normals = attribute(Mesh::ATTRIBUTE_NORMAL);
+    let column_uvs = attribute(Mesh::ATTRIBUTE_UV_0);
+    let column_indices: Vec<u32> = column.indices().unwrap().iter().map(|i| i as u32).collect();
+
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut uvs = Vec::new();
+    let mut indices = Vec::new();
+    for &tile in tiles {
+        let offset = tile.as_vec2() + Vec2::splat(0.5) - size.as_vec2() / 2.0;
+        indices.extend(column_indices.iter().map(|i| i + positions.len() as u32));
+        positions.extend(
+            column_positions
+                .iter()
+                .map(|&[x, y, z]| [x + offset.x, y, z + offset.y]),
+        );
+        normals.extend(column_normals.iter().copied());
+        uvs.extend(column_uvs.iter().map(|&[u, v, _]| [u, v]));
+    }
+
+    Mesh::new(PrimitiveTopology::TriangleList)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs)
+        .with_indices(Some(Indices::U32(indices)))
+}
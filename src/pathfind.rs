@@ -1,21 +1,71 @@
-use std::{collections::BinaryHeap, marker::PhantomData};
+use std::{
+    collections::{BTreeMap, BinaryHeap},
+    marker::PhantomData,
+    sync::Arc,
+};
 
-use bevy::{prelude::*, utils::HashMap};
+#[cfg(not(target_arch = "wasm32"))]
+use bevy::tasks::{block_on, AsyncComputeTaskPool, Task};
+use bevy::{
+    ecs::system::SystemParam,
+    prelude::*,
+    utils::{Duration, HashMap, HashSet, Instant},
+};
 use rand::{seq::SliceRandom, thread_rng};
 
 use crate::{
     chunks::GeneratedChunks,
-    game::{CanMove, MOVE_DIRECTIONS},
-    tile_map::{Pos, Size, TileMap},
+    game::{CanMove, Moving, DIAGONAL_DIRECTIONS, MOVE_DIRECTIONS},
+    removed::{AppExt as _, Removed, RemovedSet},
+    terrain::{Terrain, TerrainMap},
+    tile_map::{footprint, update_tile_map, Pos, Shape, Size, TileFlag, TileMap},
 };
 
+/// Paths longer than this are not worth walking, same limit flow fields have
+const MAX_PATH_DISTANCE: u32 = 1000 * ROAD_STEP;
+/// Cost of stepping onto a road, see [step_cost]
+const ROAD_STEP: u32 = 5;
+/// Tiles one search may look at before giving up, keeps searches for unreachable targets cheap
+const MAX_SEARCHED_TILES: usize = 50_000;
+/// The cache is dropped once it remembers this many tiles
+const MAX_CACHED_TILES: usize = 100_000;
+/// Units are near a tile if they are in the same or a neighbouring region
+const REGION_SIZE: i32 = 16;
+/// Units standing on or walking onto a tile at once
+const MAX_OCCUPANCY: usize = 2;
+/// Crabs squeeze past each other on roads
+const MAX_ROAD_OCCUPANCY: usize = 4;
+
 pub struct Plugin;
 
 impl bevy::app::Plugin for Plugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Update, despawn_debug);
+        app.add_event::<ResetPathfinding>();
+        app.init_resource::<PathfindingBudget>();
+        #[cfg(target_arch = "wasm32")]
+        app.add_systems(First, reset_frame_budget);
+        app.init_resource::<PathfindingMetrics>();
+        app.init_resource::<MapSnapshot>();
+        // Crabs follow the fields during ticks, so they follow the map tick by tick too
+        app.configure_sets(
+            FixedUpdate,
+            PathfindingSet.after(update_tile_map).after(RemovedSet),
+        );
+        app.add_systems(
+            FixedUpdate,
+            update_map_snapshot
+                .in_set(PathfindingSet)
+                .before(PathfindingIteration),
+        );
         app.insert_resource(Ents::default());
-        app.add_systems(Update, update_ents);
+        app.keep_removed::<CanMove>().keep_removed::<Moving>();
+        app.add_systems(FixedUpdate, update_ents.after(RemovedSet));
+        app.init_resource::<PathCache>();
+        app.add_systems(
+            Update,
+            clear_path_cache.run_if(on_event::<ResetPathfinding>()),
+        );
+        app.add_systems(FixedUpdate, invalidate_path_cache.in_set(PathfindingSet));
     }
 }
 
@@ -27,13 +77,172 @@ impl AppExt for App {
     fn register_pathfinding_towards<C: Component>(&mut self) {
         self.insert_resource(Pathfinding::<C> {
             closest: default(),
+            targets: default(),
             updates: default(),
+            changed: default(),
+            target_changes: default(),
+            field: Some(default()),
+            task: None,
             phantom_data: PhantomData,
         });
-        self.add_systems(Update, (detect_map_updates::<C>, pathfind_iteration::<C>));
+        self.world
+            .get_resource_or_insert_with(PathfindingBudget::default)
+            .fields += 1;
+        self.keep_removed::<C>();
+        // Loading can happen while paused, when there are no ticks to catch the event
+        self.add_systems(
+            Update,
+            reset_pathfinding::<C>.run_if(on_event::<ResetPathfinding>()),
+        );
+        self.add_systems(
+            FixedUpdate,
+            collect_recompute::<C>
+                .in_set(PathfindingSet)
+                .before(update_map_snapshot),
+        );
+        self.add_systems(
+            FixedUpdate,
+            (detect_map_updates::<C>, pathfind_iteration::<C>)
+                .chain()
+                .in_set(PathfindingSet)
+                .in_set(PathfindingIteration),
+        );
+    }
+}
+
+/// Keeping flow fields and paths up with the map, runs every tick before
+/// [SimulationSet](crate::game::SimulationSet)
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PathfindingSet;
+
+/// Starting and collecting background flow field recomputes
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+struct PathfindingIteration;
+
+/// Time flow field recomputes get per tick, split evenly between the fields.
+///
+/// Recomputes run on [AsyncComputeTaskPool], one per field at a time, and are picked up on the next
+/// tick. On the web they run right away on the main thread, so the time is shared by everything
+/// running in a frame and a frame catching up on several ticks only gets one tick's worth.
+#[derive(Resource)]
+pub struct PathfindingBudget {
+    pub time_per_tick: Duration,
+    /// Updates per tick no matter how fast the machine is, so a batch never holds up
+    /// crabs for long
+    pub max_updates_per_tick: usize,
+    /// Waits for running recomputes on the next tick instead of picking them up once they are
+    /// done, so scenarios play out the same on every machine
+    pub wait_for_recomputes: bool,
+    fields: usize,
+    #[cfg(target_arch = "wasm32")]
+    left_this_frame: Duration,
+}
+
+impl Default for PathfindingBudget {
+    fn default() -> Self {
+        Self {
+            time_per_tick: Duration::from_millis(8),
+            max_updates_per_tick: 40_000,
+            wait_for_recomputes: false,
+            fields: 0,
+            #[cfg(target_arch = "wasm32")]
+            left_this_frame: Duration::ZERO,
+        }
+    }
+}
+
+/// What one recompute may spend
+#[derive(Clone, Copy)]
+struct RecomputeBudget {
+    time: Duration,
+    updates: usize,
+}
+
+impl PathfindingBudget {
+    fn per_field(&self) -> RecomputeBudget {
+        let fields = self.fields.max(1);
+        RecomputeBudget {
+            time: self.time_per_tick / fields as u32,
+            updates: (self.max_updates_per_tick / fields).max(1),
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn take_for_field(&mut self) -> Option<RecomputeBudget> {
+        Some(self.per_field())
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn take_for_field(&mut self) -> Option<RecomputeBudget> {
+        let budget = self.per_field();
+        Some(RecomputeBudget {
+            time: budget.time.min(self.left_this_frame),
+            ..budget
+        })
+        .filter(|budget| !budget.time.is_zero())
+    }
+
+    /// The batch was done right away, whatever it took is gone for the rest of the frame
+    #[cfg(target_arch = "wasm32")]
+    fn spend(&mut self, batch: &Batch) {
+        self.left_this_frame = self.left_this_frame.saturating_sub(batch.elapsed);
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn reset_frame_budget(mut budget: ResMut<PathfindingBudget>) {
+    budget.left_this_frame = budget.time_per_tick;
+}
+
+/// How well flow fields keep up with the map, by type name of what they lead to
+#[derive(Resource, Default, Debug)]
+pub struct PathfindingMetrics {
+    pub fields: BTreeMap<&'static str, FieldMetrics>,
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct FieldMetrics {
+    /// Tiles waiting to be updated, not counting the ones a still running recompute has
+    pub queue_len: usize,
+    pub updates_last_batch: usize,
+    pub time_last_batch: Duration,
+    /// Ticks the field took to settle the last time the map changed
+    pub last_convergence: Option<u32>,
+    busy_for: Option<u32>,
+}
+
+impl PathfindingMetrics {
+    pub fn field<T>(&self) -> Option<&FieldMetrics> {
+        self.fields.get(std::any::type_name::<T>())
+    }
+
+    pub fn total_queue_len(&self) -> usize {
+        self.fields.values().map(|field| field.queue_len).sum()
     }
 }
 
+/// Forget everything calculated so far, e.g. when the whole world was replaced
+#[derive(Event)]
+pub struct ResetPathfinding;
+
+fn reset_pathfinding<T: Component>(mut data: ResMut<Pathfinding<T>>) {
+    let data = &mut *data;
+    // Dropping the task cancels it, the field starts over from the targets known so far
+    data.task = None;
+    data.closest = default();
+    data.field = Some(Field {
+        targets: data.targets.keys().copied().collect(),
+        ..default()
+    });
+    data.target_changes.clear();
+    data.changed.clear();
+    data.updates = data
+        .targets
+        .keys()
+        .map(|&pos| Update { distance: 0, pos })
+        .collect();
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 struct Closest {
     distance: u32,
@@ -42,11 +251,90 @@ struct Closest {
 
 #[derive(Resource)]
 pub struct Pathfinding<T> {
-    closest: HashMap<IVec2, Closest>,
+    /// Published by the last finished recompute, the running one works on the [Field]
+    closest: Arc<HashMap<IVec2, Closest>>,
+    /// Footprint tiles of the targets, with how many targets cover them
+    targets: HashMap<IVec2, u32>,
+    /// Queued since the running recompute started
     updates: BinaryHeap<Update>,
+    /// Tiles the map changed on since the running recompute started, also in `updates`
+    changed: Vec<IVec2>,
+    /// Tiles that started or stopped being a target since the running recompute started
+    target_changes: Vec<(IVec2, bool)>,
+    /// Taken by the running recompute, back here when it is published
+    field: Option<Field>,
+    task: Option<RecomputeTask>,
     phantom_data: PhantomData<T>,
 }
 
+impl<T> Pathfinding<T> {
+    fn update_at(&mut self, pos: IVec2) {
+        self.updates.push(Update { distance: 0, pos });
+        self.changed.push(pos);
+    }
+
+    fn add_target(&mut self, pos: IVec2) {
+        let count = self.targets.entry(pos).or_default();
+        *count += 1;
+        if *count == 1 {
+            self.target_changes.push((pos, true));
+        }
+        self.update_at(pos);
+    }
+
+    fn remove_target(&mut self, pos: IVec2) {
+        let Some(count) = self.targets.get_mut(&pos) else {
+            return;
+        };
+        *count -= 1;
+        if *count == 0 {
+            self.targets.remove(&pos);
+            self.target_changes.push((pos, false));
+        }
+        self.update_at(pos);
+    }
+}
+
+/// Flow field recomputes work on, the map published before the last batch and that batch's changes.
+///
+/// Publishing a batch hands the map published before it back as the next working copy,
+/// so the whole map is never copied.
+#[derive(Default)]
+struct Field {
+    closest: HashMap<IVec2, Closest>,
+    targets: HashSet<IVec2>,
+    /// Made by the last batch, see [Field::catch_up]
+    changes: Vec<(IVec2, Option<Closest>)>,
+}
+
+impl Field {
+    /// Brings the map up to the published one and takes the target changes since
+    fn catch_up(&mut self, target_changes: Vec<(IVec2, bool)>) {
+        for (pos, closest) in self.changes.drain(..) {
+            match closest {
+                Some(closest) => self.closest.insert(pos, closest),
+                None => self.closest.remove(&pos),
+            };
+        }
+        for (pos, is_target) in target_changes {
+            if is_target {
+                self.targets.insert(pos);
+            } else {
+                self.targets.remove(&pos);
+            }
+        }
+    }
+
+    fn set(&mut self, pos: IVec2, closest: Option<Closest>) {
+        match closest {
+            Some(closest) => self.closest.insert(pos, closest),
+            None => self.closest.remove(&pos),
+        };
+        self.changes.push((pos, closest));
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
 pub struct Direction {
     pub dir: IVec2,
     pub distance: u32,
@@ -56,75 +344,195 @@ pub struct Direction {
 pub struct Ents {
     map: HashMap<IVec2, usize>,
     prev: HashMap<Entity, IVec2>,
+    regions: HashMap<IVec2, usize>,
+    /// Tiles units are walking onto
+    reserved: HashMap<Entity, IVec2>,
+    reserved_tiles: HashMap<IVec2, usize>,
 }
 
-fn update_ents(
+pub fn update_ents(
     mut res: ResMut<Ents>,
     ents: Query<(Entity, &Pos), (With<CanMove>, Changed<Pos>)>,
-    mut removed: RemovedComponents<CanMove>,
+    moving: Query<(), With<Moving>>,
+    removed: Res<Removed<CanMove>>,
+    stopped: Res<Removed<Moving>>,
 ) {
-    for entity in removed.read() {
+    // Units that stopped before getting there don't keep the tile,
+    // units walking again by then hold their new tile.
+    for entity in stopped.iter() {
+        if !moving.contains(entity) {
+            res.release(entity);
+        }
+    }
+    for entity in removed.iter() {
+        res.release(entity);
         if let Some(pos) = res.prev.remove(&entity) {
             *res.map.get_mut(&pos).unwrap() -= 1;
+            *res.regions.get_mut(&region(pos)).unwrap() -= 1;
         }
     }
     for (entity, pos) in ents.iter() {
+        if res.reserved.get(&entity) == Some(&pos.0) {
+            res.release(entity);
+        }
         if let Some(&pos) = res.prev.get(&entity) {
             *res.map.get_mut(&pos).unwrap() -= 1;
+            *res.regions.get_mut(&region(pos)).unwrap() -= 1;
         }
         *res.map.entry(pos.0).or_default() += 1;
+        *res.regions.entry(region(pos.0)).or_default() += 1;
         res.prev.insert(entity, pos.0);
     }
 }
 
+/// Units that fit on a tile
+pub fn capacity(road: bool) -> usize {
+    if road {
+        MAX_ROAD_OCCUPANCY
+    } else {
+        MAX_OCCUPANCY
+    }
+}
+
+fn region(pos: IVec2) -> IVec2 {
+    pos.div_euclid(IVec2::splat(REGION_SIZE))
+}
+
 impl Ents {
-    fn contains(&self, pos: IVec2) -> bool {
-        match self.map.get(&pos) {
-            Some(&amount) => amount != 0,
-            None => false,
+    /// Units standing on the tile and the ones walking onto it
+    fn occupancy(&self, pos: IVec2) -> usize {
+        self.map.get(&pos).copied().unwrap_or(0)
+            + self.reserved_tiles.get(&pos).copied().unwrap_or(0)
+    }
+
+    pub fn has_room(&self, pos: IVec2, road: bool) -> bool {
+        self.occupancy(pos) < capacity(road)
+    }
+
+    /// Counts the unit as being on the tile until it gets there
+    pub fn reserve(&mut self, entity: Entity, pos: IVec2) {
+        self.release(entity);
+        self.reserved.insert(entity, pos);
+        *self.reserved_tiles.entry(pos).or_default() += 1;
+    }
+
+    pub fn release(&mut self, entity: Entity) {
+        if let Some(pos) = self.reserved.remove(&entity) {
+            *self.reserved_tiles.get_mut(&pos).unwrap() -= 1;
         }
     }
+
+    fn occupied_regions(&self) -> HashSet<IVec2> {
+        self.regions
+            .iter()
+            .filter(|(_, &amount)| amount != 0)
+            .map(|(&region, _)| region)
+            .collect()
+    }
+}
+
+/// Flow fields update these tiles first, crabs are about to walk there
+fn is_near(pos: IVec2, occupied: impl Fn(IVec2) -> bool) -> bool {
+    let region = region(pos);
+    (-1..=1).any(|x| (-1..=1).any(|y| occupied(region + IVec2::new(x, y))))
+}
+
+/// Walking onto a road costs the same everywhere, off roads it depends on the [Terrain].
+/// Diagonal steps cost about √2 times straight ones.
+pub fn step_cost(road: bool, diagonal: bool, terrain: Terrain) -> u32 {
+    let straight = if road {
+        ROAD_STEP
+    } else {
+        terrain.walk_cost() * ROAD_STEP
+    };
+    if diagonal {
+        straight * 7 / 5
+    } else {
+        straight
+    }
+}
+
+/// Diagonal steps squeezing between two tiles where at least one is blocking
+pub fn cuts_corner(from: IVec2, dir: IVec2, is_blocking: impl Fn(IVec2) -> bool) -> bool {
+    dir.x != 0
+        && dir.y != 0
+        && (is_blocking(from + IVec2::new(dir.x, 0)) || is_blocking(from + IVec2::new(0, dir.y)))
 }
 
 impl<T> Pathfinding<T> {
+    /// Also returns the cost of getting to the target that way
     fn pathfind_using_direction(
         &self,
         from: IVec2,
+        tile_map: &TileMap,
         directions: impl IntoIterator<Item = IVec2>,
-    ) -> Option<Direction> {
-        let closest_distance = directions
+    ) -> Option<(Direction, u32)> {
+        let options: Vec<(IVec2, u32, f64)> = directions
             .into_iter()
-            .filter_map(|dir| self.closest.get(&(from + dir)))
-            .map(|closest| closest.distance)
-            .min()?;
-        let dir = MOVE_DIRECTIONS
-            .choose_weighted(&mut thread_rng(), |&dir| {
-                self.closest.get(&(from + dir)).map_or(0.0, |closest| {
-                    if closest.distance == closest_distance {
-                        closest.ways
-                    } else {
-                        0.0
-                    }
-                })
+            .filter_map(|dir| {
+                let closest = self.closest.get(&(from + dir))?;
+                let diagonal = dir.x != 0 && dir.y != 0;
+                // Ents only interact with what is straight next to them
+                if diagonal && closest.distance == 0 {
+                    return None;
+                }
+                let distance = closest.distance
+                    + step_cost(
+                        tile_map.is_road(from + dir),
+                        diagonal,
+                        tile_map.terrain().get(from + dir),
+                    );
+                Some((dir, distance, closest.ways))
             })
-            .ok()
-            .copied()?;
-        Some(Direction {
-            dir,
-            distance: self.closest[&(from + dir)].distance + 1,
-        })
+            .collect();
+        let closest_distance = options.iter().map(|&(_, distance, _)| distance).min()?;
+        let &(dir, distance, _) = options
+            .choose_weighted(&mut thread_rng(), |&(_, distance, ways)| {
+                if distance == closest_distance {
+                    ways
+                } else {
+                    0.0
+                }
+            })
+            .ok()?;
+        Some((
+            Direction {
+                dir,
+                distance: self.closest[&(from + dir)].distance + 1,
+            },
+            distance,
+        ))
     }
-    pub fn pathfind(&self, ents: &Ents, from: IVec2) -> Option<Direction> {
-        self.pathfind_using_direction(
+
+    /// Diagonal steps are only taken if `diagonal` is set.
+    ///
+    /// Sidesteps to a tile with room if that's just as short, otherwise the step may lead onto a full tile,
+    /// see [Ents::has_room].
+    pub fn pathfind(
+        &self,
+        ents: &Ents,
+        tile_map: &TileMap,
+        from: IVec2,
+        diagonal: bool,
+    ) -> Option<Direction> {
+        let directions = MOVE_DIRECTIONS
+            .into_iter()
+            .chain(DIAGONAL_DIRECTIONS.into_iter().filter(|_| diagonal))
+            .filter(|&dir| !cuts_corner(from, dir, |pos| tile_map.is_blocking(pos)));
+        let (best, distance) = self.pathfind_using_direction(from, tile_map, directions.clone())?;
+        let with_room = self.pathfind_using_direction(
             from,
-            MOVE_DIRECTIONS
-                .into_iter()
-                .filter(|&dir| !ents.contains(from + dir)),
-        )
-        .or_else(|| self.pathfind_using_direction(from, MOVE_DIRECTIONS))
+            tile_map,
+            directions.filter(|&dir| ents.has_room(from + dir, tile_map.is_road(from + dir))),
+        );
+        Some(match with_room {
+            Some((sidestep, sidestep_distance)) if sidestep_distance <= distance => sidestep,
+            _ => best,
+        })
     }
 }
 
+/// Tiles are updated closest first, so each one is usually done once its neighbours are settled
 #[derive(PartialEq, Eq)]
 struct Update {
     distance: u64,
@@ -143,97 +551,226 @@ impl Ord for Update {
     }
 }
 
+/// Blocking tiles come from the [TileMap], targets that stop being one without going away
+/// don't show up there
 fn detect_map_updates<T: Component>(
     mut data: ResMut<Pathfinding<T>>,
+    tile_map: Res<TileMap>,
     map_updates: Query<
-        (Entity, &Pos, Option<&Size>),
+        (Entity, &Pos, Option<&Size>, Option<&Shape>),
         (
-            Or<(Changed<Pos>, Changed<Size>, Added<T>, Changed<Blocking>)>,
-            Or<(With<T>, With<Blocking>)>,
+            Or<(Changed<Pos>, Changed<Size>, Changed<Shape>, Added<T>)>,
+            With<T>,
         ),
     >,
-    mut removed: RemovedComponents<T>,
-    mut removed_blocking: RemovedComponents<Blocking>,
-    mut prev: Local<HashMap<Entity, (IVec2, IVec2)>>,
+    removed: Res<Removed<T>>,
+    mut prev: Local<HashMap<Entity, Vec<IVec2>>>,
 ) {
-    let mut update_at = |pos: IVec2, size: IVec2| {
-        for x in 0..size.x {
-            for y in 0..size.y {
-                data.updates.push(Update {
-                    distance: 0,
-                    pos: pos + IVec2::new(x, y),
-                });
-            }
-        }
-    };
-    for entity in removed.read().chain(removed_blocking.read()) {
-        if let Some((prev_pos, prev_size)) = prev.remove(&entity) {
-            update_at(prev_pos, prev_size);
+    for &pos in tile_map.changed_tiles() {
+        data.update_at(pos);
+    }
+    for entity in removed.iter() {
+        for pos in prev.remove(&entity).into_iter().flatten() {
+            data.remove_target(pos);
         }
     }
-    for (entity, pos, size) in map_updates.iter() {
-        if let Some(&(prev_pos, prev_size)) = prev.get(&entity) {
-            update_at(prev_pos, prev_size);
+    for (entity, pos, size, shape) in map_updates.iter() {
+        for pos in prev.remove(&entity).into_iter().flatten() {
+            data.remove_target(pos);
+        }
+        let tiles: Vec<_> = footprint(pos.0, size, shape).collect();
+        for &pos in &tiles {
+            data.add_target(pos);
         }
-        let size = size.map_or(IVec2::splat(1), |size| size.0);
-        update_at(pos.0, size);
-        prev.insert(entity, (pos.0, size));
+        prev.insert(entity, tiles);
     }
 }
 
 #[derive(Component)]
-struct DebugThing(f32);
+pub struct Blocking;
 
-fn despawn_debug(mut q: Query<(Entity, &mut DebugThing)>, mut commands: Commands, time: Res<Time>) {
-    for (entity, mut debug) in q.iter_mut() {
-        debug.0 += time.delta_seconds();
-        if debug.0 > 1.0 {
-            commands.entity(entity).despawn();
-        }
-    }
+/// Blocking and road tiles and the terrain as flow fields see them, shared with running recomputes
+#[derive(Resource, Default)]
+pub struct MapSnapshot(Arc<MapTiles>);
+
+#[derive(Default, Clone)]
+struct MapTiles {
+    blocking: HashSet<IVec2>,
+    roads: HashSet<IVec2>,
+    terrain: TerrainMap,
+    generated_chunks: GeneratedChunks,
 }
 
-#[derive(Component)]
-pub struct Blocking;
+impl MapTiles {
+    fn is_blocking(&self, pos: IVec2) -> bool {
+        self.blocking.contains(&pos) || !self.terrain.get(pos).is_walkable()
+    }
+}
 
-fn pathfind_iteration<T: Component>(
-    searching_for: Query<(), With<T>>,
-    roads: Query<()>,
-    blocking: Query<(), With<Blocking>>,
-    tile_map: Res<TileMap>,
-    mut data: ResMut<Pathfinding<T>>,
+/// Only the tiles the [TileMap] reports as changed are updated.
+///
+/// Finished recomputes are collected before this runs, so the snapshot is only copied while
+/// one is still running.
+pub fn update_map_snapshot(
     generated_chunks: Res<GeneratedChunks>,
-    mut commands: Commands,
+    tile_map: Res<TileMap>,
+    mut snapshot: ResMut<MapSnapshot>,
 ) {
-    let mut iterations_left = 1000; // TODO base on time?
-    while let Some(update) = data.updates.pop() {
-        let new_closest = if tile_map
-            .entities_at(update.pos)
-            .any(|entity| searching_for.contains(entity))
-        {
+    let terrain_changed = snapshot.0.terrain.revision() != tile_map.terrain().revision();
+    if tile_map.changed_tiles().is_empty() && !generated_chunks.is_changed() && !terrain_changed {
+        return;
+    }
+    let map = Arc::make_mut(&mut snapshot.0);
+    for &pos in tile_map.changed_tiles() {
+        for (tiles, flag) in [
+            (&mut map.blocking, TileFlag::Blocking),
+            (&mut map.roads, TileFlag::Road),
+        ] {
+            if tile_map.has(pos, flag) {
+                tiles.insert(pos);
+            } else {
+                tiles.remove(&pos);
+            }
+        }
+    }
+    if terrain_changed {
+        map.terrain = tile_map.terrain().clone();
+    }
+    if generated_chunks.is_changed() {
+        map.generated_chunks = generated_chunks.clone();
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+type RecomputeTask = Task<Batch>;
+/// No threads on the web, the batch is done right away
+#[cfg(target_arch = "wasm32")]
+type RecomputeTask = Batch;
+
+#[cfg(not(target_arch = "wasm32"))]
+fn start_recompute(recompute: Recompute) -> RecomputeTask {
+    AsyncComputeTaskPool::get().spawn(async move { recompute.run() })
+}
+
+#[cfg(target_arch = "wasm32")]
+fn start_recompute(recompute: Recompute) -> RecomputeTask {
+    recompute.run()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn is_finished(task: &RecomputeTask) -> bool {
+    task.is_finished()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn is_finished(_task: &RecomputeTask) -> bool {
+    true
+}
+
+/// Only waits if the recompute is still running and [PathfindingBudget::wait_for_recomputes] is set
+#[cfg(not(target_arch = "wasm32"))]
+fn finish_recompute(task: RecomputeTask) -> Batch {
+    block_on(task)
+}
+
+#[cfg(target_arch = "wasm32")]
+fn finish_recompute(task: RecomputeTask) -> Batch {
+    task
+}
+
+/// Everything a recompute needs, so it doesn't touch the world while running
+struct Recompute {
+    field: Field,
+    target_changes: Vec<(IVec2, bool)>,
+    updates: BinaryHeap<Update>,
+    changed: Vec<IVec2>,
+    map: Arc<MapTiles>,
+    occupied_regions: HashSet<IVec2>,
+    budget: RecomputeBudget,
+}
+
+struct Batch {
+    field: Field,
+    /// Left over when the budget was used up
+    updates: BinaryHeap<Update>,
+    updates_done: usize,
+    elapsed: Duration,
+}
+
+impl Recompute {
+    fn run(mut self) -> Batch {
+        let started = Instant::now();
+        let has_budget = |updates_done| {
+            updates_done < self.budget.updates && started.elapsed() < self.budget.time
+        };
+        // Crabs keep following the published field meanwhile
+        let mut field = std::mem::take(&mut self.field);
+        field.catch_up(std::mem::take(&mut self.target_changes));
+        let mut updates = std::mem::take(&mut self.updates);
+        let mut updates_done = 0;
+        // Changes near crabs get a first look, so crabs don't wait for the whole field to settle.
+        // They stay queued for their turn, which then finds their neighbours settled.
+        let mut near_ents = std::mem::take(&mut self.changed);
+        near_ents.retain(|&pos| is_near(pos, |region| self.occupied_regions.contains(&region)));
+        near_ents.sort_by_key(|pos| (pos.x, pos.y));
+        near_ents.dedup();
+        for pos in near_ents.into_iter().take(self.budget.updates / 2) {
+            if !has_budget(updates_done) {
+                break;
+            }
+            self.update(&mut field, &mut updates, Update { distance: 0, pos });
+            updates_done += 1;
+        }
+        while has_budget(updates_done) {
+            let Some(update) = updates.pop() else {
+                break;
+            };
+            self.update(&mut field, &mut updates, update);
+            updates_done += 1;
+        }
+        Batch {
+            field,
+            updates,
+            updates_done,
+            elapsed: started.elapsed(),
+        }
+    }
+
+    /// Takes the best way through a neighbour, neighbours are queued again if that changed
+    fn update(
+        &self,
+        field: &mut Field,
+        updates: &mut BinaryHeap<Update>,
+        Update { distance: key, pos }: Update,
+    ) {
+        let new_closest = if field.targets.contains(&pos) {
             Some(Closest {
                 distance: 0,
                 ways: 1.0,
             })
-        } else if tile_map
-            .entities_at(update.pos)
-            .any(|entity| blocking.contains(entity))
-        {
+        } else if self.map.is_blocking(pos) {
             None
         } else {
-            let mut closest = None;
-            for dir in MOVE_DIRECTIONS {
-                let next_pos = update.pos + dir;
-                let w = if tile_map
-                    .entities_at(next_pos)
-                    .any(|entity| roads.get(entity).is_ok())
+            let mut new_closest = None;
+            // Diagonal paths are there for ents that can take them, the rest still find a
+            // straight neighbour closer to the target on every tile
+            for dir in MOVE_DIRECTIONS.into_iter().chain(DIAGONAL_DIRECTIONS) {
+                let next_pos = pos + dir;
+                let diagonal = dir.x != 0 && dir.y != 0;
+                if cuts_corner(pos, dir, |pos| self.map.is_blocking(pos)) {
+                    continue;
+                }
+                let w = step_cost(
+                    self.map.roads.contains(&next_pos),
+                    diagonal,
+                    self.map.terrain.get(next_pos),
+                );
+                if let Some(next_closest) = field
+                    .closest
+                    .get(&next_pos)
+                    .filter(|next_closest| !diagonal || next_closest.distance != 0)
                 {
-                    1
-                } else {
-                    2
-                };
-                if let Some(next_closest) = data.closest.get(&next_pos) {
-                    let do_replace = match &mut closest {
+                    let do_replace = match &mut new_closest {
                         Some(Closest { distance, ways }) => {
                             if *distance == next_closest.distance + w {
                                 *ways = (*ways + next_closest.ways).min(1e5);
@@ -245,62 +782,396 @@ fn pathfind_iteration<T: Component>(
                         None => true,
                     };
                     if do_replace {
-                        closest = Some(Closest {
+                        new_closest = Some(Closest {
                             distance: next_closest.distance + w,
                             ways: next_closest.ways,
                         });
                     };
                 }
             }
-            if let Some(c) = &mut closest {
-                // TODO ??
-                if c.distance > 1000 {
-                    closest = None;
+            new_closest.filter(|closest| closest.distance <= MAX_PATH_DISTANCE)
+        };
+
+        let old = field.closest.get(&pos).copied();
+        if old == new_closest {
+            return;
+        }
+        // Only neighbours farther away can go through the tile
+        let (distance, raised) = match (old, new_closest) {
+            // Closer ways are only taken once everything closer is settled, until then the way
+            // may go through tiles that are about to lose theirs
+            (_, Some(new))
+                if (new.distance as u64) > key
+                    && old.is_none_or(|old| new.distance <= old.distance) =>
+            {
+                updates.push(Update {
+                    distance: new.distance as u64,
+                    pos,
+                });
+                return;
+            }
+            (Some(old), Some(new)) if new.distance <= old.distance => {
+                field.set(pos, Some(new));
+                (new.distance, false)
+            }
+            (None, Some(new)) => {
+                field.set(pos, Some(new));
+                (new.distance, false)
+            }
+            // Farther than it was, neighbours that went through it are cleared first so they
+            // don't count up from each other, the tile gets its new way back after them
+            (Some(old), new) => {
+                field.set(pos, None);
+                if let Some(new) = new {
+                    updates.push(Update {
+                        distance: new.distance as u64,
+                        pos,
+                    });
                 }
+                (old.distance, true)
             }
-            closest
+            (None, None) => unreachable!(),
         };
+        for dir in MOVE_DIRECTIONS.into_iter().chain(DIAGONAL_DIRECTIONS) {
+            let next_pos = pos + dir;
+            let farther = field
+                .closest
+                .get(&next_pos)
+                .map_or(!raised, |next| next.distance > distance);
+            if farther && self.map.generated_chunks.is_generated(next_pos) {
+                updates.push(Update {
+                    distance: distance as u64,
+                    pos: next_pos,
+                });
+            }
+        }
+    }
+}
 
-        let old = data.closest.get(&update.pos);
-        if old != new_closest.as_ref() {
-            if false {
-                commands.spawn((
-                    Text2dBundle {
-                        text: Text::from_section(
-                            new_closest
-                                .as_ref()
-                                .map_or("none".to_owned(), |closest| closest.distance.to_string()),
-                            default(),
-                        ),
-                        transform: Transform::from_translation(update.pos.as_vec2().extend(1.0))
-                            .with_scale(Vec3::splat(0.1)),
-                        ..default()
-                    },
-                    DebugThing(0.0),
-                ));
+/// Publishes the running recompute once it is done, before the map snapshot it used changes.
+/// Crabs keep following the field published before until then.
+fn collect_recompute<T: Component>(
+    mut data: ResMut<Pathfinding<T>>,
+    budget: Res<PathfindingBudget>,
+    mut metrics: ResMut<PathfindingMetrics>,
+) {
+    let data = &mut *data;
+    if !data
+        .task
+        .as_ref()
+        .is_some_and(|task| budget.wait_for_recomputes || is_finished(task))
+    {
+        return;
+    }
+    if let Some(task) = data.task.take() {
+        let batch = finish_recompute(task);
+        let Field {
+            closest,
+            targets,
+            changes,
+        } = batch.field;
+        let published = std::mem::replace(&mut data.closest, Arc::new(closest));
+        // Crabs only borrow the published map, so nothing else holds on to it
+        data.field = Some(Field {
+            closest: Arc::try_unwrap(published).unwrap_or_else(|published| (*published).clone()),
+            targets,
+            changes,
+        });
+        data.updates.extend(batch.updates);
+        let metrics = metrics
+            .fields
+            .entry(std::any::type_name::<T>())
+            .or_default();
+        metrics.updates_last_batch = batch.updates_done;
+        metrics.time_last_batch = batch.elapsed;
+    }
+}
+
+/// Starts the next recompute if there is anything left to update
+fn pathfind_iteration<T: Component>(
+    snapshot: Res<MapSnapshot>,
+    mut data: ResMut<Pathfinding<T>>,
+    ents: Res<Ents>,
+    mut budget: ResMut<PathfindingBudget>,
+    mut metrics: ResMut<PathfindingMetrics>,
+) {
+    let data = &mut *data;
+    let metrics = metrics
+        .fields
+        .entry(std::any::type_name::<T>())
+        .or_default();
+    metrics.queue_len = data.updates.len();
+    if data.task.is_some() {
+        // Whatever it doesn't get to comes back with it
+        *metrics.busy_for.get_or_insert(0) += 1;
+        return;
+    }
+    if data.updates.is_empty() {
+        if let Some(busy_for) = metrics.busy_for.take() {
+            metrics.last_convergence = Some(busy_for);
+        }
+        return;
+    }
+    *metrics.busy_for.get_or_insert(0) += 1;
+    let Some(field_budget) = budget.take_for_field() else {
+        return;
+    };
+    let Some(field) = data.field.take() else {
+        return;
+    };
+    let task = start_recompute(Recompute {
+        field,
+        target_changes: std::mem::take(&mut data.target_changes),
+        updates: std::mem::take(&mut data.updates),
+        changed: std::mem::take(&mut data.changed),
+        map: snapshot.0.clone(),
+        occupied_regions: ents.occupied_regions(),
+        budget: field_budget,
+    });
+    #[cfg(target_arch = "wasm32")]
+    budget.spend(&task);
+    data.task = Some(task);
+}
+
+/// What [PathQuery] searches a path to
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PathTarget {
+    /// Any tile of the entity's footprint
+    Entity(Entity),
+    /// Any of the tiles
+    Tiles(Vec<IVec2>),
+}
+
+/// Paths to one specific target, for when the closest target of a kind is not the one we want
+#[derive(SystemParam)]
+pub struct PathQuery<'w, 's> {
+    tile_map: Res<'w, TileMap>,
+    footprints: Query<'w, 's, (&'static Pos, Option<&'static Size>, Option<&'static Shape>)>,
+    generated_chunks: Res<'w, GeneratedChunks>,
+    cache: ResMut<'w, PathCache>,
+}
+
+/// Next steps of paths found so far, every tile of a found path knows where to go next
+#[derive(Resource, Default)]
+pub struct PathCache {
+    targets: HashMap<PathTarget, CachedTarget>,
+    cached_tiles: usize,
+}
+
+struct CachedTarget {
+    goals: Vec<IVec2>,
+    next_steps: HashMap<IVec2, Direction>,
+    unreachable_from: HashSet<IVec2>,
+}
+
+impl PathCache {
+    fn clear(&mut self) {
+        self.targets.clear();
+        self.cached_tiles = 0;
+    }
+}
+
+impl PathQuery<'_, '_> {
+    /// Next step towards the target, same as [Pathfinding::pathfind] does for the closest target.
+    ///
+    /// Distance is 1 when standing next to the target, `None` if it can't be reached.
+    pub fn pathfind(&mut self, from: IVec2, target: &PathTarget) -> Option<Direction> {
+        let goals = self.goals(target)?;
+        if goals.contains(&from) {
+            return None;
+        }
+        if self.cache.cached_tiles > MAX_CACHED_TILES {
+            self.cache.clear();
+        }
+        let cached = self
+            .cache
+            .targets
+            .entry(target.clone())
+            .or_insert_with(|| CachedTarget {
+                goals: goals.clone(),
+                next_steps: default(),
+                unreachable_from: default(),
+            });
+        if cached.goals != goals {
+            // Target moved
+            *cached = CachedTarget {
+                goals: goals.clone(),
+                next_steps: default(),
+                unreachable_from: default(),
+            };
+        }
+        if let Some(&direction) = cached.next_steps.get(&from) {
+            return Some(direction);
+        }
+        if cached.unreachable_from.contains(&from) {
+            return None;
+        }
+
+        let found = self.search(from, &goals);
+        let cache = &mut *self.cache;
+        let cached = cache.targets.get_mut(target).unwrap();
+        let Some(path) = found else {
+            cached.unreachable_from.insert(from);
+            cache.cached_tiles += 1;
+            return None;
+        };
+        for step in path.windows(2) {
+            let (pos, next, distance) = (step[0].0, step[1].0, step[1].1);
+            cached.next_steps.insert(
+                pos,
+                Direction {
+                    dir: next - pos,
+                    distance: distance + 1,
+                },
+            );
+        }
+        cache.cached_tiles += path.len();
+        cached.next_steps.get(&from).copied()
+    }
+
+    /// Sidesteps to a tile with room if that still gets closer, otherwise the same as
+    /// [PathQuery::pathfind]
+    pub fn pathfind_with_room(
+        &mut self,
+        ents: &Ents,
+        from: IVec2,
+        target: &PathTarget,
+    ) -> Option<Direction> {
+        let best = self.pathfind(from, target)?;
+        let tile_map = Res::clone(&self.tile_map);
+        let has_room =
+            |pos: IVec2| !tile_map.is_blocking(pos) && ents.has_room(pos, tile_map.is_road(pos));
+        if has_room(from + best.dir) {
+            return Some(best);
+        }
+        for dir in MOVE_DIRECTIONS {
+            if dir == best.dir || !has_room(from + dir) {
+                continue;
             }
-            match new_closest {
-                Some(new) => {
-                    data.closest.insert(update.pos, new);
+            match self.pathfind(from + dir, target) {
+                Some(sidestep) if sidestep.distance < best.distance => {
+                    return Some(Direction {
+                        dir,
+                        distance: sidestep.distance + 1,
+                    });
                 }
-                None => {
-                    data.closest.remove(&update.pos);
+                _ => {}
+            }
+        }
+        Some(best)
+    }
+
+    /// Every tile to walk through, not including `from`, the last one is a tile of the target
+    pub fn path(&mut self, mut from: IVec2, target: &PathTarget) -> Option<Vec<IVec2>> {
+        let mut path = Vec::new();
+        while let Some(direction) = self.pathfind(from, target) {
+            from += direction.dir;
+            path.push(from);
+            if direction.distance == 1 {
+                return Some(path);
+            }
+        }
+        None
+    }
+
+    fn goals(&self, target: &PathTarget) -> Option<Vec<IVec2>> {
+        match target {
+            PathTarget::Entity(entity) => {
+                let (pos, size, shape) = self.footprints.get(*entity).ok()?;
+                Some(footprint(pos.0, size, shape).collect())
+            }
+            PathTarget::Tiles(tiles) => Some(tiles.clone()),
+        }
+    }
+
+    /// Goals can be entered even if they are blocking
+    fn step_cost(&self, pos: IVec2, goals: &[IVec2]) -> Option<u32> {
+        if !self.generated_chunks.is_generated(pos) {
+            return None;
+        }
+        if self.tile_map.is_blocking(pos) && !goals.contains(&pos) {
+            return None;
+        }
+        Some(step_cost(
+            self.tile_map.is_road(pos),
+            false,
+            self.tile_map.terrain().get(pos),
+        ))
+    }
+
+    /// A* from `from` to the closest goal, returns tiles of the path with their distance to the goal
+    fn search(&self, from: IVec2, goals: &[IVec2]) -> Option<Vec<(IVec2, u32)>> {
+        let heuristic = |pos: IVec2| {
+            goals
+                .iter()
+                .map(|&goal| {
+                    let d = (goal - pos).abs();
+                    (d.x + d.y) as u32 * ROAD_STEP
+                })
+                .min()
+                .unwrap_or(0)
+        };
+        let mut came_from = HashMap::<IVec2, IVec2>::new();
+        let mut distance = HashMap::<IVec2, u32>::new();
+        let mut queue = BinaryHeap::new();
+        distance.insert(from, 0);
+        queue.push(Update {
+            distance: heuristic(from) as u64,
+            pos: from,
+        });
+        let mut searched = 0;
+        while let Some(Update { pos, .. }) = queue.pop() {
+            if goals.contains(&pos) {
+                let total = distance[&pos];
+                let mut path = vec![(pos, 0)];
+                let mut pos = pos;
+                while let Some(&prev) = came_from.get(&pos) {
+                    path.push((prev, total - distance[&prev]));
+                    pos = prev;
                 }
+                path.reverse();
+                return Some(path);
+            }
+            searched += 1;
+            if searched > MAX_SEARCHED_TILES {
+                return None;
             }
+            let pos_distance = distance[&pos];
             for dir in MOVE_DIRECTIONS {
-                let next_pos = update.pos + dir;
-                if generated_chunks.is_generated(next_pos) {
-                    data.updates.push(Update {
-                        distance: update.distance + 1,
-                        pos: next_pos,
-                    });
+                let next = pos + dir;
+                let Some(cost) = self.step_cost(next, goals) else {
+                    continue;
+                };
+                let next_distance = pos_distance + cost;
+                if next_distance > MAX_PATH_DISTANCE
+                    || distance.get(&next).is_some_and(|&d| d <= next_distance)
+                {
+                    continue;
                 }
+                distance.insert(next, next_distance);
+                came_from.insert(next, pos);
+                queue.push(Update {
+                    distance: (next_distance + heuristic(next)) as u64,
+                    pos: next,
+                });
             }
         }
+        None
+    }
+}
 
-        iterations_left -= 1;
-        if iterations_left == 0 {
-            break;
-        }
+/// Paths go around buildings and water and prefer roads, so cached ones are stale once those change
+fn invalidate_path_cache(
+    tile_map: Res<TileMap>,
+    mut terrain_revision: Local<u64>,
+    mut cache: ResMut<PathCache>,
+) {
+    if !tile_map.changed_tiles().is_empty() || *terrain_revision != tile_map.terrain().revision() {
+        *terrain_revision = tile_map.terrain().revision();
+        cache.clear();
     }
 }
+
+fn clear_path_cache(mut cache: ResMut<PathCache>) {
+    cache.clear();
+}
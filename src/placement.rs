@@ -0,0 +1,245 @@
+//! Where ents can be placed.
+//!
+//! Everything has to fit on free tiles off water and reach a road, defs add their own
+//! [PlacementRule]s on top.
+//! Every rule a spot breaks gives a [Rejection], the placing preview shows them in the tooltip.
+
+use std::fmt;
+
+use bevy::{ecs::system::SystemParam, prelude::*, utils::HashSet};
+use serde::Deserialize;
+
+use crate::{
+    ent_defs::{EntDef, EntDefs},
+    game::{EntType, Placeholder, PlacementPreview},
+    resource_kind::Resources,
+    terrain::Terrain,
+    tile_map::{footprint, Pos, Rotation, Shape, Size, TileFlag, TileMap},
+};
+
+/// Extra condition a kind of ent sets on where it goes, in `ents.defs.ron`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum PlacementRule {
+    /// At most this many, construction sites included
+    MaxCount(usize),
+    /// At most this many tiles between it and one of the ent type
+    Near(EntType, i32),
+    /// At least this many tiles between it and every one of the ent type
+    AwayFrom(EntType, i32),
+    /// Every tile of the footprint is on the terrain
+    OnlyOn(Terrain),
+    /// No tile of the footprint is on the terrain
+    NotOn(Terrain),
+}
+
+/// Why an ent can't go somewhere
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// Not defined or without a cost
+    NotBuildable,
+    /// Moving the building costs more than there is, counting its refund
+    NotEnoughMoney(Resources),
+    Blocked,
+    OnRoad,
+    NoRoad,
+    NoEntrance,
+    TooMany(usize),
+    NotNear(EntType, i32),
+    TooCloseTo(EntType, i32),
+    On(Terrain),
+    NotOn(Terrain),
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NotBuildable => write!(f, "Can't be built"),
+            Self::NotEnoughMoney(cost) => write!(f, "Not enough money, costs {cost}"),
+            Self::Blocked => write!(f, "Something is in the way"),
+            Self::OnRoad => write!(f, "Can't go on a road"),
+            Self::NoRoad => write!(f, "Needs a road next to it"),
+            Self::NoEntrance => write!(f, "Needs a road at its entrance"),
+            Self::TooMany(max) => write!(f, "Only {max} can be built"),
+            Self::NotNear(ent_type, distance) => {
+                write!(
+                    f,
+                    "Has to be within {distance} tiles of a {}",
+                    ent_type.name()
+                )
+            }
+            Self::TooCloseTo(ent_type, distance) => {
+                write!(
+                    f,
+                    "Has to be {distance} tiles away from a {}",
+                    ent_type.name()
+                )
+            }
+            Self::On(terrain) => write!(f, "Can't be built on {}", terrain.name()),
+            Self::NotOn(terrain) => write!(f, "Has to be built on {}", terrain.name()),
+        }
+    }
+}
+
+/// Free tiles between two footprints, 0 when they touch
+fn distance(a: &[IVec2], b: impl Iterator<Item = IVec2>) -> i32 {
+    b.flat_map(|b| a.iter().map(move |&a| (a - b).abs().max_element() - 1))
+        .min()
+        .map_or(i32::MAX, |gap| gap.max(0))
+}
+
+impl PlacementRule {
+    /// `tiles` and `terrain` are the footprint and what is under it,
+    /// `placed` are all the other ents with their footprint
+    fn check<T: Iterator<Item = IVec2>>(
+        self,
+        ent_type: EntType,
+        tiles: &[IVec2],
+        terrain: &HashSet<Terrain>,
+        mut placed: impl Iterator<Item = (EntType, T)>,
+    ) -> Option<Rejection> {
+        match self {
+            Self::MaxCount(max) => (placed.filter(|&(other, _)| other == ent_type).count() >= max)
+                .then_some(Rejection::TooMany(max)),
+            Self::Near(near, max) => (!placed
+                .any(|(other, other_tiles)| other == near && distance(tiles, other_tiles) <= max))
+            .then_some(Rejection::NotNear(near, max)),
+            Self::AwayFrom(away_from, min) => placed
+                .any(|(other, other_tiles)| {
+                    other == away_from && distance(tiles, other_tiles) < min
+                })
+                .then_some(Rejection::TooCloseTo(away_from, min)),
+            Self::OnlyOn(only_on) => terrain
+                .iter()
+                .any(|&other| other != only_on)
+                .then_some(Rejection::NotOn(only_on)),
+            Self::NotOn(not_on) => terrain.contains(&not_on).then_some(Rejection::On(not_on)),
+        }
+    }
+}
+
+/// Checks spots for ents against the map and the def's rules
+#[derive(SystemParam)]
+pub struct Placement<'w, 's> {
+    tile_map: Res<'w, TileMap>,
+    defs: Res<'w, EntDefs>,
+    placed: Query<
+        'w,
+        's,
+        (
+            Entity,
+            AnyOf<(&'static EntType, &'static Placeholder)>,
+            &'static Pos,
+            &'static Size,
+            Option<&'static Shape>,
+        ),
+        Without<PlacementPreview>,
+    >,
+}
+
+impl Placement<'_, '_> {
+    pub fn tile_map(&self) -> &TileMap {
+        &self.tile_map
+    }
+
+    /// Everything wrong with putting the ent at `pos`, empty if it can go there.
+    /// The `moving` building is not counted as being in the way of itself.
+    pub fn check(
+        &self,
+        ent_type: EntType,
+        pos: IVec2,
+        rotation: Rotation,
+        moving: Option<Entity>,
+    ) -> Vec<Rejection> {
+        let Some(def) = self.defs.get(ent_type).filter(|def| def.cost.is_some()) else {
+            return vec![Rejection::NotBuildable];
+        };
+        let mut rejections = check_tiles(&self.tile_map, def, ent_type, pos, rotation, moving);
+        let size = Size(rotation.size(def.size()));
+        let tiles: Vec<_> = footprint(pos, Some(&size), def.shape(rotation).as_ref()).collect();
+        let terrain = tiles
+            .iter()
+            .map(|&tile| self.tile_map.terrain().get(tile))
+            .collect();
+        let placed = || {
+            self.placed
+                .iter()
+                .filter(move |&(entity, ..)| Some(entity) != moving)
+                .map(|(_, (ent_type, placeholder), pos, size, shape)| {
+                    let ent_type = ent_type
+                        .copied()
+                        .or(placeholder.map(|placeholder| placeholder.0));
+                    (ent_type.unwrap(), footprint(pos.0, Some(size), shape))
+                })
+        };
+        rejections.extend(
+            def.rules
+                .iter()
+                .filter_map(|rule| rule.check(ent_type, &tiles, &terrain, placed())),
+        );
+        rejections
+    }
+}
+
+/// Roads go next to roads. Buildings need a free tile around them and a road next to them,
+/// or on every one of their entrances if they have any. The `moving` building is left out.
+fn check_tiles(
+    tile_map: &TileMap,
+    def: &EntDef,
+    ent_type: EntType,
+    pos: IVec2,
+    rotation: Rotation,
+    moving: Option<Entity>,
+) -> Vec<Rejection> {
+    let size = Size(rotation.size(def.size()));
+    let tiles: HashSet<IVec2> = footprint(pos, Some(&size), def.shape(rotation).as_ref()).collect();
+    let around: HashSet<IVec2> = tiles
+        .iter()
+        .flat_map(|&tile| {
+            (-1..=1).flat_map(move |x| (-1..=1).map(move |y| tile + IVec2::new(x, y)))
+        })
+        .filter(|tile| !tiles.contains(tile))
+        .collect();
+
+    // Water next to buildings is fine, crabs just can't walk around them there
+    let has = |cell, flag| tile_map.has_other_than(cell, flag, moving);
+    let is_blocking = |cell| has(cell, TileFlag::Blocking) || has(cell, TileFlag::PlannedBlocking);
+
+    let is_road = |cell| has(cell, TileFlag::Road) || has(cell, TileFlag::PlannedRoad);
+
+    let mut rejections = Vec::new();
+    let mut reject = |rejected, rejection| {
+        if rejected {
+            rejections.push(rejection);
+        }
+    };
+    reject(
+        tiles
+            .iter()
+            .any(|&tile| !tile_map.terrain().get(tile).is_walkable()),
+        Rejection::On(Terrain::Water),
+    );
+    match ent_type {
+        EntType::ROAD => {
+            reject(tiles.iter().copied().any(is_blocking), Rejection::Blocked);
+            reject(tiles.iter().copied().any(is_road), Rejection::OnRoad);
+            reject(!around.iter().copied().any(is_road), Rejection::NoRoad);
+        }
+        _ => {
+            reject(tiles.iter().copied().any(is_road), Rejection::OnRoad);
+            reject(
+                tiles.iter().chain(&around).copied().any(is_blocking),
+                Rejection::Blocked,
+            );
+            let mut entrances = def
+                .entrances(rotation)
+                .map(|entrance| pos + entrance)
+                .peekable();
+            if entrances.peek().is_some() {
+                reject(!entrances.all(is_road), Rejection::NoEntrance);
+            } else {
+                reject(!around.iter().copied().any(is_road), Rejection::NoRoad);
+            }
+        }
+    }
+    rejections
+}
@@ -0,0 +1,55 @@
+//! Component removals for the simulation.
+//!
+//! Bevy only reports removals for two frames and at high frame rates there are more frames
+//! than that between ticks, so every frame they are kept until the next tick reads them.
+
+use std::marker::PhantomData;
+
+use bevy::prelude::*;
+
+pub trait AppExt {
+    /// Makes [Removed<C>] available to systems running during ticks
+    fn keep_removed<C: Component>(&mut self) -> &mut Self;
+}
+
+impl AppExt for App {
+    fn keep_removed<C: Component>(&mut self) -> &mut Self {
+        if self.world.contains_resource::<Removed<C>>() {
+            return self;
+        }
+        self.insert_resource(Removed::<C> {
+            pending: default(),
+            tick: default(),
+            phantom_data: PhantomData,
+        });
+        self.add_systems(Last, keep_removed::<C>);
+        self.add_systems(FixedUpdate, start_tick::<C>.in_set(RemovedSet));
+        self
+    }
+}
+
+/// Hands the removals kept since the last tick over to this one, runs first every tick
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RemovedSet;
+
+/// Entities that lost `C` since the last tick
+#[derive(Resource)]
+pub struct Removed<C> {
+    pending: Vec<Entity>,
+    tick: Vec<Entity>,
+    phantom_data: PhantomData<C>,
+}
+
+impl<C> Removed<C> {
+    pub fn iter(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.tick.iter().copied()
+    }
+}
+
+fn keep_removed<C: Component>(mut removed: RemovedComponents<C>, mut res: ResMut<Removed<C>>) {
+    res.pending.extend(removed.read());
+}
+
+fn start_tick<C: Component>(mut res: ResMut<Removed<C>>) {
+    res.tick = std::mem::take(&mut res.pending);
+}
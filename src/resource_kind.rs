@@ -0,0 +1,179 @@
+use std::{
+    fmt,
+    ops::{Add, AddAssign, Index, IndexMut, Mul, Sub, SubAssign},
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Hash, Serialize, Deserialize)]
+pub enum ResourceKind {
+    Wood,
+    Stone,
+    Gold,
+}
+
+impl ResourceKind {
+    pub const ALL: [Self; 3] = [Self::Wood, Self::Stone, Self::Gold];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Wood => "wood",
+            Self::Stone => "stone",
+            Self::Gold => "gold",
+        }
+    }
+}
+
+/// Type level [ResourceKind], used where every kind needs its own components and systems
+/// (like a separate pathfinding field)
+pub trait Kind: Send + Sync + 'static {
+    const KIND: ResourceKind;
+}
+
+pub struct Wood;
+
+impl Kind for Wood {
+    const KIND: ResourceKind = ResourceKind::Wood;
+}
+
+pub struct Stone;
+
+impl Kind for Stone {
+    const KIND: ResourceKind = ResourceKind::Stone;
+}
+
+pub struct Gold;
+
+impl Kind for Gold {
+    const KIND: ResourceKind = ResourceKind::Gold;
+}
+
+/// Some amount of every kind of resource
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone, Serialize, Deserialize)]
+#[serde(from = "NamedResources", into = "NamedResources")]
+pub struct Resources([i32; 3]);
+
+/// How [Resources] look in files: `(wood: 10, stone: 5)`, missing kinds are zero
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct NamedResources {
+    wood: i32,
+    stone: i32,
+    gold: i32,
+}
+
+impl From<NamedResources> for Resources {
+    fn from(named: NamedResources) -> Self {
+        Self::new(named.wood, named.stone, named.gold)
+    }
+}
+
+impl From<Resources> for NamedResources {
+    fn from(resources: Resources) -> Self {
+        Self {
+            wood: resources[ResourceKind::Wood],
+            stone: resources[ResourceKind::Stone],
+            gold: resources[ResourceKind::Gold],
+        }
+    }
+}
+
+impl Resources {
+    pub const ZERO: Self = Self([0; 3]);
+
+    pub const fn new(wood: i32, stone: i32, gold: i32) -> Self {
+        Self([wood, stone, gold])
+    }
+
+    pub fn total(&self) -> i32 {
+        self.0.iter().sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|&amount| amount == 0)
+    }
+
+    /// Enough of every kind to pay for `cost`
+    pub fn covers(&self, cost: Self) -> bool {
+        ResourceKind::ALL
+            .into_iter()
+            .all(|kind| self[kind] >= cost[kind])
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ResourceKind, i32)> + '_ {
+        ResourceKind::ALL.into_iter().map(|kind| (kind, self[kind]))
+    }
+}
+
+impl Index<ResourceKind> for Resources {
+    type Output = i32;
+    fn index(&self, kind: ResourceKind) -> &i32 {
+        &self.0[kind as usize]
+    }
+}
+
+impl IndexMut<ResourceKind> for Resources {
+    fn index_mut(&mut self, kind: ResourceKind) -> &mut i32 {
+        &mut self.0[kind as usize]
+    }
+}
+
+impl Add for Resources {
+    type Output = Self;
+    fn add(mut self, rhs: Self) -> Self {
+        self += rhs;
+        self
+    }
+}
+
+impl AddAssign for Resources {
+    fn add_assign(&mut self, rhs: Self) {
+        for kind in ResourceKind::ALL {
+            self[kind] += rhs[kind];
+        }
+    }
+}
+
+impl Sub for Resources {
+    type Output = Self;
+    fn sub(mut self, rhs: Self) -> Self {
+        self -= rhs;
+        self
+    }
+}
+
+impl SubAssign for Resources {
+    fn sub_assign(&mut self, rhs: Self) {
+        for kind in ResourceKind::ALL {
+            self[kind] -= rhs[kind];
+        }
+    }
+}
+
+impl Mul<i32> for Resources {
+    type Output = Self;
+    fn mul(mut self, rhs: i32) -> Self {
+        for kind in ResourceKind::ALL {
+            self[kind] *= rhs;
+        }
+        self
+    }
+}
+
+/// `10 wood 5 stone`, kinds that are zero are skipped
+impl fmt::Display for Resources {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "0");
+        }
+        let mut first = true;
+        for (kind, amount) in self.iter().filter(|&(_, amount)| amount != 0) {
+            if !first {
+                write!(f, " ")?;
+            }
+            first = false;
+            write!(f, "{amount} {}", kind.name())?;
+        }
+        Ok(())
+    }
+}
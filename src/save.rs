@@ -0,0 +1,515 @@
+use bevy::{prelude::*, utils::HashMap};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    buildings::Buildings,
+    chunks::{GenerateChunk, GenerateTerrain, GeneratedChunks},
+    ent_defs::EntDefs,
+    game::{
+        self, BuildingUpgrade, BuildingUpgradeComponent, BuildingUpgradeToPerform, CanUpgrade,
+        EntType, Harvestable, Home, Inventory, Money, NeedsResource, Placeholder, Spawn, Storage,
+        StorageLevelChild, WorldSeed,
+    },
+    history::History,
+    jobs::JobPriorities,
+    pathfind::ResetPathfinding,
+    resource_kind::{ResourceKind, Resources},
+    tile_map::{Pos, Rotation, Size, TileMap},
+};
+
+/// Bump this when the format changes in a way old saves can't be read anymore
+const SAVE_VERSION: u32 = 5;
+
+const AUTOSAVE_INTERVAL_SECONDS: f32 = 60.0;
+
+pub struct Plugin;
+
+impl bevy::app::Plugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<SaveGame>();
+        app.add_event::<LoadGame>();
+        app.insert_resource(Autosave(Timer::from_seconds(
+            AUTOSAVE_INTERVAL_SECONDS,
+            TimerMode::Repeating,
+        )));
+        app.add_systems(Startup, load_on_startup);
+        app.add_systems(Update, (save_load_keybinds, autosave));
+        app.add_systems(Last, save_game.run_if(on_event::<SaveGame>()));
+        app.add_systems(First, load_game.run_if(on_event::<LoadGame>()));
+        app.add_systems(PreUpdate, restore_ents);
+        app.init_resource::<SavedUpgrades>();
+    }
+
+    fn finish(&self, app: &mut App) {
+        // Upgrades of every registered building are saved, the ones from mods too
+        let saving = app.world.resource_mut::<Buildings>().take_upgrade_saving();
+        for register in saving {
+            register(app);
+        }
+    }
+}
+
+#[derive(Event)]
+pub struct SaveGame;
+
+#[derive(Event)]
+pub struct LoadGame;
+
+#[derive(Resource)]
+struct Autosave(Timer);
+
+#[derive(Serialize, Deserialize)]
+struct SaveFile {
+    version: u32,
+    seed: Option<u64>,
+    money: Resources,
+    job_priorities: JobPriorities,
+    generated_chunks: Vec<[i32; 2]>,
+    ents: Vec<SavedEnt>,
+    placeholders: Vec<SavedPlaceholder>,
+    harvestables: Vec<SavedHarvestable>,
+}
+
+#[derive(Component, Clone, Serialize, Deserialize)]
+struct SavedEnt {
+    ent_type: EntType,
+    pos: [i32; 2],
+    rotation: Rotation,
+    storage: Option<(Resources, i32)>,
+    inventory: Option<Resources>,
+    spawn: Option<usize>,
+    upgrade: Option<SavedUpgrade>,
+    /// Index of the crab's home in the saved ents
+    home: Option<usize>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct SavedUpgrade {
+    level: i32,
+    /// Resources still needed and total cost of the upgrade being built right now
+    in_progress: Option<(Resources, Resources)>,
+    upgrades_left: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedPlaceholder {
+    ent_type: EntType,
+    pos: [i32; 2],
+    rotation: Rotation,
+    needs: (Resources, Resources),
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedHarvestable {
+    pos: [i32; 2],
+    kind: ResourceKind,
+    amount: i32,
+}
+
+fn save_load_keybinds(
+    keyboard: Res<Input<KeyCode>>,
+    mut save: EventWriter<SaveGame>,
+    mut load: EventWriter<LoadGame>,
+) {
+    if keyboard.just_pressed(KeyCode::F5) {
+        save.send(SaveGame);
+    }
+    if keyboard.just_pressed(KeyCode::F9) {
+        load.send(LoadGame);
+    }
+}
+
+/// Refreshing the page or restarting the game continues where it left off
+fn load_on_startup(mut load: EventWriter<LoadGame>) {
+    if storage::exists() {
+        load.send(LoadGame);
+    }
+}
+
+fn autosave(mut autosave: ResMut<Autosave>, time: Res<Time>, mut save: EventWriter<SaveGame>) {
+    if autosave.0.tick(time.delta()).just_finished() {
+        save.send(SaveGame);
+    }
+}
+
+/// Upgrades of the ents being saved, collected by one system per kind of upgrade
+#[derive(Resource, Default)]
+struct SavedUpgrades(HashMap<Entity, SavedUpgrade>);
+
+fn collect_upgrades<T: BuildingUpgrade>(
+    upgrades: Query<(
+        Entity,
+        &BuildingUpgradeComponent<T>,
+        Option<&CanUpgrade<T>>,
+        Option<&NeedsResource>,
+        Has<BuildingUpgradeToPerform<T>>,
+    )>,
+    mut saved: ResMut<SavedUpgrades>,
+) {
+    for (entity, upgrade, can_upgrade, needs, in_progress) in upgrades.iter() {
+        saved.0.insert(
+            entity,
+            SavedUpgrade {
+                level: upgrade.current_level,
+                in_progress: needs
+                    .filter(|_| in_progress)
+                    .map(|needs| (needs.0, needs.1)),
+                upgrades_left: can_upgrade.map(|can_upgrade| can_upgrade.upgrades_left),
+            },
+        );
+    }
+}
+
+fn save_game(
+    seed: Res<WorldSeed>,
+    money: Res<Money>,
+    job_priorities: Res<JobPriorities>,
+    generated_chunks: Res<GeneratedChunks>,
+    ents: Query<(
+        Entity,
+        &EntType,
+        &Pos,
+        Option<&Rotation>,
+        Option<&Storage>,
+        Option<&Inventory>,
+        Option<&Spawn>,
+        Option<&Home>,
+    )>,
+    placeholders: Query<(&Pos, &Rotation, &Placeholder, &NeedsResource)>,
+    harvestables: Query<(&Pos, &Harvestable)>,
+    mut upgrades: ResMut<SavedUpgrades>,
+) {
+    let indices: HashMap<Entity, usize> = ents
+        .iter()
+        .enumerate()
+        .map(|(index, (entity, ..))| (entity, index))
+        .collect();
+    let save = SaveFile {
+        version: SAVE_VERSION,
+        seed: Some(seed.0),
+        money: money.0,
+        job_priorities: *job_priorities,
+        generated_chunks: generated_chunks
+            .0
+            .iter()
+            .map(|pos| pos.to_array())
+            .collect(),
+        ents: ents
+            .iter()
+            .map(
+                |(entity, &ent_type, pos, rotation, storage, inventory, spawn, home)| SavedEnt {
+                    ent_type,
+                    pos: pos.0.to_array(),
+                    rotation: rotation.copied().unwrap_or_default(),
+                    storage: storage.map(|storage| (storage.current, storage.max)),
+                    inventory: inventory.map(|inventory| inventory.current),
+                    spawn: spawn.map(|spawn| spawn.amount),
+                    upgrade: upgrades.0.remove(&entity),
+                    home: home.and_then(|home| indices.get(&home.0).copied()),
+                },
+            )
+            .collect(),
+        placeholders: placeholders
+            .iter()
+            .map(|(pos, &rotation, placeholder, needs)| SavedPlaceholder {
+                ent_type: placeholder.0,
+                pos: pos.0.to_array(),
+                rotation,
+                needs: (needs.0, needs.1),
+            })
+            .collect(),
+        harvestables: harvestables
+            .iter()
+            .map(|(pos, harvestable)| SavedHarvestable {
+                pos: pos.0.to_array(),
+                kind: harvestable.kind,
+                amount: harvestable.amount,
+            })
+            .collect(),
+    };
+    upgrades.0.clear();
+    let data = match ron::to_string(&save) {
+        Ok(data) => data,
+        Err(e) => {
+            error!("Failed to serialize the game: {e}");
+            return;
+        }
+    };
+    match storage::write(&data) {
+        Ok(()) => info!("Game saved"),
+        Err(e) => error!("Failed to save the game: {e}"),
+    }
+}
+
+fn load_game(
+    ents: Query<(Entity, Option<&StorageLevelChild>), With<EntType>>,
+    others: Query<Entity, Or<(With<Placeholder>, With<Harvestable>)>>,
+    mut seed: ResMut<WorldSeed>,
+    mut money: ResMut<Money>,
+    mut job_priorities: ResMut<JobPriorities>,
+    mut history: ResMut<History>,
+    mut generated_chunks: ResMut<GeneratedChunks>,
+    mut generate_chunk_events: ResMut<Events<GenerateChunk>>,
+    mut generate_terrain: EventWriter<GenerateTerrain>,
+    mut tile_map: ResMut<TileMap>,
+    mut reset_pathfinding: EventWriter<ResetPathfinding>,
+    defs: Res<EntDefs>,
+    mut commands: Commands,
+) {
+    let save = match storage::read() {
+        Ok(data) => data,
+        Err(e) => {
+            error!("Failed to read the save: {e}");
+            return;
+        }
+    };
+    let save: SaveFile = match ron::from_str(&save) {
+        Ok(save) => save,
+        Err(e) => {
+            error!("Failed to parse the save: {e}");
+            return;
+        }
+    };
+    if save.version != SAVE_VERSION {
+        error!(
+            "Save version {} is not supported (expected {SAVE_VERSION})",
+            save.version,
+        );
+        return;
+    }
+
+    for (entity, level) in ents.iter() {
+        commands.entity(entity).despawn_recursive();
+        if let Some(level) = level {
+            commands.entity(level.0).despawn_recursive();
+        }
+    }
+    for entity in others.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+
+    if let Some(saved_seed) = save.seed {
+        // Chunks that are not generated yet must continue the same map
+        seed.set_if_neq(WorldSeed(saved_seed));
+    }
+    money.0 = save.money;
+    *job_priorities = save.job_priorities;
+    history.clear();
+    generated_chunks.0 = save
+        .generated_chunks
+        .into_iter()
+        .map(IVec2::from_array)
+        .collect();
+    // These were requested for the world we just threw away
+    generate_chunk_events.clear();
+    // Terrain is not saved, it comes from the seed like it did the first time
+    tile_map.terrain_mut().clear();
+    for &chunk in generated_chunks.0.iter() {
+        generate_terrain.send(GenerateTerrain(chunk));
+    }
+    reset_pathfinding.send(ResetPathfinding);
+
+    let homes: Vec<Option<usize>> = save.ents.iter().map(|ent| ent.home).collect();
+    // Ents of a mod that is not there anymore are left out
+    let entities: Vec<Option<Entity>> = save
+        .ents
+        .into_iter()
+        .map(|ent| {
+            if !defs.contains(ent.ent_type) {
+                warn!(
+                    "Skipped {} from the save, it is not defined",
+                    ent.ent_type.name()
+                );
+                return None;
+            }
+            let entity = commands.spawn((
+                Pos(IVec2::from_array(ent.pos)),
+                ent.rotation,
+                ent.ent_type,
+                ent,
+            ));
+            Some(entity.id())
+        })
+        .collect();
+    for (&entity, home) in entities.iter().zip(homes) {
+        let home = home.and_then(|home| *entities.get(home)?);
+        if let (Some(entity), Some(home)) = (entity, home) {
+            commands.entity(entity).insert(Home(home));
+        }
+    }
+    for placeholder in save.placeholders {
+        let Some(def) = defs.get(placeholder.ent_type) else {
+            warn!(
+                "Skipped a {} placeholder from the save, it is not defined",
+                placeholder.ent_type.name()
+            );
+            continue;
+        };
+        game::spawn_placeholder(
+            &mut commands,
+            def,
+            placeholder.ent_type,
+            IVec2::from_array(placeholder.pos),
+            placeholder.rotation,
+            NeedsResource(placeholder.needs.0, placeholder.needs.1),
+        );
+    }
+    commands.spawn_batch(
+        save.harvestables
+            .into_iter()
+            .map(|harvestable| {
+                game::harvestable_bundle(
+                    IVec2::from_array(harvestable.pos),
+                    harvestable.kind,
+                    harvestable.amount,
+                )
+            })
+            .collect::<Vec<_>>(),
+    );
+    info!("Game loaded");
+}
+
+/// Loaded ents first get default components like freshly built ones,
+/// after that we overwrite them with what was saved
+fn restore_ents(
+    mut ents: Query<
+        (
+            Entity,
+            &SavedEnt,
+            Option<&mut Storage>,
+            Option<&mut Inventory>,
+            Option<&mut Spawn>,
+        ),
+        With<Size>,
+    >,
+    mut commands: Commands,
+) {
+    for (entity, saved, storage, inventory, spawn) in ents.iter_mut() {
+        if let (Some(mut storage), Some((current, max))) = (storage, saved.storage) {
+            storage.current = current;
+            storage.max = max;
+        }
+        if let (Some(mut inventory), Some(current)) = (inventory, saved.inventory) {
+            inventory.current = current;
+        }
+        match (spawn, saved.spawn) {
+            (Some(mut spawn), Some(amount)) => spawn.amount = amount,
+            (Some(_), None) => {
+                commands.entity(entity).remove::<Spawn>();
+            }
+            _ => {}
+        }
+        commands.entity(entity).remove::<SavedEnt>();
+    }
+}
+
+/// Called for every kind of upgrade a registered building has, see [Buildings::take_upgrade_saving]
+pub fn register_saved_upgrade<T: BuildingUpgrade>(app: &mut App) {
+    app.add_systems(
+        Last,
+        collect_upgrades::<T>
+            .before(save_game)
+            .run_if(on_event::<SaveGame>()),
+    );
+    app.add_systems(PreUpdate, restore_upgrade::<T>);
+}
+
+fn restore_upgrade<T: BuildingUpgrade>(
+    mut ents: Query<
+        (
+            Entity,
+            &SavedEnt,
+            &mut BuildingUpgradeComponent<T>,
+            Option<&mut CanUpgrade<T>>,
+        ),
+        With<Size>,
+    >,
+    mut commands: Commands,
+) {
+    for (entity, saved, mut upgrade, can_upgrade) in ents.iter_mut() {
+        let Some(saved) = &saved.upgrade else {
+            continue;
+        };
+        upgrade.current_level = saved.level;
+        if let Some((needs, total)) = saved.in_progress {
+            commands.entity(entity).insert((
+                NeedsResource(needs, total),
+                BuildingUpgradeToPerform::<T>(default()),
+            ));
+        }
+        match (can_upgrade, saved.upgrades_left) {
+            (Some(mut can_upgrade), Some(upgrades_left)) if upgrades_left != 0 => {
+                can_upgrade.upgrades_left = upgrades_left;
+            }
+            (Some(_), _) => {
+                commands.entity(entity).remove::<CanUpgrade<T>>();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// In the data directory of the user, so the game finds it wherever it is started from
+#[cfg(not(target_arch = "wasm32"))]
+mod storage {
+    use std::{env, path::PathBuf};
+
+    fn path() -> Result<PathBuf, String> {
+        let var = |name| env::var_os(name).map(PathBuf::from);
+        let data_dir = if cfg!(windows) {
+            var("APPDATA")
+        } else if cfg!(target_os = "macos") {
+            var("HOME").map(|home| home.join("Library/Application Support"))
+        } else {
+            var("XDG_DATA_HOME").or_else(|| var("HOME").map(|home| home.join(".local/share")))
+        };
+        let data_dir = data_dir.ok_or("no data directory")?;
+        Ok(data_dir.join("ents").join("ents.save"))
+    }
+
+    pub fn write(data: &str) -> Result<(), String> {
+        let path = path()?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        std::fs::write(path, data).map_err(|e| e.to_string())
+    }
+
+    pub fn read() -> Result<String, String> {
+        std::fs::read_to_string(path()?).map_err(|e| e.to_string())
+    }
+
+    pub fn exists() -> bool {
+        path().is_ok_and(|path| path.exists())
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+mod storage {
+    const KEY: &str = "ents.save";
+
+    fn local_storage() -> Result<web_sys::Storage, String> {
+        web_sys::window()
+            .ok_or("no window")?
+            .local_storage()
+            .map_err(|e| format!("{e:?}"))?
+            .ok_or_else(|| "no local storage".to_owned())
+    }
+
+    pub fn write(data: &str) -> Result<(), String> {
+        local_storage()?
+            .set_item(KEY, data)
+            .map_err(|e| format!("{e:?}"))
+    }
+
+    pub fn read() -> Result<String, String> {
+        local_storage()?
+            .get_item(KEY)
+            .map_err(|e| format!("{e:?}"))?
+            .ok_or_else(|| "no save found".to_owned())
+    }
+
+    pub fn exists() -> bool {
+        local_storage().is_ok_and(|storage| storage.get_item(KEY).is_ok_and(|item| item.is_some()))
+    }
+}
@@ -0,0 +1,248 @@
+//! Ground the world stands on, decided by elevation when a chunk is generated.
+//!
+//! Crabs can't walk or build on water and are slower on sand and rock.
+//! Trees grow on fertile ground, stone and gold are found in rock.
+
+use std::sync::Arc;
+
+use bevy::{
+    prelude::*,
+    render::texture::{
+        ImageAddressMode, ImageLoaderSettings, ImageSampler, ImageSamplerDescriptor,
+    },
+    utils::HashMap,
+};
+use serde::Deserialize;
+
+use crate::{chunks::CHUNK_SIZE, meshes, tile_map::TileMap};
+
+/// Ground meshes, not needed when running headless
+pub struct UiPlugin;
+
+impl Plugin for UiPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Startup, load_ground_material);
+        app.add_systems(Update, update_ground_meshes);
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+pub enum Terrain {
+    Water,
+    Sand,
+    #[default]
+    Fertile,
+    Rock,
+}
+
+/// Elevation below which there is water
+const SEA_LEVEL: f32 = -0.3;
+/// Sand goes this much higher than the sea
+const SHORE_HEIGHT: f32 = 0.08;
+/// Elevation above which there is rock
+const ROCK_LEVEL: f32 = 0.35;
+/// How deep water looks for its elevation
+const WATER_DEPTH: f32 = 3.0;
+
+impl Terrain {
+    pub fn from_elevation(elevation: f32) -> Self {
+        if elevation < SEA_LEVEL {
+            Self::Water
+        } else if elevation < SEA_LEVEL + SHORE_HEIGHT {
+            Self::Sand
+        } else if elevation > ROCK_LEVEL {
+            Self::Rock
+        } else {
+            Self::Fertile
+        }
+    }
+
+    pub fn is_walkable(self) -> bool {
+        self != Self::Water
+    }
+
+    /// Steps onto it cost this many times more than onto a road
+    pub fn walk_cost(self) -> u32 {
+        match self {
+            Self::Fertile => 2,
+            Self::Sand => 3,
+            Self::Rock | Self::Water => 4,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Water => "water",
+            Self::Sand => "sand",
+            Self::Fertile => "fertile ground",
+            Self::Rock => "rock",
+        }
+    }
+
+    /// Tints the ground texture
+    fn color(self) -> Color {
+        match self {
+            Self::Water => Color::rgb(0.25, 0.45, 0.85),
+            Self::Sand => Color::rgb(1.0, 0.9, 0.6),
+            Self::Fertile => Color::WHITE,
+            Self::Rock => Color::rgb(0.6, 0.6, 0.6),
+        }
+    }
+}
+
+/// Terrain of one generated chunk
+pub struct TerrainChunk {
+    tiles: Box<[Terrain]>,
+    /// Height of the ground at the corners of tiles, `CHUNK_SIZE + 1` per row
+    heights: Box<[f32]>,
+}
+
+impl TerrainChunk {
+    /// Tiles of neighbouring chunks are looked at too, so heights match along the edges
+    pub fn generate(chunk: IVec2, elevation: impl Fn(IVec2) -> f32) -> Self {
+        let origin = chunk * CHUNK_SIZE;
+        let with_border = CHUNK_SIZE + 2;
+        let elevations: Vec<f32> = (-1..CHUNK_SIZE + 1)
+            .flat_map(|y| (-1..CHUNK_SIZE + 1).map(move |x| IVec2::new(x, y)))
+            .map(|tile| elevation(origin + tile))
+            .collect();
+        let elevation =
+            |tile: IVec2| elevations[((tile.y + 1) * with_border + tile.x + 1) as usize];
+        let tiles = (0..CHUNK_SIZE)
+            .flat_map(|y| (0..CHUNK_SIZE).map(move |x| IVec2::new(x, y)))
+            .map(|tile| Terrain::from_elevation(elevation(tile)))
+            .collect();
+        // Land is flat so ents stand on it, water sinks into the tiles around it
+        let height = |tile: IVec2| ((elevation(tile) - SEA_LEVEL) * WATER_DEPTH).min(0.0);
+        let heights = (0..=CHUNK_SIZE)
+            .flat_map(|y| (0..=CHUNK_SIZE).map(move |x| IVec2::new(x, y)))
+            .map(|corner| {
+                [IVec2::ZERO, IVec2::X, IVec2::Y, IVec2::ONE]
+                    .into_iter()
+                    .map(|offset| height(corner - offset))
+                    .fold(0.0, f32::min)
+            })
+            .collect();
+        Self { tiles, heights }
+    }
+
+    fn get(&self, local: IVec2) -> Terrain {
+        self.tiles[(local.y * CHUNK_SIZE + local.x) as usize]
+    }
+}
+
+/// Terrain of generated chunks, cheap to clone for flow field recomputes
+#[derive(Default, Clone)]
+pub struct TerrainMap {
+    chunks: HashMap<IVec2, Arc<TerrainChunk>>,
+    revision: u64,
+}
+
+impl TerrainMap {
+    /// Fertile where nothing is generated yet
+    pub fn get(&self, pos: IVec2) -> Terrain {
+        let chunk = pos.div_euclid(IVec2::splat(CHUNK_SIZE));
+        self.chunks
+            .get(&chunk)
+            .map_or_else(default, |terrain| terrain.get(pos - chunk * CHUNK_SIZE))
+    }
+
+    pub fn insert(&mut self, chunk: IVec2, terrain: TerrainChunk) {
+        self.chunks.insert(chunk, Arc::new(terrain));
+        self.revision += 1;
+    }
+
+    pub fn clear(&mut self) {
+        self.chunks.clear();
+        self.revision += 1;
+    }
+
+    /// Changes whenever a chunk is added or removed
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+}
+
+#[derive(Resource)]
+struct GroundMaterial(Handle<StandardMaterial>);
+
+fn load_ground_material(
+    asset_server: Res<AssetServer>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut commands: Commands,
+) {
+    let ground_texture: Handle<Image> = asset_server.load_with_settings("ground.png", {
+        let sampler_desc = ImageSamplerDescriptor {
+            address_mode_u: ImageAddressMode::Repeat,
+            address_mode_v: ImageAddressMode::Repeat,
+            ..Default::default()
+        };
+
+        move |s: &mut ImageLoaderSettings| {
+            s.sampler = ImageSampler::Descriptor(sampler_desc.clone());
+        }
+    });
+    commands.insert_resource(GroundMaterial(materials.add(StandardMaterial {
+        base_color_texture: Some(ground_texture),
+        perceptual_roughness: 1.0,
+        ..default()
+    })));
+}
+
+/// Mesh of the terrain it was made from
+#[derive(Component)]
+struct Ground {
+    chunk: IVec2,
+    terrain: Arc<TerrainChunk>,
+}
+
+/// One mesh per chunk, remade when the chunk's terrain is replaced like after loading a save
+fn update_ground_meshes(
+    tile_map: Res<TileMap>,
+    grounds: Query<(Entity, &Ground)>,
+    material: Res<GroundMaterial>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut revision: Local<Option<u64>>,
+    mut commands: Commands,
+) {
+    let terrain = tile_map.terrain();
+    if *revision == Some(terrain.revision()) {
+        return;
+    }
+    *revision = Some(terrain.revision());
+    let mut shown = Vec::new();
+    for (entity, ground) in grounds.iter() {
+        match terrain.chunks.get(&ground.chunk) {
+            Some(chunk) if Arc::ptr_eq(chunk, &ground.terrain) => shown.push(ground.chunk),
+            _ => commands.entity(entity).despawn(),
+        }
+    }
+    for (&chunk, chunk_terrain) in terrain.chunks.iter() {
+        if shown.contains(&chunk) {
+            continue;
+        }
+        let colors: Vec<Color> = chunk_terrain
+            .tiles
+            .iter()
+            .map(|tile| tile.color())
+            .collect();
+        commands.spawn((
+            PbrBundle {
+                mesh: meshes.add(meshes::ground_mesh(
+                    CHUNK_SIZE,
+                    &chunk_terrain.heights,
+                    &colors,
+                )),
+                material: material.0.clone(),
+                transform: Transform::from_translation(
+                    (chunk * CHUNK_SIZE).as_vec2().extend(-0.001).xzy(),
+                ),
+                ..default()
+            },
+            Ground {
+                chunk,
+                terrain: chunk_terrain.clone(),
+            },
+        ));
+    }
+}
@@ -0,0 +1,1630 @@
+//! Headless scenarios running the simulation without a window, renderer or player input
+
+use std::{marker::PhantomData, time::Duration};
+
+use bevy::{
+    app::ScheduleRunnerPlugin,
+    ecs::system::{Command, CommandQueue, EntityCommand, SystemState},
+    prelude::*,
+    time::TimeUpdateStrategy,
+};
+
+use crate::{
+    buildings::{AppExt, Building},
+    chunks::{GenerateRegion, GeneratedChunks},
+    construction_queue::ConstructionQueue,
+    ent_defs::{EntDef, EntDefs},
+    game::{
+        self, BuildingUpgrade, BuildingUpgradeComponent, CanMove, CancelBuildingUpgrade,
+        CancelPlaceholder, Demolish, EntType, Harvestable, Home, Inventory, Money, MoveBuilding,
+        Moving, Needs, NeedsResource, PlaceRoads, Placeholder, ProvidePopulation, SimulationSet,
+        StartBuildingUpgrade, Storage, Waiting, WalkTo, WorldSeed,
+    },
+    game_speed::{self, GameSpeed},
+    history::{Action, Perform, Redo, Site, Undo},
+    jobs::{Claim, JobBoard, JobKind, JobPriorities},
+    pathfind::{self, PathQuery, PathTarget, PathfindingBudget, PathfindingMetrics},
+    placement::{Placement, PlacementRule, Rejection},
+    resource_kind::{ResourceKind, Resources, Stone, Wood},
+    terrain::{Terrain, TerrainChunk},
+    tile_map::{Pos, Rotation, TileFlag, TileMap},
+    unit_state::{
+        BringingResource, ChoosingResource, Harvesting, Storing, TakingResource, Transition,
+        UnitState, UnitStateChanged,
+    },
+};
+
+pub struct Harness {
+    pub app: App,
+}
+
+/// Keeps [SimulationSet] from running
+#[derive(Resource)]
+struct SettlingPathfinding;
+
+impl Harness {
+    /// Empty world around the origin, nothing gets generated so scenarios place everything themselves.
+    ///
+    /// Every frame is exactly one simulation tick.
+    pub fn new() -> Self {
+        let mut harness = Self::with_frame_time(Duration::ZERO);
+        let tick = harness.app.world.resource::<Time<Fixed>>().timestep();
+        harness
+            .app
+            .insert_resource(TimeUpdateStrategy::ManualDuration(tick));
+        harness
+    }
+
+    pub fn with_frame_time(frame_time: Duration) -> Self {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins.set(ScheduleRunnerPlugin::run_once()));
+        app.insert_resource(TimeUpdateStrategy::ManualDuration(frame_time));
+        app.insert_resource(WorldSeed(0));
+        app.add_plugins(game::SimulationPlugin::default());
+        // Only the update cap limits recomputes and they are picked up on the next tick,
+        // so scenarios play out the same on every machine
+        let mut budget = app.world.resource_mut::<PathfindingBudget>();
+        budget.time_per_tick = Duration::MAX;
+        budget.wait_for_recomputes = true;
+        app.configure_sets(
+            FixedUpdate,
+            SimulationSet.run_if(not(resource_exists::<SettlingPathfinding>())),
+        );
+
+        let mut generated = app.world.resource_mut::<GeneratedChunks>();
+        for x in -1..=1 {
+            for y in -1..=1 {
+                generated.0.insert(IVec2::new(x, y));
+            }
+        }
+        Self { app }
+    }
+
+    pub fn spawn(&mut self, ent_type: EntType, pos: IVec2) -> Entity {
+        self.app.world.spawn((Pos(pos), ent_type)).id()
+    }
+
+    pub fn spawn_harvestable(&mut self, pos: IVec2, kind: ResourceKind, amount: i32) -> Entity {
+        self.app
+            .world
+            .spawn(game::harvestable_bundle(pos, kind, amount))
+            .id()
+    }
+
+    /// Same as the player placing the ent: pays for it and leaves a placeholder for builders.
+    /// Ents the player can't place are placed for free.
+    pub fn place(&mut self, ent_type: EntType, pos: IVec2) -> Entity {
+        let defs = self.app.world.resource::<EntDefs>();
+        let def = defs.get(ent_type).unwrap();
+        let cost = def.cost.unwrap_or(Resources::ZERO);
+        let mut queue = CommandQueue::default();
+        let mut commands = Commands::new(&mut queue, &self.app.world);
+        let entity = game::spawn_placeholder(
+            &mut commands,
+            def,
+            ent_type,
+            pos,
+            Rotation::default(),
+            NeedsResource(cost, cost),
+        );
+        self.app.world.resource_mut::<Money>().0 -= cost;
+        queue.apply(&mut self.app.world);
+        entity
+    }
+
+    /// Why the ent can't be placed there, empty if it can
+    pub fn rejections(
+        &mut self,
+        ent_type: EntType,
+        pos: IVec2,
+        rotation: Rotation,
+    ) -> Vec<Rejection> {
+        SystemState::<Placement>::new(&mut self.app.world)
+            .get(&self.app.world)
+            .check(ent_type, pos, rotation, None)
+    }
+
+    /// Same as the player clicking the building
+    pub fn upgrade<T: BuildingUpgrade>(&mut self, entity: Entity) {
+        StartBuildingUpgrade::<T>(PhantomData).apply(entity, &mut self.app.world);
+    }
+
+    /// Runs one frame
+    pub fn tick(&mut self) {
+        self.app.update();
+    }
+
+    pub fn run_for(&mut self, duration: Duration) {
+        let frame_time = match self.app.world.resource::<TimeUpdateStrategy>() {
+            TimeUpdateStrategy::ManualDuration(frame_time) => *frame_time,
+            _ => unreachable!(),
+        };
+        for _ in 0..duration.as_nanos() / frame_time.as_nanos() {
+            self.tick();
+        }
+    }
+
+    /// Ticks until flow fields caught up with the map, nothing else happens meanwhile
+    pub fn settle_pathfinding(&mut self) {
+        self.app.insert_resource(SettlingPathfinding);
+        self.run_until(1000, |world| {
+            let metrics = world.resource::<PathfindingMetrics>();
+            !metrics.fields.is_empty() && metrics.total_queue_len() == 0
+        });
+        self.app.world.remove_resource::<SettlingPathfinding>();
+    }
+
+    /// Ticks until the condition holds, panics if it doesn't within the given amount of ticks
+    pub fn run_until(&mut self, max_ticks: usize, mut condition: impl FnMut(&mut World) -> bool) {
+        for _ in 0..max_ticks {
+            self.tick();
+            if condition(&mut self.app.world) {
+                return;
+            }
+        }
+        panic!("condition not reached in {max_ticks} ticks");
+    }
+
+    pub fn money(&self) -> Resources {
+        self.app.world.resource::<Money>().0
+    }
+
+    /// What the player pays for placing the ent
+    pub fn cost(&self, ent_type: EntType) -> Resources {
+        let defs = self.app.world.resource::<EntDefs>();
+        defs.get(ent_type).unwrap().cost.unwrap()
+    }
+
+    pub fn get<C: Component>(&self, entity: Entity) -> Option<&C> {
+        self.app.world.get::<C>(entity)
+    }
+
+    pub fn get_mut<C: Component>(&mut self, entity: Entity) -> Option<Mut<'_, C>> {
+        self.app.world.get_mut::<C>(entity)
+    }
+
+    pub fn has<C: Component>(&self, entity: Entity) -> bool {
+        self.app.world.get::<C>(entity).is_some()
+    }
+
+    pub fn count<C: Component>(&mut self) -> usize {
+        count::<C>(&mut self.app.world)
+    }
+
+    pub fn count_ent_type(&mut self, ent_type: EntType) -> usize {
+        count_ent_type(&mut self.app.world, ent_type)
+    }
+}
+
+fn count<C: Component>(world: &mut World) -> usize {
+    world.query_filtered::<(), With<C>>().iter(world).len()
+}
+
+fn count_ent_type(world: &mut World, ent_type: EntType) -> usize {
+    world
+        .query::<&EntType>()
+        .iter(world)
+        .filter(|&&typ| typ == ent_type)
+        .count()
+}
+
+#[test]
+fn harvester_brings_resources_to_storage() {
+    let mut harness = Harness::new();
+    let storage = harness.spawn(EntType::STORAGE, IVec2::new(0, 0));
+    let harvester = harness.spawn(EntType::HARVESTER, IVec2::new(6, 0));
+    harness.spawn_harvestable(IVec2::new(10, 0), ResourceKind::Stone, 3);
+    harness.tick();
+    assert!(harness.has::<Harvesting>(harvester));
+    let money = harness.money();
+
+    let mut seen_storing = false;
+    harness.run_until(2000, |world| {
+        seen_storing |= world.get::<Storing>(harvester).is_some();
+        world.get::<Storage>(storage).unwrap().current == Resources::new(0, 3, 0)
+    });
+    assert!(seen_storing);
+    assert_eq!(harness.money(), money + Resources::new(0, 3, 0));
+    assert_eq!(harness.count::<Harvestable>(), 0);
+    assert!(harness
+        .get::<Inventory>(harvester)
+        .unwrap()
+        .current
+        .is_empty());
+}
+
+#[test]
+fn builder_finishes_placed_house() {
+    let mut harness = Harness::new();
+    let base = harness.spawn(EntType::BASE, IVec2::new(0, 0));
+    let builder = harness.spawn(EntType::BUILDER, IVec2::new(6, 0));
+    harness.tick();
+    let base_resources = harness.get::<Storage>(base).unwrap().current;
+
+    let placeholder = harness.place(EntType::HOUSE, IVec2::new(10, 0));
+    let cost = Resources::new(10, 0, 0);
+    assert_eq!(harness.money(), base_resources - cost);
+
+    let mut seen = (false, false);
+    harness.run_until(2000, |world| {
+        seen.0 |= world.get::<TakingResource<Wood>>(builder).is_some();
+        seen.1 |= world.get::<BringingResource<Wood>>(builder).is_some();
+        count::<Placeholder>(world) == 0
+    });
+    assert!(seen.0 && seen.1);
+    harness.tick();
+    assert!(harness.app.world.get_entity(placeholder).is_none());
+    assert_eq!(harness.count_ent_type(EntType::HOUSE), 1);
+    assert_eq!(
+        harness.get::<Storage>(base).unwrap().current,
+        base_resources - cost
+    );
+}
+
+#[test]
+fn upgraded_house_spawns_more_crabs() {
+    let mut harness = Harness::new();
+    harness.spawn(EntType::BASE, IVec2::new(0, 0));
+    harness.spawn(EntType::BUILDER, IVec2::new(6, 0));
+    let house = harness.spawn(EntType::HOUSE, IVec2::new(10, 0));
+    harness.run_until(100, |world| count_ent_type(world, EntType::HARVESTER) == 5);
+
+    let money = harness.money();
+    harness.upgrade::<ProvidePopulation>(house);
+    let cost = harness
+        .app
+        .world
+        .resource::<EntDefs>()
+        .get(EntType::HOUSE)
+        .unwrap()
+        .level_cost(1)
+        .unwrap();
+    assert_eq!(harness.money(), money - cost);
+    assert_eq!(
+        harness
+            .get::<BuildingUpgradeComponent<ProvidePopulation>>(house)
+            .unwrap()
+            .current_level,
+        1
+    );
+
+    harness.run_until(3000, |world| {
+        count_ent_type(world, EntType::HARVESTER) == 10
+    });
+    assert!(!harness.has::<NeedsResource>(house));
+}
+
+#[test]
+fn upgrades_ending_between_ticks_are_noticed() {
+    // Several frames per tick, more than removals are reported for
+    let mut harness = Harness::with_frame_time(Duration::from_nanos(1_000_000_000 / 144));
+    harness.spawn(EntType::BASE, IVec2::new(0, 0));
+    let builder = harness.spawn(EntType::BUILDER, IVec2::new(6, 0));
+    let house = harness.spawn(EntType::HOUSE, IVec2::new(10, 0));
+    harness.run_for(Duration::from_secs(1));
+
+    harness.upgrade::<ProvidePopulation>(house);
+    harness.run_until(10, |world| world.get::<Needs<Wood>>(house).is_some());
+    CancelBuildingUpgrade::<ProvidePopulation>(PhantomData).apply(house, &mut harness.app.world);
+    harness.run_for(Duration::from_secs(1));
+    assert!(!harness.has::<Needs<Wood>>(house));
+    let queue = harness.app.world.resource::<ConstructionQueue>();
+    assert_eq!(queue.position(house), None);
+
+    harness.upgrade::<ProvidePopulation>(house);
+    harness.run_until(20000, |world| world.get::<NeedsResource>(house).is_none());
+    harness.run_for(Duration::from_secs(1));
+    assert!(!harness.has::<Needs<Wood>>(house));
+    let queue = harness.app.world.resource::<ConstructionQueue>();
+    assert_eq!(queue.position(house), None);
+    assert!(!harness.has::<Claim>(builder));
+}
+
+#[test]
+fn job_board_drops_jobs_gone_between_ticks() {
+    let mut harness = Harness::with_frame_time(Duration::from_nanos(1_000_000_000 / 144));
+    harness.spawn(EntType::BASE, IVec2::new(0, 0));
+    let storage = harness.spawn(EntType::STORAGE, IVec2::new(10, 0));
+    let house = harness.spawn(EntType::HOUSE, IVec2::new(0, 10));
+    harness.run_for(Duration::from_secs(1));
+    harness.upgrade::<ProvidePopulation>(house);
+    harness.run_until(10, |world| {
+        let board = world.resource::<JobBoard>();
+        board.get(house, JobKind::Build).is_some() && board.get(storage, JobKind::Store).is_some()
+    });
+
+    CancelBuildingUpgrade::<ProvidePopulation>(PhantomData).apply(house, &mut harness.app.world);
+    Demolish.apply(storage, &mut harness.app.world);
+    harness.run_for(Duration::from_secs(1));
+    let board = harness.app.world.resource::<JobBoard>();
+    assert!(board.get(house, JobKind::Build).is_none());
+    assert!(board.get(storage, JobKind::Store).is_none());
+}
+
+#[test]
+fn income_does_not_depend_on_frame_rate() {
+    let income = |fps: u64| {
+        let mut harness = Harness::with_frame_time(Duration::from_nanos(1_000_000_000 / fps));
+        harness.spawn(EntType::STORAGE, IVec2::new(0, 0));
+        for y in 0..3 {
+            harness.spawn(EntType::HARVESTER, IVec2::new(6, y));
+        }
+        harness.spawn_harvestable(IVec2::new(12, 0), ResourceKind::Wood, 100);
+        // Let pathfinding settle so only the simulation itself is measured
+        harness.run_for(Duration::from_secs(1));
+        let money = harness.money();
+        harness.run_for(Duration::from_secs(20));
+        harness.money() - money
+    };
+    let slow = income(20);
+    assert!(slow.total() > 0);
+    assert_eq!(slow, income(60));
+    assert_eq!(slow, income(144));
+}
+
+#[test]
+fn gold_harvester_income_does_not_depend_on_frame_rate() {
+    let income = |fps: u64| {
+        let mut harness = Harness::with_frame_time(Duration::from_nanos(1_000_000_000 / fps));
+        harness.spawn(EntType::STORAGE, IVec2::new(0, 0));
+        // Harvests a few ticks in a row before going back, unlike a regular harvester
+        harness.spawn(EntType::GOLD_HARVESTER, IVec2::new(6, 0));
+        harness.spawn_harvestable(IVec2::new(12, 0), ResourceKind::Wood, 100);
+        harness.run_for(Duration::from_secs(1));
+        let money = harness.money();
+        harness.run_for(Duration::from_secs(20));
+        harness.money() - money
+    };
+    let slow = income(20);
+    assert!(slow.total() > 0);
+    assert_eq!(slow, income(60));
+    assert_eq!(slow, income(144));
+}
+
+#[test]
+fn game_speed_scales_simulation() {
+    let income = |speed: GameSpeed| {
+        let mut harness = Harness::new();
+        harness.app.init_resource::<Input<KeyCode>>();
+        harness.app.add_plugins(game_speed::Plugin);
+        harness.app.insert_resource(speed);
+        harness.spawn(EntType::STORAGE, IVec2::new(0, 0));
+        harness.spawn(EntType::HARVESTER, IVec2::new(6, 0));
+        harness.spawn_harvestable(IVec2::new(12, 0), ResourceKind::Wood, 100);
+        let money = harness.money();
+        harness.run_for(Duration::from_secs(10));
+        (harness.money() - money).total()
+    };
+    assert_eq!(income(GameSpeed::Paused), 0);
+    let normal = income(GameSpeed::Normal);
+    assert!(normal > 0);
+    assert!(income(GameSpeed::Quadruple) > 2 * normal);
+}
+
+#[test]
+fn placing_while_paused_sees_earlier_placements() {
+    let mut harness = Harness::new();
+    harness.app.init_resource::<Input<KeyCode>>();
+    harness.app.add_plugins(game_speed::Plugin);
+    harness.spawn(EntType::ROAD, IVec2::ZERO);
+    harness.tick();
+    harness.tick();
+    harness.app.insert_resource(GameSpeed::Paused);
+    harness.tick();
+    let ticked = harness.app.world.resource::<Time<Fixed>>().elapsed();
+
+    let road = harness.place(EntType::ROAD, IVec2::new(1, 0));
+    harness.tick();
+    let rejections = |harness: &mut Harness, x| {
+        harness.rejections(EntType::ROAD, IVec2::new(x, 0), Rotation::default())
+    };
+    assert_eq!(rejections(&mut harness, 1), [Rejection::OnRoad]);
+    assert_eq!(rejections(&mut harness, 2), []);
+
+    CancelPlaceholder.apply(road, &mut harness.app.world);
+    harness.tick();
+    assert_eq!(rejections(&mut harness, 1), []);
+    assert_eq!(rejections(&mut harness, 2), [Rejection::NoRoad]);
+    assert_eq!(
+        harness.app.world.resource::<Time<Fixed>>().elapsed(),
+        ticked
+    );
+}
+
+#[test]
+fn builders_bring_every_kind_a_building_needs() {
+    let mut harness = Harness::new();
+    let base = harness.spawn(EntType::BASE, IVec2::new(0, 0));
+    harness.spawn(EntType::BUILDER, IVec2::new(6, 0));
+    harness.spawn(EntType::BUILDER, IVec2::new(6, 2));
+    harness.tick();
+    let base_resources = harness.get::<Storage>(base).unwrap().current;
+
+    let cost = harness
+        .app
+        .world
+        .resource::<EntDefs>()
+        .get(EntType::UPGRADE_INVENTORY)
+        .unwrap()
+        .cost
+        .unwrap();
+    assert!(cost[ResourceKind::Wood] > 0 && cost[ResourceKind::Stone] > 0);
+    harness.place(EntType::UPGRADE_INVENTORY, IVec2::new(10, 0));
+
+    let mut seen_stone = false;
+    harness.run_until(5000, |world| {
+        seen_stone |= world
+            .query_filtered::<(), With<BringingResource<Stone>>>()
+            .iter(world)
+            .next()
+            .is_some();
+        count_ent_type(world, EntType::UPGRADE_INVENTORY) == 1
+    });
+    assert!(seen_stone);
+    harness.run_until(100, |world| {
+        world
+            .query_filtered::<&Inventory, With<ChoosingResource>>()
+            .iter(world)
+            .count()
+            == 2
+    });
+    assert_eq!(
+        harness.get::<Storage>(base).unwrap().current,
+        base_resources - cost
+    );
+}
+
+#[test]
+fn world_has_every_kind_of_deposit() {
+    let mut harness = Harness::new();
+    harness
+        .app
+        .world
+        .resource_mut::<GeneratedChunks>()
+        .0
+        .clear();
+    harness
+        .app
+        .world
+        .send_event(GenerateRegion(Rect::new(-100.0, -100.0, 100.0, 100.0)));
+    harness.run_until(10, |world| count::<Harvestable>(world) != 0);
+    let kinds: Vec<ResourceKind> = harness
+        .app
+        .world
+        .query::<&Harvestable>()
+        .iter(&harness.app.world)
+        .map(|harvestable| harvestable.kind)
+        .collect();
+    for kind in ResourceKind::ALL {
+        assert!(kinds.contains(&kind), "no {kind:?} generated");
+    }
+}
+
+#[test]
+fn ent_defs_decide_what_buildings_do() {
+    let mut harness = Harness::new();
+    let defs = include_str!("../assets/ents.defs.ron")
+        .replace("spawn: (\"Harvester\", 5)", "spawn: (\"Builder\", 2)");
+    harness
+        .app
+        .insert_resource(EntDefs::parse(defs.as_bytes()).unwrap());
+    harness.spawn(EntType::HOUSE, IVec2::new(0, 0));
+    harness.run_until(100, |world| count_ent_type(world, EntType::BUILDER) == 2);
+    assert_eq!(harness.count_ent_type(EntType::HARVESTER), 0);
+
+    // Typos are errors rather than silently using the default
+    assert!(EntDefs::parse(br#"{ "Harvester": (sise: (1, 1)) }"#).is_err());
+    // So are ents that cover no tiles
+    assert!(EntDefs::parse(br#"{ "House": (size: (2, 0)) }"#).is_err());
+    assert!(EntDefs::parse(br#"{ "House": (size: (2, 1), footprint: Some([".."])) }"#).is_err());
+}
+
+#[test]
+fn mods_can_register_buildings() {
+    #[derive(Component)]
+    struct Shrine;
+
+    let shrine = EntType::new("Shrine");
+    let mut harness = Harness::new();
+    harness.app.register_building(
+        Building::new(shrine)
+            .with_def(EntDef {
+                size: (2, 1),
+                cost: Some(Resources::new(5, 5, 0)),
+                ..default()
+            })
+            .with_bundle(|| Shrine)
+            .with_upgrade::<ProvidePopulation>(),
+    );
+    harness.spawn(EntType::BASE, IVec2::new(0, 0));
+    harness.spawn(EntType::BUILDER, IVec2::new(6, 0));
+    harness.tick();
+
+    harness.place(shrine, IVec2::new(10, 0));
+    harness.run_until(2000, |world| count_ent_type(world, shrine) == 1);
+    harness.tick();
+    assert_eq!(harness.count::<Shrine>(), 1);
+    assert_eq!(
+        harness.count::<BuildingUpgradeComponent<ProvidePopulation>>(),
+        1
+    );
+}
+
+#[test]
+fn crabs_walk_to_the_target_they_are_sent_to() {
+    let mut harness = Harness::new();
+    let near = harness.spawn(EntType::STORAGE, IVec2::new(0, 0));
+    let far = harness.spawn(EntType::STORAGE, IVec2::new(20, 0));
+    // Wall between the crab and the far storage, of buildings that don't spawn crabs standing
+    // around in the way
+    for y in -4..=4 {
+        harness.spawn(EntType::BUILDER_ACADEMY, IVec2::new(12, y * 2));
+    }
+    let crab = harness.spawn(EntType::BUILDER, IVec2::new(8, 1));
+    let other_crab = harness.spawn(EntType::BUILDER, IVec2::new(8, 5));
+    // Footprints are known to the tile map a tick after spawning
+    harness.tick();
+    harness.tick();
+
+    let mut state = SystemState::<PathQuery>::new(&mut harness.app.world);
+    let path = state
+        .get_mut(&mut harness.app.world)
+        .path(IVec2::new(8, 1), &PathTarget::Entity(far))
+        .unwrap();
+    let wall = IRect::new(12, -8, 14, 9);
+    assert!(path.iter().all(|&pos| !wall.contains(pos)));
+    assert!((20..24).contains(&path.last().unwrap().x));
+
+    // Each goes to its own storage, not the closest one
+    harness
+        .app
+        .world
+        .entity_mut(crab)
+        .insert(WalkTo(PathTarget::Entity(far)));
+    harness
+        .app
+        .world
+        .entity_mut(other_crab)
+        .insert(WalkTo(PathTarget::Entity(near)));
+    harness.run_until(500, |world| {
+        world.get::<WalkTo>(crab).is_none() && world.get::<WalkTo>(other_crab).is_none()
+    });
+    let tile_map = harness.app.world.resource::<TileMap>();
+    for (crab, target) in [(crab, far), (other_crab, near)] {
+        let pos = harness.get::<Pos>(crab).unwrap().0;
+        assert!(
+            game::MOVE_DIRECTIONS.iter().any(|&dir| tile_map
+                .entities_at(pos + dir)
+                .any(|entity| entity == target)),
+            "{pos} is not next to the storage the crab was sent to"
+        );
+    }
+}
+
+#[test]
+fn flow_fields_settle_in_small_batches() {
+    let mut harness = Harness::new();
+    harness
+        .app
+        .world
+        .resource_mut::<PathfindingBudget>()
+        .max_updates_per_tick = 800;
+    // A single chunk, so the fields are small enough to settle at the minimal pace
+    let mut generated = harness.app.world.resource_mut::<GeneratedChunks>();
+    generated.0.clear();
+    generated.0.insert(IVec2::ZERO);
+    harness.spawn(EntType::STORAGE, IVec2::new(0, 0));
+    harness.spawn_harvestable(IVec2::new(12, 0), ResourceKind::Wood, 100);
+    // Out of time before the first update
+    let mut budget = harness.app.world.resource_mut::<PathfindingBudget>();
+    budget.time_per_tick = Duration::ZERO;
+    harness.tick();
+    harness.tick();
+    let metrics = harness.app.world.resource::<PathfindingMetrics>();
+    let field = metrics.field::<Harvestable>().unwrap();
+    assert!(field.queue_len > 0);
+    assert_eq!(field.updates_last_batch, 0);
+    let mut budget = harness.app.world.resource_mut::<PathfindingBudget>();
+    budget.time_per_tick = Duration::MAX;
+
+    harness.run_until(1000, |world| {
+        let metrics = world.resource::<PathfindingMetrics>();
+        metrics.total_queue_len() == 0
+    });
+    let metrics = harness.app.world.resource::<PathfindingMetrics>();
+    assert!(metrics
+        .field::<Harvestable>()
+        .unwrap()
+        .last_convergence
+        .is_some());
+}
+
+#[test]
+fn flow_fields_are_picked_up_once_recomputed() {
+    let mut harness = Harness::new();
+    harness
+        .app
+        .world
+        .resource_mut::<PathfindingBudget>()
+        .wait_for_recomputes = false;
+    harness.spawn(EntType::STORAGE, IVec2::new(0, 0));
+    let harvestable = harness.spawn_harvestable(IVec2::new(12, 0), ResourceKind::Wood, 100);
+    harness.run_until(1000, |world| {
+        // Gives the recomputes time to finish, ticks don't wait for them
+        std::thread::sleep(Duration::from_millis(1));
+        let metrics = world.resource::<PathfindingMetrics>();
+        metrics.total_queue_len() == 0
+            && metrics
+                .field::<Harvestable>()
+                .is_some_and(|field| field.last_convergence.is_some())
+    });
+
+    // The target moved, the field follows it
+    harness.get_mut::<Pos>(harvestable).unwrap().0 = IVec2::new(-12, 0);
+    let crab = harness.spawn(EntType::HARVESTER, IVec2::new(-6, 0));
+    harness.run_until(1000, |world| {
+        world.get::<Inventory>(crab).unwrap().current.total() > 0
+    });
+}
+
+#[test]
+fn tile_map_keeps_track_of_what_is_on_each_tile() {
+    let mut harness = Harness::new();
+    // Right on the corner of four chunks
+    let house = harness.spawn(EntType::HOUSE, IVec2::new(63, -1));
+    harness.spawn(EntType::ROAD, IVec2::new(70, 0));
+    harness.spawn_harvestable(IVec2::new(75, 0), ResourceKind::Wood, 10);
+    harness.tick();
+    harness.tick();
+
+    let tile_map = harness.app.world.resource::<TileMap>();
+    for pos in [(63, -1), (64, -1), (63, 0), (64, 0)] {
+        assert!(tile_map.is_blocking(IVec2::from(pos)));
+    }
+    assert!(!tile_map.is_blocking(IVec2::new(65, 0)));
+    assert!(tile_map.is_road(IVec2::new(70, 0)));
+    assert!(tile_map.is_harvestable(IVec2::new(75, 0)));
+    let in_rect: Vec<Entity> = tile_map
+        .entities_in_rect(IRect::new(60, -4, 80, 4))
+        .collect();
+    assert_eq!(in_rect.iter().filter(|&&e| e == house).count(), 4);
+
+    harness.app.world.despawn(house);
+    harness.tick();
+    let tile_map = harness.app.world.resource::<TileMap>();
+    assert!(!tile_map.is_blocking(IVec2::new(64, 0)));
+    assert!(!tile_map
+        .entities_in_rect(IRect::new(60, -4, 80, 4))
+        .any(|e| e == house));
+}
+
+#[test]
+fn diagonal_movement_does_not_cut_corners() {
+    let diagonal_steps = |diagonal_movement: bool| {
+        let mut harness = Harness::new();
+        harness.app.world.resource_mut::<EntDefs>().insert(
+            EntType::HARVESTER,
+            EntDef {
+                diagonal_movement,
+                ..EntDefs::builtin().get(EntType::HARVESTER).unwrap().clone()
+            },
+        );
+        let storage = harness.spawn(EntType::STORAGE, IVec2::new(0, 0));
+        harness.spawn(EntType::HOUSE, IVec2::new(8, 6));
+        harness.spawn(EntType::HARVESTER, IVec2::new(12, 10));
+        harness.spawn_harvestable(IVec2::new(16, 14), ResourceKind::Wood, 2);
+        harness.settle_pathfinding();
+
+        let mut diagonal_steps = 0;
+        harness.run_until(3000, |world| {
+            let started: Vec<(IVec2, IVec2)> = world
+                .query::<(&Pos, &Moving)>()
+                .iter(world)
+                .filter(|(_, moving)| moving.t == 0.0)
+                .map(|(pos, moving)| (pos.0, moving.next_pos - pos.0))
+                .filter(|(_, dir)| dir.x != 0 && dir.y != 0)
+                .collect();
+            let tile_map = world.resource::<TileMap>();
+            for (pos, dir) in started {
+                diagonal_steps += 1;
+                assert!(!pathfind::cuts_corner(pos, dir, |pos| tile_map.is_blocking(pos)));
+            }
+            world.get::<Storage>(storage).unwrap().current.total() == 2
+        });
+        diagonal_steps
+    };
+    assert!(diagonal_steps(true) > 0);
+    assert_eq!(diagonal_steps(false), 0);
+}
+
+#[test]
+fn crabs_do_not_pile_up_on_one_tile() {
+    let mut harness = Harness::new();
+    harness.spawn(EntType::STORAGE, IVec2::new(0, 0));
+    harness.spawn_harvestable(IVec2::new(30, 0), ResourceKind::Wood, 20);
+    for _ in 0..12 {
+        harness.spawn(EntType::HARVESTER, IVec2::new(10, 0));
+    }
+    harness.settle_pathfinding();
+
+    // Long enough for crabs to run out of patience, they still don't squeeze in
+    let mut most_on_one_tile = 0;
+    for _ in 0..200 {
+        harness.tick();
+        let world = &mut harness.app.world;
+        let mut on_tile = bevy::utils::HashMap::<IVec2, usize>::new();
+        for (pos, _) in world.query::<(&Pos, &CanMove)>().iter(world) {
+            if pos.0 != IVec2::new(10, 0) {
+                *on_tile.entry(pos.0).or_default() += 1;
+            }
+        }
+        most_on_one_tile = most_on_one_tile.max(on_tile.values().copied().max().unwrap_or(0));
+    }
+    assert_eq!(most_on_one_tile, pathfind::capacity(false));
+    assert!(harness.count::<Waiting>() > 0);
+}
+
+#[test]
+fn crabs_standing_around_block_the_way() {
+    let mut harness = Harness::new();
+    // Corridor along y = 0 between two water walls
+    let elevation = |pos: IVec2| match pos {
+        IVec2 {
+            x: 0..=10,
+            y: -1 | 1,
+        } => -1.0,
+        _ => 0.0,
+    };
+    let mut tile_map = harness.app.world.resource_mut::<TileMap>();
+    for chunk in [IVec2::ZERO, IVec2::new(0, -1)] {
+        tile_map
+            .terrain_mut()
+            .insert(chunk, TerrainChunk::generate(chunk, elevation));
+    }
+    // Nothing to harvest or store, so they stay where they are
+    let standing = [
+        harness.spawn(EntType::HARVESTER, IVec2::new(5, 0)),
+        harness.spawn(EntType::HARVESTER, IVec2::new(5, 0)),
+    ];
+    let walker = harness.spawn(EntType::HARVESTER, IVec2::new(1, 0));
+    harness
+        .app
+        .world
+        .entity_mut(walker)
+        .insert(WalkTo(PathTarget::Tiles(vec![IVec2::new(9, 0)])));
+
+    let mut waited = false;
+    for _ in 0..300 {
+        harness.tick();
+        let walker_pos = harness.get::<Pos>(walker).unwrap().0;
+        assert!(walker_pos.x < 5, "walked through the crabs to {walker_pos}");
+        waited |= harness.has::<Waiting>(walker);
+    }
+    assert!(waited);
+    for crab in standing {
+        assert_eq!(harness.get::<Pos>(crab).unwrap().0, IVec2::new(5, 0));
+    }
+}
+
+#[test]
+fn builder_follows_the_construction_queue() {
+    let mut harness = Harness::new();
+    harness.spawn(EntType::BASE, IVec2::new(0, 0));
+    harness.tick();
+    let near = harness.place(EntType::HOUSE, IVec2::new(12, 0));
+    let far = harness.place(EntType::HOUSE, IVec2::new(-20, 0));
+    harness.tick();
+    let mut queue = harness.app.world.resource_mut::<ConstructionQueue>();
+    assert_eq!(queue.iter().collect::<Vec<_>>(), [near, far]);
+    // Even though the other one is closer
+    queue.move_by_one(far, true);
+    let builder = harness.spawn(EntType::BUILDER, IVec2::new(6, 0));
+
+    let mut claimed = Vec::new();
+    harness.run_until(3000, |world| {
+        if let Some(claim) = world.get::<Claim>(builder) {
+            claimed.push(claim.target);
+        }
+        world.get_entity(far).is_none()
+    });
+    assert!(claimed.iter().all(|&site| site == far));
+    assert!(harness.has::<NeedsResource>(near));
+    harness.run_until(3000, |world| world.get_entity(near).is_none());
+}
+
+#[test]
+fn harvesters_keep_harvesting_while_it_comes_before_upgrades() {
+    let mut harness = Harness::new();
+    let storage = harness.spawn(EntType::STORAGE, IVec2::new(0, 0));
+    let harvester = harness.spawn(EntType::HARVESTER, IVec2::new(6, 0));
+    harness.spawn_harvestable(IVec2::new(10, 0), ResourceKind::Wood, 100);
+    harness.spawn(EntType::BUILDER_ACADEMY, IVec2::new(0, 10));
+    let mut priorities = harness.app.world.resource_mut::<JobPriorities>();
+    priorities.set(JobKind::Harvest, 4);
+    assert!(priorities.prefers(JobKind::Harvest, JobKind::Upgrade));
+
+    harness.settle_pathfinding();
+    harness.run_for(Duration::from_secs(5));
+    assert!(!matches!(
+        harness.get::<Claim>(harvester),
+        Some(Claim {
+            kind: JobKind::Upgrade,
+            ..
+        })
+    ));
+    assert_eq!(harness.count_ent_type(EntType::BUILDER), 0);
+    assert!(harness.get::<Storage>(storage).unwrap().current[ResourceKind::Wood] > 0);
+
+    harness
+        .app
+        .world
+        .resource_mut::<JobPriorities>()
+        .set(JobKind::Harvest, 1);
+    harness.run_until(2000, |world| count_ent_type(world, EntType::BUILDER) == 1);
+    assert_eq!(harness.count_ent_type(EntType::HARVESTER), 0);
+}
+
+#[test]
+fn harvesters_claim_space_in_the_closest_storage() {
+    let mut harness = Harness::new();
+    let near = harness.spawn(EntType::STORAGE, IVec2::new(0, 0));
+    harness.spawn(EntType::STORAGE, IVec2::new(30, 0));
+    let harvester = harness.spawn(EntType::HARVESTER, IVec2::new(8, 0));
+    harness.spawn_harvestable(IVec2::new(12, 0), ResourceKind::Wood, 100);
+    harness.settle_pathfinding();
+
+    let mut claimed = None;
+    harness.run_until(2000, |world| {
+        claimed = world
+            .get::<Claim>(harvester)
+            .filter(|claim| claim.kind == JobKind::Store)
+            .map(|claim| (claim.target, claim.amount));
+        claimed.is_some()
+    });
+    let inventory = harness.get::<Inventory>(harvester).unwrap().current.total();
+    assert_eq!(claimed, Some((near, inventory)));
+    harness.run_until(2000, |world| {
+        world.get::<Storage>(near).unwrap().current.total() == inventory
+    });
+}
+
+#[test]
+fn builders_harvest_while_it_comes_before_building() {
+    let mut harness = Harness::new();
+    let storage = harness.spawn(EntType::STORAGE, IVec2::new(0, 0));
+    harness.spawn(EntType::BUILDER, IVec2::new(6, 0));
+    harness.spawn_harvestable(IVec2::new(10, 0), ResourceKind::Wood, 100);
+    let house = harness.place(EntType::HOUSE, IVec2::new(0, 10));
+    harness
+        .app
+        .world
+        .resource_mut::<JobPriorities>()
+        .set(JobKind::Harvest, 4);
+    harness.settle_pathfinding();
+
+    // Enough for the house, which is still waiting for all of it
+    let money = harness.money();
+    harness.run_until(2000, |world| {
+        world.get::<Storage>(storage).unwrap().current[ResourceKind::Wood] >= 10
+    });
+    assert!(harness.money()[ResourceKind::Wood] >= money[ResourceKind::Wood] + 10);
+    assert_eq!(
+        harness.get::<NeedsResource>(house).unwrap().0,
+        harness.get::<NeedsResource>(house).unwrap().1
+    );
+
+    harness
+        .app
+        .world
+        .resource_mut::<JobPriorities>()
+        .set(JobKind::Harvest, 1);
+    harness.run_until(3000, |world| count_ent_type(world, EntType::HOUSE) == 1);
+}
+
+#[test]
+fn unit_state_changes_follow_the_transition_table() {
+    let mut harness = Harness::new();
+    let harvester = harness.spawn(EntType::HARVESTER, IVec2::new(0, 0));
+    harness.tick();
+    assert_eq!(
+        harness.get::<UnitState>(harvester),
+        Some(&UnitState::Harvesting)
+    );
+    let mut reader = harness
+        .app
+        .world
+        .resource::<Events<UnitStateChanged>>()
+        .get_reader_current();
+
+    let world = &mut harness.app.world;
+    // Another system already moved it on
+    Transition {
+        from: Some(UnitState::Storing),
+        to: UnitState::Harvesting,
+    }
+    .apply(harvester, world);
+    // Harvesters don't build
+    Transition {
+        from: Some(UnitState::Harvesting),
+        to: UnitState::Taking(ResourceKind::Wood),
+    }
+    .apply(harvester, world);
+    assert!(world.get::<TakingResource<Wood>>(harvester).is_none());
+    Transition {
+        from: Some(UnitState::Harvesting),
+        to: UnitState::Storing,
+    }
+    .apply(harvester, world);
+
+    assert_eq!(world.get::<UnitState>(harvester), Some(&UnitState::Storing));
+    assert!(world.get::<Harvesting>(harvester).is_none());
+    assert!(world.get::<Storing>(harvester).is_some());
+    let changes: Vec<_> = reader
+        .read(world.resource::<Events<UnitStateChanged>>())
+        .copied()
+        .collect();
+    assert_eq!(
+        changes,
+        [UnitStateChanged {
+            entity: harvester,
+            from: Some(UnitState::Harvesting),
+            to: UnitState::Storing,
+        }]
+    );
+}
+
+#[test]
+fn demolished_storage_refunds_and_hands_over_its_contents() {
+    let mut harness = Harness::new();
+    let base = harness.spawn(EntType::BASE, IVec2::new(0, 0));
+    let storage = harness.spawn(EntType::STORAGE, IVec2::new(10, 0));
+    harness.tick();
+    harness.get_mut::<Storage>(storage).unwrap().current = Resources::new(5, 5, 0);
+    harness.get_mut::<Storage>(base).unwrap().current = Resources::ZERO;
+    let money = harness.money();
+
+    Demolish.apply(storage, &mut harness.app.world);
+    assert_eq!(
+        game::demolish_refund(Resources::new(60, 40, 0)),
+        Resources::new(30, 20, 0)
+    );
+    let refund = game::demolish_refund(harness.cost(EntType::STORAGE));
+    assert!(harness.app.world.get_entity(storage).is_none());
+    assert_eq!(harness.money(), money + refund);
+    assert_eq!(
+        harness.get::<Storage>(base).unwrap().current,
+        refund + Resources::new(5, 5, 0)
+    );
+
+    // The base can't be demolished
+    Demolish.apply(base, &mut harness.app.world);
+    assert!(harness.app.world.get_entity(base).is_some());
+}
+
+#[test]
+fn crabs_of_a_demolished_house_move_to_another_one() {
+    let mut harness = Harness::new();
+    let house = harness.spawn(EntType::HOUSE, IVec2::new(0, 0));
+    let other = harness.spawn(EntType::HOUSE, IVec2::new(10, 0));
+    harness.run_until(100, |world| count_ent_type(world, EntType::HARVESTER) == 10);
+
+    Demolish.apply(house, &mut harness.app.world);
+    let homes: Vec<_> = harness
+        .app
+        .world
+        .query::<&Home>()
+        .iter(&harness.app.world)
+        .map(|home| home.0)
+        .collect();
+    assert_eq!(homes, [other; 10]);
+}
+
+#[test]
+fn moved_building_is_built_again_at_the_new_spot() {
+    let mut harness = Harness::new();
+    harness.spawn(EntType::BASE, IVec2::new(0, 0));
+    let house = harness.spawn(EntType::HOUSE, IVec2::new(10, 0));
+    harness.spawn(EntType::ROAD, IVec2::new(20, -1));
+    harness.tick();
+    harness.tick();
+    let money = harness.money();
+
+    let world = &mut harness.app.world;
+    // Nothing to connect to here
+    MoveBuilding {
+        to: IVec2::new(30, 0),
+        rotation: Rotation::default(),
+    }
+    .apply(house, world);
+    assert!(world.get_entity(house).is_some());
+    MoveBuilding {
+        to: IVec2::new(20, 0),
+        rotation: Rotation::default(),
+    }
+    .apply(house, world);
+    assert!(world.get_entity(house).is_none());
+
+    let cost = harness.cost(EntType::HOUSE);
+    assert_eq!(harness.money(), money - cost + game::demolish_refund(cost));
+    let placeholders: Vec<_> = harness
+        .app
+        .world
+        .query::<(&Placeholder, &Pos)>()
+        .iter(&harness.app.world)
+        .map(|(placeholder, pos)| (placeholder.0, pos.0))
+        .collect();
+    assert_eq!(placeholders, [(EntType::HOUSE, IVec2::new(20, 0))]);
+}
+
+#[test]
+fn moved_building_can_overlap_where_it_was() {
+    let mut harness = Harness::new();
+    harness.spawn(EntType::BASE, IVec2::new(0, 0));
+    let house = harness.spawn(EntType::HOUSE, IVec2::new(10, 0));
+    for x in 9..15 {
+        harness.spawn(EntType::ROAD, IVec2::new(x, -1));
+    }
+    harness.tick();
+    harness.tick();
+
+    assert_eq!(
+        harness.rejections(EntType::HOUSE, IVec2::new(11, 0), Rotation::default()),
+        [Rejection::Blocked]
+    );
+    MoveBuilding {
+        to: IVec2::new(11, 0),
+        rotation: Rotation::default(),
+    }
+    .apply(house, &mut harness.app.world);
+    assert!(harness.app.world.get_entity(house).is_none());
+    assert_eq!(harness.count::<Placeholder>(), 1);
+}
+
+#[test]
+fn cancelled_construction_gives_back_everything() {
+    let mut harness = Harness::new();
+    let base = harness.spawn(EntType::BASE, IVec2::new(0, 0));
+    let builder = harness.spawn(EntType::BUILDER, IVec2::new(6, 0));
+    harness.tick();
+    let stock = Resources::new(60, 40, 0);
+    harness.get_mut::<Storage>(base).unwrap().current = stock;
+    harness.app.world.resource_mut::<Money>().0 = stock;
+
+    let placeholder = harness.place(EntType::STORAGE, IVec2::new(10, 0));
+    harness.run_until(2000, |world| {
+        let needs = world.get::<NeedsResource>(placeholder).unwrap();
+        needs.0 != needs.1
+    });
+    CancelPlaceholder.apply(placeholder, &mut harness.app.world);
+    assert!(harness.app.world.get_entity(placeholder).is_none());
+    assert_eq!(harness.money(), stock);
+    let carried = harness.get::<Inventory>(builder).unwrap().current;
+    assert_eq!(
+        harness.get::<Storage>(base).unwrap().current + carried,
+        stock
+    );
+    assert!(!harness.has::<Claim>(builder));
+}
+
+#[test]
+fn cancelled_upgrade_goes_back_to_the_previous_level() {
+    let mut harness = Harness::new();
+    harness.spawn(EntType::BASE, IVec2::new(0, 0));
+    let house = harness.spawn(EntType::HOUSE, IVec2::new(10, 0));
+    harness.tick();
+    let money = harness.money();
+
+    harness.upgrade::<ProvidePopulation>(house);
+    CancelBuildingUpgrade::<ProvidePopulation>(PhantomData).apply(house, &mut harness.app.world);
+    assert_eq!(harness.money(), money);
+    assert!(!harness.has::<NeedsResource>(house));
+    let level = |harness: &Harness| {
+        harness
+            .get::<BuildingUpgradeComponent<ProvidePopulation>>(house)
+            .unwrap()
+            .current_level
+    };
+    assert_eq!(level(&harness), 0);
+
+    // Nothing to cancel once it is done
+    harness.upgrade::<ProvidePopulation>(house);
+    harness
+        .app
+        .world
+        .entity_mut(house)
+        .get_mut::<NeedsResource>()
+        .unwrap()
+        .0 = Resources::ZERO;
+    harness.tick();
+    CancelBuildingUpgrade::<ProvidePopulation>(PhantomData).apply(house, &mut harness.app.world);
+    assert_eq!(level(&harness), 1);
+    let cost = harness
+        .app
+        .world
+        .resource::<EntDefs>()
+        .get(EntType::HOUSE)
+        .unwrap()
+        .level_cost(1)
+        .unwrap();
+    assert_eq!(harness.money(), money - cost);
+}
+
+#[test]
+fn dragged_roads_go_around_what_is_in_the_way() {
+    let mut harness = Harness::new();
+    harness.spawn(EntType::ROAD, IVec2::new(0, 0));
+    harness.spawn_harvestable(IVec2::new(2, 0), ResourceKind::Wood, 10);
+    harness.tick();
+    harness.tick();
+
+    let mut placement = SystemState::<Placement>::new(&mut harness.app.world);
+    let placement = placement.get(&harness.app.world);
+    let cells = game::road_cells(&placement, IVec2::new(0, 0), IVec2::new(3, 2), false);
+    assert_eq!(
+        cells,
+        [(0, 0), (0, 1), (0, 2), (1, 2), (2, 2), (3, 2)].map(IVec2::from)
+    );
+    // Nothing to connect to
+    let cells = game::road_cells(&placement, IVec2::new(5, 5), IVec2::new(7, 5), false);
+    assert!(game::plan_roads(&placement, &cells)
+        .iter()
+        .all(|(_, rejections)| rejections == &[Rejection::NoRoad]));
+    // Dragging far away stops at the reach
+    let cells = game::road_cells(&placement, IVec2::ZERO, IVec2::new(1000, 0), false);
+    assert_eq!(cells.last(), Some(&IVec2::new(game::MAX_ROAD_LINE, 0)));
+    let cells = game::road_cells(&placement, IVec2::ZERO, IVec2::splat(-1000), true);
+    assert_eq!(cells.len(), (game::MAX_ROAD_AREA as usize + 1).pow(2));
+
+    harness.app.world.resource_mut::<Money>().0 = Resources::new(0, 4, 0);
+    PlaceRoads {
+        from: IVec2::new(0, 0),
+        to: IVec2::new(3, 2),
+        area: false,
+    }
+    .apply(&mut harness.app.world);
+    harness.tick();
+    // Only had money for four of the five
+    assert_eq!(harness.money(), Resources::ZERO);
+    let tile_map = harness.app.world.resource::<TileMap>();
+    assert!([(0, 1), (0, 2), (1, 2), (2, 2)]
+        .into_iter()
+        .all(|cell| tile_map.has(IVec2::from(cell), TileFlag::PlannedRoad)));
+    assert!(!tile_map.has(IVec2::new(3, 2), TileFlag::PlannedRoad));
+}
+
+#[test]
+fn dragged_roads_follow_the_road_rules() {
+    let mut harness = Harness::new();
+    let mut road = harness
+        .app
+        .world
+        .resource::<EntDefs>()
+        .get(EntType::ROAD)
+        .unwrap()
+        .clone();
+    road.rules = vec![PlacementRule::AwayFrom(EntType::HOUSE, 3)];
+    harness
+        .app
+        .world
+        .resource_mut::<EntDefs>()
+        .insert(EntType::ROAD, road);
+    harness.spawn(EntType::ROAD, IVec2::new(0, 0));
+    harness.spawn(EntType::HOUSE, IVec2::new(5, 3));
+    harness.tick();
+    harness.tick();
+
+    let mut placement = SystemState::<Placement>::new(&mut harness.app.world);
+    let placement = placement.get(&harness.app.world);
+    let cells = game::road_cells(&placement, IVec2::new(0, 0), IVec2::new(3, 0), false);
+    let plan = game::plan_roads(&placement, &cells);
+    let too_close = Rejection::TooCloseTo(EntType::HOUSE, 3);
+    assert_eq!(
+        plan,
+        [
+            (IVec2::new(1, 0), vec![]),
+            (IVec2::new(2, 0), vec![too_close]),
+            (IVec2::new(3, 0), vec![too_close, Rejection::NoRoad]),
+        ]
+    );
+
+    PlaceRoads {
+        from: IVec2::new(0, 0),
+        to: IVec2::new(3, 0),
+        area: false,
+    }
+    .apply(&mut harness.app.world);
+    assert_eq!(harness.count::<Placeholder>(), 1);
+}
+
+#[test]
+fn dragged_road_area_is_filled_from_the_start() {
+    let mut harness = Harness::new();
+    harness.spawn(EntType::ROAD, IVec2::new(0, 0));
+    harness.tick();
+    harness.tick();
+    harness.app.world.resource_mut::<Money>().0 = Resources::new(0, 100, 0);
+
+    PlaceRoads {
+        from: IVec2::new(1, 0),
+        to: IVec2::new(3, 2),
+        area: true,
+    }
+    .apply(&mut harness.app.world);
+    assert_eq!(harness.count::<Placeholder>(), 9);
+    assert_eq!(harness.money(), Resources::new(0, 91, 0));
+}
+
+#[test]
+fn rotated_buildings_cover_their_footprint_and_face_their_entrances() {
+    let rotation = Rotation(1);
+    assert_eq!(rotation.size(IVec2::new(4, 3)), IVec2::new(3, 4));
+    assert_eq!(
+        rotation.tile(IVec2::ZERO, IVec2::new(4, 3)),
+        IVec2::new(2, 0)
+    );
+    assert_eq!(
+        Rotation(4).tile(IVec2::new(1, 2), IVec2::new(4, 3)),
+        IVec2::new(1, 2)
+    );
+
+    let mut harness = Harness::new();
+    let mut house = harness
+        .app
+        .world
+        .resource::<EntDefs>()
+        .get(EntType::HOUSE)
+        .unwrap()
+        .clone();
+    house.entrances = vec![(0, -1)];
+    harness
+        .app
+        .world
+        .resource_mut::<EntDefs>()
+        .insert(EntType::HOUSE, house);
+    // Storages are L-shaped
+    harness
+        .app
+        .world
+        .spawn((Pos(IVec2::ZERO), Rotation(2), EntType::STORAGE));
+    harness.spawn(EntType::ROAD, IVec2::new(10, -1));
+    harness.tick();
+    harness.tick();
+
+    let tile_map = harness.app.world.resource::<TileMap>();
+    assert!(tile_map.is_blocking(IVec2::new(3, 2)));
+    assert!(!tile_map.is_blocking(IVec2::new(0, 0)));
+    assert!(!tile_map.is_blocking(IVec2::new(1, 0)));
+    assert!(tile_map.is_blocking(IVec2::new(2, 0)));
+
+    assert_eq!(
+        harness.rejections(EntType::HOUSE, IVec2::new(10, 0), Rotation(0)),
+        []
+    );
+    // The entrance would be on the other side
+    assert_eq!(
+        harness.rejections(EntType::HOUSE, IVec2::new(10, 0), Rotation(2)),
+        [Rejection::NoEntrance]
+    );
+}
+
+#[test]
+fn storage_faces_a_road_with_its_entrance() {
+    let mut harness = Harness::new();
+    harness.spawn(EntType::ROAD, IVec2::new(10, -1));
+    harness.tick();
+    harness.tick();
+    let rejections = harness.rejections(EntType::STORAGE, IVec2::new(10, 0), Rotation::default());
+    assert_eq!(rejections, [Rejection::NoEntrance]);
+
+    harness.spawn(EntType::ROAD, IVec2::new(11, -1));
+    harness.tick();
+    harness.tick();
+    let rejections = harness.rejections(EntType::STORAGE, IVec2::new(10, 0), Rotation::default());
+    assert!(rejections.is_empty());
+}
+
+#[test]
+fn placement_rules_give_every_reason_a_spot_is_rejected() {
+    let mut harness = Harness::new();
+    let mut house = harness
+        .app
+        .world
+        .resource::<EntDefs>()
+        .get(EntType::HOUSE)
+        .unwrap()
+        .clone();
+    house.rules = vec![
+        PlacementRule::MaxCount(1),
+        PlacementRule::Near(EntType::BASE, 5),
+        PlacementRule::AwayFrom(EntType::STORAGE, 3),
+    ];
+    harness
+        .app
+        .world
+        .resource_mut::<EntDefs>()
+        .insert(EntType::HOUSE, house);
+    harness.spawn(EntType::BASE, IVec2::ZERO);
+    harness.spawn(EntType::STORAGE, IVec2::new(12, 0));
+    for x in 5..20 {
+        harness.spawn(EntType::ROAD, IVec2::new(x, -1));
+    }
+    harness.tick();
+    harness.tick();
+
+    assert_eq!(
+        harness.rejections(EntType::HOUSE, IVec2::new(7, 0), Rotation::default()),
+        []
+    );
+    assert_eq!(
+        harness.rejections(EntType::HOUSE, IVec2::new(12, -1), Rotation::default()),
+        [
+            Rejection::OnRoad,
+            Rejection::Blocked,
+            Rejection::NotNear(EntType::BASE, 5),
+            Rejection::TooCloseTo(EntType::STORAGE, 3),
+        ]
+    );
+
+    harness.place(EntType::HOUSE, IVec2::new(7, 0));
+    assert_eq!(
+        harness.rejections(EntType::HOUSE, IVec2::new(7, 0), Rotation::default()),
+        [Rejection::Blocked, Rejection::TooMany(1)]
+    );
+}
+
+#[test]
+fn placement_rules_measure_the_footprint_not_its_bounding_box() {
+    let mut harness = Harness::new();
+    let mut house = harness
+        .app
+        .world
+        .resource::<EntDefs>()
+        .get(EntType::HOUSE)
+        .unwrap()
+        .clone();
+    house.rules = vec![PlacementRule::AwayFrom(EntType::STORAGE, 1)];
+    harness
+        .app
+        .world
+        .resource_mut::<EntDefs>()
+        .insert(EntType::HOUSE, house);
+    // Its top right corner at (12, 2) and (13, 2) is left out
+    harness.spawn(EntType::STORAGE, IVec2::new(10, 0));
+    harness.tick();
+    harness.tick();
+
+    let too_close = Rejection::TooCloseTo(EntType::STORAGE, 1);
+    assert!(harness
+        .rejections(EntType::HOUSE, IVec2::new(11, 3), Rotation::default())
+        .contains(&too_close));
+    assert!(!harness
+        .rejections(EntType::HOUSE, IVec2::new(13, 3), Rotation::default())
+        .contains(&too_close));
+}
+
+#[test]
+fn placing_is_undone_until_builders_bring_something() {
+    let mut harness = Harness::new();
+    harness.spawn(EntType::ROAD, IVec2::new(10, -1));
+    harness.tick();
+    harness.tick();
+    let money = harness.money();
+    let cost = Resources::new(10, 0, 0);
+
+    Perform(Action::Place(vec![Site {
+        entity: Entity::PLACEHOLDER,
+        ent_type: EntType::HOUSE,
+        pos: IVec2::new(10, 0),
+        rotation: Rotation::default(),
+    }]))
+    .apply(&mut harness.app.world);
+    assert_eq!(harness.money(), money - cost);
+    assert_eq!(harness.count::<Placeholder>(), 1);
+
+    Undo.apply(&mut harness.app.world);
+    assert_eq!(harness.money(), money);
+    assert_eq!(harness.count::<Placeholder>(), 0);
+    harness.tick();
+
+    Redo.apply(&mut harness.app.world);
+    assert_eq!(harness.money(), money - cost);
+    let placeholder = harness
+        .app
+        .world
+        .query_filtered::<Entity, With<Placeholder>>()
+        .single(&harness.app.world);
+
+    // A builder brought the first wood
+    harness.get_mut::<NeedsResource>(placeholder).unwrap().0 = Resources::new(9, 0, 0);
+    Undo.apply(&mut harness.app.world);
+    assert_eq!(harness.money(), money - cost);
+    assert!(harness.app.world.get_entity(placeholder).is_some());
+}
+
+#[test]
+fn ents_without_a_cost_are_not_placed() {
+    let mut harness = Harness::new();
+    harness.spawn(EntType::ROAD, IVec2::new(10, -1));
+    harness.tick();
+    harness.tick();
+    let money = harness.money();
+
+    Perform(Action::Place(vec![Site {
+        entity: Entity::PLACEHOLDER,
+        ent_type: EntType::BASE,
+        pos: IVec2::new(10, 0),
+        rotation: Rotation::default(),
+    }]))
+    .apply(&mut harness.app.world);
+    assert_eq!(harness.count::<Placeholder>(), 0);
+    assert_eq!(harness.money(), money);
+}
+
+#[test]
+fn undone_demolish_puts_the_building_and_its_contents_back() {
+    let mut harness = Harness::new();
+    let base = harness.spawn(EntType::BASE, IVec2::new(0, 0));
+    let storage = harness.spawn(EntType::STORAGE, IVec2::new(10, 0));
+    let house = harness.spawn(EntType::HOUSE, IVec2::new(0, 10));
+    harness.spawn(EntType::ROAD, IVec2::new(11, -1));
+    harness.spawn(EntType::ROAD, IVec2::new(0, 9));
+    harness.run_until(100, |world| count_ent_type(world, EntType::HARVESTER) == 5);
+    let stored = Resources::new(5, 5, 0);
+    harness.get_mut::<Storage>(storage).unwrap().current = stored;
+    harness.get_mut::<Storage>(base).unwrap().current = Resources::ZERO;
+    let money = harness.money();
+    let demolish = |entity, ent_type, pos| {
+        Perform(Action::Demolish(
+            Site {
+                entity,
+                ent_type,
+                pos,
+                rotation: Rotation::default(),
+            },
+            Resources::ZERO,
+        ))
+    };
+
+    demolish(storage, EntType::STORAGE, IVec2::new(10, 0)).apply(&mut harness.app.world);
+    let refund = game::demolish_refund(harness.cost(EntType::STORAGE));
+    assert_eq!(harness.money(), money + refund);
+    assert_eq!(
+        harness.get::<Storage>(base).unwrap().current,
+        refund + stored
+    );
+
+    Undo.apply(&mut harness.app.world);
+    harness.tick();
+    assert_eq!(harness.money(), money);
+    assert_eq!(
+        harness.get::<Storage>(base).unwrap().current,
+        Resources::ZERO
+    );
+    let (storage, current) = harness
+        .app
+        .world
+        .query::<(Entity, &EntType, &Storage)>()
+        .iter(&harness.app.world)
+        .find(|&(_, &ent_type, _)| ent_type == EntType::STORAGE)
+        .map(|(entity, _, storage)| (entity, storage.current))
+        .unwrap();
+    assert_eq!(current, stored);
+
+    // The refund was spent meanwhile
+    Redo.apply(&mut harness.app.world);
+    assert_eq!(harness.count_ent_type(EntType::STORAGE), 0);
+    harness.get_mut::<Storage>(base).unwrap().current = Resources::ZERO;
+    Undo.apply(&mut harness.app.world);
+    assert_eq!(harness.count_ent_type(EntType::STORAGE), 0);
+    assert!(harness.app.world.get_entity(storage).is_none());
+
+    // Its crabs moved out, putting it back doesn't bring new ones
+    demolish(house, EntType::HOUSE, IVec2::new(0, 10)).apply(&mut harness.app.world);
+    Undo.apply(&mut harness.app.world);
+    assert_eq!(harness.count_ent_type(EntType::HOUSE), 1);
+    for _ in 0..20 {
+        harness.tick();
+    }
+    assert_eq!(harness.count_ent_type(EntType::HARVESTER), 5);
+}
+
+#[test]
+fn redone_placing_follows_the_placement_rules() {
+    let mut harness = Harness::new();
+    let mut house = harness
+        .app
+        .world
+        .resource::<EntDefs>()
+        .get(EntType::HOUSE)
+        .unwrap()
+        .clone();
+    house.rules = vec![PlacementRule::MaxCount(1)];
+    harness
+        .app
+        .world
+        .resource_mut::<EntDefs>()
+        .insert(EntType::HOUSE, house);
+    for x in 9..20 {
+        harness.spawn(EntType::ROAD, IVec2::new(x, -1));
+    }
+    harness.tick();
+    harness.tick();
+
+    Perform(Action::Place(vec![Site {
+        entity: Entity::PLACEHOLDER,
+        ent_type: EntType::HOUSE,
+        pos: IVec2::new(10, 0),
+        rotation: Rotation::default(),
+    }]))
+    .apply(&mut harness.app.world);
+    Undo.apply(&mut harness.app.world);
+    harness.place(EntType::HOUSE, IVec2::new(15, 0));
+    let money_left = harness.money();
+
+    // Nothing is in the way, but only one house can be built
+    Redo.apply(&mut harness.app.world);
+    assert_eq!(harness.count::<Placeholder>(), 1);
+    assert_eq!(harness.money(), money_left);
+}
+
+#[test]
+fn terrain_decides_where_crabs_walk_and_build() {
+    let mut harness = Harness::new();
+    let mut house = harness
+        .app
+        .world
+        .resource::<EntDefs>()
+        .get(EntType::HOUSE)
+        .unwrap()
+        .clone();
+    house.rules = vec![PlacementRule::NotOn(Terrain::Rock)];
+    harness
+        .app
+        .world
+        .resource_mut::<EntDefs>()
+        .insert(EntType::HOUSE, house);
+    // Water wall at x = 10 from y = 0 to 20, rock band further right up to y = 8
+    let elevation = |pos: IVec2| match pos {
+        IVec2 { x: 10, y } if y < 20 => -1.0,
+        IVec2 { x: 20..=29, y } if y < 8 => 1.0,
+        _ => 0.0,
+    };
+    harness
+        .app
+        .world
+        .resource_mut::<TileMap>()
+        .terrain_mut()
+        .insert(IVec2::ZERO, TerrainChunk::generate(IVec2::ZERO, elevation));
+    let tile_map = harness.app.world.resource::<TileMap>();
+    assert_eq!(tile_map.terrain().get(IVec2::new(10, 5)), Terrain::Water);
+    assert!(tile_map.is_blocking(IVec2::new(10, 5)));
+    assert!(
+        pathfind::step_cost(false, false, Terrain::Rock)
+            > pathfind::step_cost(false, false, Terrain::Fertile)
+    );
+
+    let rejections = harness.rejections(EntType::HOUSE, IVec2::new(9, 5), Rotation::default());
+    assert!(rejections.contains(&Rejection::On(Terrain::Water)));
+    assert!(!rejections.contains(&Rejection::Blocked));
+    let rejections = harness.rejections(EntType::HOUSE, IVec2::new(22, 2), Rotation::default());
+    assert!(rejections.contains(&Rejection::On(Terrain::Rock)));
+    assert!(!rejections.contains(&Rejection::On(Terrain::Water)));
+
+    let mut state = SystemState::<PathQuery>::new(&mut harness.app.world);
+    let mut path = |from, to| {
+        state
+            .get_mut(&mut harness.app.world)
+            .path(from, &PathTarget::Tiles(vec![to]))
+            .unwrap()
+    };
+    // Around the water since there is no way through
+    let around_water = path(IVec2::new(5, 5), IVec2::new(15, 5));
+    // Around the rock since it is shorter than walking over it
+    let around_rock = path(IVec2::new(15, 5), IVec2::new(35, 5));
+    let tile_map = harness.app.world.resource::<TileMap>();
+    assert!(around_water.len() > 10);
+    assert!(around_water
+        .iter()
+        .chain(&around_rock)
+        .all(|&pos| tile_map.terrain().get(pos) == Terrain::Fertile));
+}
@@ -1,83 +1,384 @@
 use bevy::{
+    ecs::system::{EntityCommand, SystemParam},
     prelude::*,
-    utils::{HashMap, HashSet},
+    utils::HashMap,
+};
+use serde::{Deserialize, Serialize};
+use smallvec::SmallVec;
+
+use crate::{
+    chunks::CHUNK_SIZE,
+    game::{BlockingGhost, GhostRoad, Harvestable, Road},
+    pathfind::Blocking,
+    terrain::TerrainMap,
 };
 
 pub struct Plugin;
 
 impl bevy::app::Plugin for Plugin {
     fn build(&self, app: &mut App) {
-        app.insert_resource(TileMap {
-            entities_by_tile: default(),
-            prev: default(),
-        });
-        app.add_systems(PreUpdate, update_tile_map);
+        app.init_resource::<TileMap>();
+        // Ents move during simulation ticks, possibly several of them per frame
+        app.add_systems(FixedUpdate, update_tile_map);
+        app.add_systems(Last, keep_removals);
     }
 }
 
 #[derive(Component)]
 pub struct Pos(pub IVec2);
 
+/// Bounding box of the footprint, already rotated
 #[derive(Component)]
 pub struct Size(pub IVec2);
 
-#[derive(Resource)]
+/// Tiles of the footprint relative to [Pos], for ents that don't cover their whole [Size]
+#[derive(Component, Debug, Clone, PartialEq, Eq)]
+pub struct Shape(pub Vec<IVec2>);
+
+/// Quarter turns counterclockwise, seen from above
+#[derive(Component, Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Rotation(pub u8);
+
+impl Rotation {
+    pub fn rotated(self) -> Self {
+        Self((self.0 + 1) % 4)
+    }
+
+    /// Size of a footprint after rotating it
+    pub fn size(self, size: IVec2) -> IVec2 {
+        if self.0.is_multiple_of(2) {
+            size
+        } else {
+            size.yx()
+        }
+    }
+
+    /// Where a tile of a footprint with the size ends up, works for tiles outside of it too
+    pub fn tile(self, mut tile: IVec2, mut size: IVec2) -> IVec2 {
+        for _ in 0..self.0 % 4 {
+            tile = IVec2::new(size.y - 1 - tile.y, tile.x);
+            size = size.yx();
+        }
+        tile
+    }
+
+    pub fn quat(self) -> Quat {
+        Quat::from_rotation_y(-std::f32::consts::FRAC_PI_2 * self.0 as f32)
+    }
+}
+
+/// Every tile the ent covers
+pub fn footprint<'a>(
+    pos: IVec2,
+    size: Option<&Size>,
+    shape: Option<&'a Shape>,
+) -> impl Iterator<Item = IVec2> + 'a {
+    let size = size.map_or(IVec2::ONE, |size| size.0);
+    (0..size.x)
+        .flat_map(move |x| (0..size.y).map(move |y| IVec2::new(x, y)))
+        .filter(move |tile| shape.is_none_or(|shape| shape.0.contains(tile)))
+        .map(move |tile| pos + tile)
+}
+
+/// What is on a tile, kept per tile so checks don't have to look at every entity there
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TileFlag {
+    Blocking,
+    Road,
+    Harvestable,
+    /// Placeholder of a building crabs can't walk through once built
+    PlannedBlocking,
+    PlannedRoad,
+}
+
+const FLAGS: usize = 5;
+
+/// Entities by tile, stored in dense chunks the same size as generated ones, and the terrain
+#[derive(Resource, Default)]
 pub struct TileMap {
-    entities_by_tile: HashMap<IVec2, HashSet<Entity>>,
-    prev: HashMap<Entity, (IVec2, IVec2)>,
+    chunks: HashMap<IVec2, Chunk>,
+    prev: HashMap<Entity, Footprint>,
+    terrain: TerrainMap,
+    /// Entities that lost something we track since the last update
+    removed: Vec<Entity>,
+    /// Tiles whose flags changed, see [TileMap::changed_tiles]
+    changed: Vec<IVec2>,
+    /// How many of them the last update reported, the rest came from [UpdateTiles] since
+    reported: usize,
+}
+
+struct Chunk {
+    cells: Box<[Cell]>,
+}
+
+#[derive(Default)]
+struct Cell {
+    entities: SmallVec<[Entity; 2]>,
+    /// How many of the entities have each flag
+    flags: [u16; FLAGS],
+}
+
+#[derive(PartialEq)]
+struct Footprint {
+    tiles: Vec<IVec2>,
+    flags: [bool; FLAGS],
+}
+
+/// What the tile map looks at on an ent
+type Tracked<'a> = (
+    &'a Pos,
+    Option<&'a Size>,
+    Option<&'a Shape>,
+    bool,
+    bool,
+    bool,
+    bool,
+    bool,
+);
+
+impl Footprint {
+    fn new(
+        (pos, size, shape, blocking, road, harvestable, blocking_ghost, ghost_road): Tracked,
+    ) -> Self {
+        let mut flags = [false; FLAGS];
+        flags[TileFlag::Blocking as usize] = blocking;
+        flags[TileFlag::Road as usize] = road;
+        flags[TileFlag::Harvestable as usize] = harvestable;
+        flags[TileFlag::PlannedBlocking as usize] = blocking_ghost;
+        flags[TileFlag::PlannedRoad as usize] = ghost_road;
+        Self {
+            tiles: footprint(pos.0, size, shape).collect(),
+            flags,
+        }
+    }
+}
+
+fn chunk_and_index(pos: IVec2) -> (IVec2, usize) {
+    let chunk = pos.div_euclid(IVec2::splat(CHUNK_SIZE));
+    let local = pos - chunk * CHUNK_SIZE;
+    (chunk, (local.y * CHUNK_SIZE + local.x) as usize)
 }
 
 impl TileMap {
+    fn cell(&self, pos: IVec2) -> Option<&Cell> {
+        let (chunk, index) = chunk_and_index(pos);
+        self.chunks.get(&chunk).map(|chunk| &chunk.cells[index])
+    }
+
+    fn cell_mut(&mut self, pos: IVec2) -> &mut Cell {
+        let (chunk, index) = chunk_and_index(pos);
+        let chunk = self.chunks.entry(chunk).or_insert_with(|| Chunk {
+            cells: (0..CHUNK_SIZE * CHUNK_SIZE).map(|_| default()).collect(),
+        });
+        &mut chunk.cells[index]
+    }
+
     pub fn entities_at(&self, pos: IVec2) -> impl Iterator<Item = Entity> + '_ {
-        self.entities_by_tile
-            .get(&pos)
+        self.cell(pos)
             .into_iter()
-            .flatten()
-            .copied()
+            .flat_map(|cell| cell.entities.iter().copied())
     }
-}
 
-fn update_tile_map(
-    q: Query<(Entity, &Pos, Option<&Size>), Or<(Changed<Pos>, Changed<Size>)>>,
-    mut tile_map: ResMut<TileMap>,
-    mut removed: RemovedComponents<Pos>,
-) {
-    let tile_map = &mut *tile_map;
-    for entity in removed.read() {
-        if let Some((prev_pos, prev_size)) = tile_map.prev.remove(&entity) {
-            for dx in 0..prev_size.x {
-                for dy in 0..prev_size.y {
-                    tile_map
-                        .entities_by_tile
-                        .get_mut(&(prev_pos + IVec2::new(dx, dy)))
-                        .unwrap()
-                        .remove(&entity);
-                }
-            }
+    /// Tiles from `rect.min` up to, not including, `rect.max`.
+    /// Entities covering several of them come once per tile.
+    pub fn entities_in_rect(&self, rect: IRect) -> impl Iterator<Item = Entity> + '_ {
+        let min_chunk = rect.min.div_euclid(IVec2::splat(CHUNK_SIZE));
+        let max_chunk = (rect.max - IVec2::ONE).div_euclid(IVec2::splat(CHUNK_SIZE));
+        (min_chunk.x..=max_chunk.x)
+            .flat_map(move |x| (min_chunk.y..=max_chunk.y).map(move |y| IVec2::new(x, y)))
+            .filter_map(|chunk_pos| Some((chunk_pos, self.chunks.get(&chunk_pos)?)))
+            .flat_map(move |(chunk_pos, chunk)| {
+                let origin = chunk_pos * CHUNK_SIZE;
+                let min = (rect.min - origin).max(IVec2::ZERO);
+                let max = (rect.max - origin).min(IVec2::splat(CHUNK_SIZE));
+                (min.y..max.y).flat_map(move |y| {
+                    (min.x..max.x).flat_map(move |x| {
+                        chunk.cells[(y * CHUNK_SIZE + x) as usize]
+                            .entities
+                            .iter()
+                            .copied()
+                    })
+                })
+            })
+    }
+
+    pub fn has(&self, pos: IVec2, flag: TileFlag) -> bool {
+        self.cell(pos)
+            .is_some_and(|cell| cell.flags[flag as usize] != 0)
+    }
+
+    /// Same as [TileMap::has] if `ignored` wasn't there, like a building being moved
+    pub fn has_other_than(&self, pos: IVec2, flag: TileFlag, ignored: Option<Entity>) -> bool {
+        self.cell(pos).is_some_and(|cell| {
+            let own = ignored
+                .filter(|entity| cell.entities.contains(entity))
+                .and_then(|entity| self.prev.get(&entity))
+                .is_some_and(|footprint| footprint.flags[flag as usize]);
+            cell.flags[flag as usize] > own as u16
+        })
+    }
+
+    /// Something is in the way or there is water
+    pub fn is_blocking(&self, pos: IVec2) -> bool {
+        self.has(pos, TileFlag::Blocking) || !self.terrain.get(pos).is_walkable()
+    }
+
+    pub fn is_road(&self, pos: IVec2) -> bool {
+        self.has(pos, TileFlag::Road)
+    }
+
+    pub fn is_harvestable(&self, pos: IVec2) -> bool {
+        self.has(pos, TileFlag::Harvestable)
+    }
+
+    pub fn terrain(&self) -> &TerrainMap {
+        &self.terrain
+    }
+
+    pub fn terrain_mut(&mut self) -> &mut TerrainMap {
+        &mut self.terrain
+    }
+
+    /// Tiles where something blocking, a road or a harvestable appeared or went away
+    /// since the previous update, for systems running after [update_tile_map] every tick
+    pub fn changed_tiles(&self) -> &[IVec2] {
+        &self.changed
+    }
+
+    /// `None` if the entity is gone or no longer has a [Pos]
+    fn update(&mut self, entity: Entity, footprint: Option<Footprint>) {
+        if self.prev.get(&entity) == footprint.as_ref() {
+            return;
+        }
+        self.remove(entity);
+        if let Some(footprint) = footprint {
+            self.insert(entity, footprint);
         }
     }
-    for (entity, pos, size) in q.iter() {
-        let size = size.map_or(IVec2::splat(1), |size| size.0);
-        if let Some(&(prev_pos, prev_size)) = tile_map.prev.get(&entity) {
-            for dx in 0..prev_size.x {
-                for dy in 0..prev_size.y {
-                    tile_map
-                        .entities_by_tile
-                        .get_mut(&(prev_pos + IVec2::new(dx, dy)))
-                        .unwrap()
-                        .remove(&entity);
-                }
+
+    fn remove(&mut self, entity: Entity) {
+        let Some(footprint) = self.prev.remove(&entity) else {
+            return;
+        };
+        if footprint.flags.contains(&true) {
+            self.changed.extend(&footprint.tiles);
+        }
+        for &pos in &footprint.tiles {
+            let cell = self.cell_mut(pos);
+            if let Some(index) = cell.entities.iter().position(|&e| e == entity) {
+                cell.entities.swap_remove(index);
             }
+            for (count, has) in cell.flags.iter_mut().zip(footprint.flags) {
+                *count -= has as u16;
+            }
+        }
+    }
+
+    fn insert(&mut self, entity: Entity, footprint: Footprint) {
+        if footprint.flags.contains(&true) {
+            self.changed.extend(&footprint.tiles);
         }
-        for dx in 0..size.x {
-            for dy in 0..size.y {
-                tile_map
-                    .entities_by_tile
-                    .entry(pos.0 + IVec2::new(dx, dy))
-                    .or_default()
-                    .insert(entity);
+        for &pos in &footprint.tiles {
+            let cell = self.cell_mut(pos);
+            cell.entities.push(entity);
+            for (count, has) in cell.flags.iter_mut().zip(footprint.flags) {
+                *count += has as u16;
             }
         }
-        tile_map.prev.insert(entity, (pos.0, size));
+        self.prev.insert(entity, footprint);
+    }
+}
+
+/// Entities that lost something the tile map looks at
+#[derive(SystemParam)]
+pub struct Removals<'w, 's> {
+    pos: RemovedComponents<'w, 's, Pos>,
+    blocking: RemovedComponents<'w, 's, Blocking>,
+    roads: RemovedComponents<'w, 's, Road>,
+    harvestables: RemovedComponents<'w, 's, Harvestable>,
+    blocking_ghosts: RemovedComponents<'w, 's, BlockingGhost>,
+    ghost_roads: RemovedComponents<'w, 's, GhostRoad>,
+}
+
+impl Removals<'_, '_> {
+    fn read(&mut self) -> impl Iterator<Item = Entity> + '_ {
+        self.pos
+            .read()
+            .chain(self.blocking.read())
+            .chain(self.roads.read())
+            .chain(self.harvestables.read())
+            .chain(self.blocking_ghosts.read())
+            .chain(self.ghost_roads.read())
+    }
+}
+
+/// Removals are only reported for two frames and there can be more frames than that between ticks,
+/// so every frame we keep them for the next update
+fn keep_removals(mut removals: Removals, mut tile_map: ResMut<TileMap>) {
+    tile_map.removed.extend(removals.read());
+}
+
+pub fn update_tile_map(
+    ents: Query<(
+        &Pos,
+        Option<&Size>,
+        Option<&Shape>,
+        Has<Blocking>,
+        Has<Road>,
+        Has<Harvestable>,
+        Has<BlockingGhost>,
+        Has<GhostRoad>,
+    )>,
+    changed: Query<
+        Entity,
+        (
+            With<Pos>,
+            Or<(
+                Changed<Pos>,
+                Changed<Size>,
+                Changed<Shape>,
+                Added<Blocking>,
+                Added<Road>,
+                Added<Harvestable>,
+                Added<BlockingGhost>,
+                Added<GhostRoad>,
+            )>,
+        ),
+    >,
+    mut tile_map: ResMut<TileMap>,
+    mut removals: Removals,
+) {
+    let reported = tile_map.reported;
+    tile_map.changed.drain(..reported);
+    // Removals from earlier ticks of this frame are not kept yet.
+    // Kept ones may be read again, updating an entity twice changes nothing.
+    let mut updated = std::mem::take(&mut tile_map.removed);
+    updated.extend(removals.read().chain(changed.iter()));
+    for entity in updated {
+        let footprint = ents.get(entity).ok().map(Footprint::new);
+        tile_map.update(entity, footprint);
+    }
+    tile_map.reported = tile_map.changed.len();
+}
+
+/// Brings the tile map up to date with the entity right away instead of on the next tick,
+/// so what the player places or removes counts while the game is paused too
+pub struct UpdateTiles;
+
+impl EntityCommand for UpdateTiles {
+    fn apply(self, id: Entity, world: &mut World) {
+        let footprint = world.get_entity(id).and_then(|entity| {
+            Some(Footprint::new((
+                entity.get::<Pos>()?,
+                entity.get::<Size>(),
+                entity.get::<Shape>(),
+                entity.contains::<Blocking>(),
+                entity.contains::<Road>(),
+                entity.contains::<Harvestable>(),
+                entity.contains::<BlockingGhost>(),
+                entity.contains::<GhostRoad>(),
+            )))
+        });
+        world.resource_mut::<TileMap>().update(id, footprint);
     }
 }
@@ -0,0 +1,289 @@
+//! What a crab is doing. Systems change it with [UnitStateCommands::transition] instead of
+//! inserting and removing markers themselves, so two systems can't both move a crab out of the same state.
+//!
+//! Marker components like [Harvesting] still exist for queries and flow fields,
+//! they are kept in sync with [UnitState] here and nowhere else.
+
+use std::marker::PhantomData;
+
+use bevy::{
+    ecs::system::{EntityCommand, EntityCommands},
+    prelude::*,
+    utils::HashSet,
+};
+
+use crate::{
+    game::EntType,
+    resource_kind::{self, Kind, ResourceKind},
+};
+
+pub struct Plugin;
+
+impl bevy::app::Plugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<UnitStateChanged>();
+    }
+}
+
+/// Labels over crabs showing their state, toggled with F3
+pub struct DebugViewPlugin;
+
+impl bevy::app::Plugin for DebugViewPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ShowUnitStates>();
+        app.add_systems(Update, (toggle_state_labels, update_state_labels).chain());
+    }
+}
+
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnitState {
+    Harvesting,
+    /// Bringing what was harvested, or what a builder could not deliver, to a storage
+    Storing,
+    /// Builder deciding what to go for next
+    ChoosingResource,
+    /// Builder going to a storage for the resource
+    Taking(ResourceKind),
+    /// Builder delivering the resource to a construction site
+    Bringing(ResourceKind),
+    /// Walking to an upgrade building to become the ent type
+    GoingForUpgrade(EntType),
+}
+
+impl UnitState {
+    /// Every change a crab is allowed to make
+    pub fn can_become(self, to: Self) -> bool {
+        use UnitState::*;
+        match (self, to) {
+            (Harvesting, Storing) | (Storing, Harvesting) => true,
+            (Harvesting | Storing, GoingForUpgrade(_)) => true,
+            (GoingForUpgrade(_), Harvesting) => true,
+            (ChoosingResource, Taking(_) | Bringing(_) | Harvesting) => true,
+            (Harvesting, ChoosingResource) => true,
+            (Taking(taking), Bringing(bringing)) => taking == bringing,
+            (Taking(_), ChoosingResource) => true,
+            (Bringing(_), ChoosingResource | Storing) => true,
+            (Storing, ChoosingResource) => true,
+            _ => false,
+        }
+    }
+
+    fn label(self) -> String {
+        match self {
+            Self::Taking(kind) => format!("Taking {kind:?}"),
+            Self::Bringing(kind) => format!("Bringing {kind:?}"),
+            Self::GoingForUpgrade(ent_type) => format!("Becoming {}", ent_type.name()),
+            _ => format!("{self:?}"),
+        }
+    }
+
+    fn insert_marker(self, entity: &mut EntityWorldMut) {
+        fn carrying<K: Kind>(entity: &mut EntityWorldMut, bringing: bool) {
+            if bringing {
+                entity.insert(BringingResource::<K>(PhantomData));
+            } else {
+                entity.insert(TakingResource::<K>(PhantomData));
+            }
+        }
+        match self {
+            Self::Harvesting => {
+                entity.insert(Harvesting);
+            }
+            Self::Storing => {
+                entity.insert(Storing);
+            }
+            Self::ChoosingResource => {
+                entity.insert(ChoosingResource);
+            }
+            Self::Taking(kind) | Self::Bringing(kind) => {
+                let bringing = matches!(self, Self::Bringing(_));
+                match kind {
+                    ResourceKind::Wood => carrying::<resource_kind::Wood>(entity, bringing),
+                    ResourceKind::Stone => carrying::<resource_kind::Stone>(entity, bringing),
+                    ResourceKind::Gold => carrying::<resource_kind::Gold>(entity, bringing),
+                }
+            }
+            Self::GoingForUpgrade(_) => {}
+        }
+    }
+
+    fn remove_marker(self, entity: &mut EntityWorldMut) {
+        fn carrying<K: Kind>(entity: &mut EntityWorldMut) {
+            entity.remove::<(TakingResource<K>, BringingResource<K>)>();
+        }
+        match self {
+            Self::Harvesting => {
+                entity.remove::<Harvesting>();
+            }
+            Self::Storing => {
+                entity.remove::<Storing>();
+            }
+            Self::ChoosingResource => {
+                entity.remove::<ChoosingResource>();
+            }
+            Self::Taking(kind) | Self::Bringing(kind) => match kind {
+                ResourceKind::Wood => carrying::<resource_kind::Wood>(entity),
+                ResourceKind::Stone => carrying::<resource_kind::Stone>(entity),
+                ResourceKind::Gold => carrying::<resource_kind::Gold>(entity),
+            },
+            Self::GoingForUpgrade(_) => {}
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct Harvesting;
+
+#[derive(Component)]
+pub struct Storing;
+
+#[derive(Component)]
+pub struct ChoosingResource;
+
+#[derive(Component)]
+pub struct TakingResource<K>(PhantomData<K>);
+
+#[derive(Component)]
+pub struct BringingResource<K>(PhantomData<K>);
+
+/// Sent for every state a crab starts in or changes to
+#[derive(Event, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnitStateChanged {
+    pub entity: Entity,
+    /// None for crabs that just spawned
+    pub from: Option<UnitState>,
+    pub to: UnitState,
+}
+
+/// Moves the crab from one state to another, if it is still in the first one.
+///
+/// Another system may have changed the state since the transition was decided on,
+/// then this one is dropped. Transitions not in [UnitState::can_become] are dropped with a warning.
+pub struct Transition {
+    pub from: Option<UnitState>,
+    pub to: UnitState,
+}
+
+impl EntityCommand for Transition {
+    fn apply(self, id: Entity, world: &mut World) {
+        let Some(mut entity) = world.get_entity_mut(id) else {
+            return;
+        };
+        let current = entity.get::<UnitState>().copied();
+        if current != self.from {
+            debug!(
+                "{id:?} is {current:?} instead of {:?}, not becoming {:?}",
+                self.from, self.to
+            );
+            return;
+        }
+        if let Some(current) = current {
+            if !current.can_become(self.to) {
+                warn!("{id:?} can't go from {current:?} to {:?}", self.to);
+                return;
+            }
+            current.remove_marker(&mut entity);
+        }
+        self.to.insert_marker(&mut entity);
+        entity.insert(self.to);
+        world.send_event(UnitStateChanged {
+            entity: id,
+            from: self.from,
+            to: self.to,
+        });
+    }
+}
+
+pub trait UnitStateCommands {
+    fn transition(&mut self, from: UnitState, to: UnitState) -> &mut Self;
+    /// For crabs that don't have a state yet
+    fn start_in(&mut self, state: UnitState) -> &mut Self;
+}
+
+impl UnitStateCommands for EntityCommands<'_, '_, '_> {
+    fn transition(&mut self, from: UnitState, to: UnitState) -> &mut Self {
+        self.add(Transition {
+            from: Some(from),
+            to,
+        })
+    }
+
+    fn start_in(&mut self, state: UnitState) -> &mut Self {
+        self.add(Transition {
+            from: None,
+            to: state,
+        })
+    }
+}
+
+#[derive(Resource, Default)]
+struct ShowUnitStates(bool);
+
+#[derive(Component)]
+struct StateLabel(Entity);
+
+fn toggle_state_labels(input: Res<Input<KeyCode>>, mut show: ResMut<ShowUnitStates>) {
+    if input.just_pressed(KeyCode::F3) {
+        show.0 = !show.0;
+    }
+}
+
+fn update_state_labels(
+    show: Res<ShowUnitStates>,
+    units: Query<(Entity, &UnitState, &GlobalTransform)>,
+    mut labels: Query<(Entity, &StateLabel, &mut Text, &mut Style)>,
+    camera: Query<(&Camera, &GlobalTransform), With<Camera3d>>,
+    mut commands: Commands,
+) {
+    let Ok((camera, camera_transform)) = camera.get_single() else {
+        return;
+    };
+    let mut labeled = HashSet::new();
+    for (label_entity, label, mut text, mut style) in labels.iter_mut() {
+        let Some((_, state, transform)) = units.get(label.0).ok().filter(|_| show.0) else {
+            commands.entity(label_entity).despawn_recursive();
+            continue;
+        };
+        labeled.insert(label.0);
+        let label = state.label();
+        if text.sections[0].value != label {
+            text.sections[0].value = label;
+        }
+        match camera.world_to_viewport(camera_transform, transform.translation()) {
+            Some(pos) => {
+                style.display = Display::Flex;
+                style.left = Val::Px(pos.x);
+                style.top = Val::Px(pos.y);
+            }
+            None => style.display = Display::None,
+        }
+    }
+    if !show.0 {
+        return;
+    }
+    for (entity, ..) in units.iter() {
+        if labeled.contains(&entity) {
+            continue;
+        }
+        commands.spawn((
+            TextBundle {
+                z_index: ZIndex::Global(100),
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    display: Display::None,
+                    ..default()
+                },
+                text: Text::from_section(
+                    "",
+                    TextStyle {
+                        font_size: 16.0,
+                        color: Color::WHITE,
+                        ..default()
+                    },
+                ),
+                ..default()
+            },
+            StateLabel(entity),
+        ));
+    }
+}